@@ -1,11 +1,16 @@
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 
-use jsonwebtoken::{decode, Algorithm, Validation};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use poem::Request;
 use poem_openapi::{SecurityScheme, auth::Bearer};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::debug;
 
-use crate::config::CONFIG;
+use crate::config::AppConfig;
+use crate::error::ApiError;
 
 /// Structured permission with action, resource, and scope
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -23,20 +28,12 @@ impl Permission {
             scope: scope.to_string(),
         }
     }
+}
 
-    /// Parse from "action:resource:scope" format
-    pub fn from_string(permission_str: &str) -> Option<Self> {
-        let parts: Vec<&str> = permission_str.split(':').collect();
-        if parts.len() == 3 {
-            Some(Self::new(parts[0], parts[1], parts[2]))
-        } else {
-            None
-        }
-    }
-
-    /// Convert to "action:resource:scope" format
-    pub fn to_string(&self) -> String {
-        format!("{}:{}:{}", self.action, self.resource, self.scope)
+/// Formats as "action:resource:scope"
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.action, self.resource, self.scope)
     }
 }
 
@@ -73,32 +70,131 @@ impl BearerAuthorization {
         let required_permission = Permission::new(action, resource, scope);
         self.permissions.contains(&required_permission)
     }
+}
+
+/// Authorization for routes that only read assets. They are public unless
+/// `REQUIRE_READ_AUTH` is set, so a missing or invalid token is let through
+/// here and rejected by `authorize` when it matters.
+#[derive(SecurityScheme)]
+pub enum ReadAuthorization {
+    Bearer(BearerAuthorization),
+    #[oai(fallback)]
+    Anonymous,
+}
+
+impl ReadAuthorization {
+    /// Check the caller may read assets
+    pub fn authorize(&self, config: &AppConfig) -> Result<(), ApiError> {
+        if !config.require_read_auth {
+            return Ok(());
+        }
+
+        match self {
+            Self::Bearer(claims) if claims.has_permission("read", "asset") => Ok(()),
+            Self::Bearer(_) => Err(ApiError::missing_permission("read", "asset")),
+            Self::Anonymous => Err(ApiError::unauthorized("a bearer token is required")),
+        }
+    }
 
-    /// Check if the user has any of the specified permissions
-    pub fn has_any_permission(&self, permissions: &[(String, String)]) -> bool {
-        permissions.iter().any(|(action, resource)| {
-            self.has_permission(action, resource)
-        })
+    /// Check the caller may download `asset`, with a bearer token or a
+    /// signed download link's `token` and `expires`. A link that has expired
+    /// or was signed for something else is refused even where reads are
+    /// public, so it fails the same way everywhere.
+    pub fn authorize_download(
+        &self,
+        config: &AppConfig,
+        asset: &str,
+        token: Option<&str>,
+        expires: Option<u64>,
+    ) -> Result<(), ApiError> {
+        let (Some(token), Some(expires)) = (token, expires) else {
+            return self.authorize(config);
+        };
+
+        let now = chrono::Utc::now().timestamp().max(0) as u64;
+        let Some(secret) = &config.download_link_secret else {
+            return Err(ApiError::invalid_download_link());
+        };
+        let Ok(signature) = hex::decode(token) else {
+            return Err(ApiError::invalid_download_link());
+        };
+        if expires < now || download_link_mac(secret, asset, expires).verify_slice(&signature).is_err() {
+            debug!(asset, expires, "refused download link");
+            return Err(ApiError::invalid_download_link());
+        }
+        Ok(())
     }
+}
+
+/// Hex signature of a download link to `asset` valid until `expires`, in
+/// seconds since the epoch
+pub fn sign_download_link(secret: &str, asset: &str, expires: u64) -> String {
+    hex::encode(download_link_mac(secret, asset, expires).finalize().into_bytes())
+}
+
+fn download_link_mac(secret: &str, asset: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    // The name can't hold a newline, so name and expiry can't be shifted
+    mac.update(format!("{asset}\n{expires}").as_bytes());
+    mac
+}
 
-    /// Check if the user has any of the specified permissions with specific scopes
-    pub fn has_any_permission_with_scope(&self, permissions: &[(String, String, String)]) -> bool {
-        permissions.iter().any(|(action, resource, scope)| {
-            self.has_permission_with_scope(action, resource, scope)
-        })
+/// Signature, expiry and, when configured, issuer and audience checks
+fn validation(config: &AppConfig) -> Validation {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.leeway = config.jwt_leeway_secs;
+    validation.validate_nbf = true;
+
+    let mut required_claims = vec!["exp"];
+    if let Some(issuer) = &config.jwt_issuer {
+        validation.set_issuer(&[issuer]);
+        required_claims.push("iss");
+    }
+    match &config.jwt_audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            required_claims.push("aud");
+        }
+        None => validation.validate_aud = false,
     }
+    validation.set_required_spec_claims(&required_claims);
+
+    validation
+}
+
+async fn key_checker(req: &Request, token: Bearer) -> Option<Claims> {
+    verify(req.data::<AppConfig>()?, &token.token)
 }
 
-async fn key_checker(_: &Request, token: Bearer) -> Option<Claims> {
-    let decoding_key = jsonwebtoken::DecodingKey::from_rsa_pem(CONFIG.jwt_public_key.as_bytes()).ok()?;
-    let Ok(token) = decode(
-        &token.token,
-        &decoding_key,
-        &Validation::new(Algorithm::RS256),
-    ) else {
-        return None;
-    };
-    Some(token.claims)
+/// Subject of a bearer token, only when its signature and claims check out
+/// so it can't be forged to pose as another client
+pub fn verified_subject(config: &AppConfig, token: &str) -> Option<String> {
+    verify(config, token).map(|claims| claims.sub)
+}
+
+fn verify(config: &AppConfig, token: &str) -> Option<Claims> {
+    let kid = decode_header(token).ok()?.kid;
+    let validation = validation(config);
+
+    // Only the key named by the token is tried when there is one, keys
+    // without a kid are candidates for every token.
+    let candidates = config.jwt_public_keys.iter().filter(|key| match (&key.kid, &kid) {
+        (Some(key_kid), Some(kid)) => key_kid == kid,
+        _ => true,
+    });
+
+    for key in candidates {
+        let Ok(decoding_key) = DecodingKey::from_rsa_pem(key.pem.as_bytes()) else {
+            continue;
+        };
+        match decode(token, &decoding_key, &validation) {
+            Ok(token) => return Some(token.claims),
+            Err(why) => debug!("Rejected bearer token: {}", why),
+        }
+    }
+
+    None
 }
 
 impl Deref for BearerAuthorization {
@@ -114,4 +210,75 @@ impl DerefMut for BearerAuthorization {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use poem::error::ResponseError;
+    use poem::http::StatusCode;
+
+    use super::*;
+
+    const SECRET: &str = "download-link-secret";
+
+    fn config(vars: &[(&str, &str)]) -> AppConfig {
+        let mut all = vec![
+            ("STORAGE_BACKEND", "fs"),
+            ("JWT_PUBLIC_KEY", include_str!("../../testdata/jwt.pub.pem")),
+            ("DOWNLOAD_LINK_SECRET", SECRET),
+        ];
+        all.extend_from_slice(vars);
+        AppConfig::from_vars(&all).unwrap()
+    }
+
+    fn now() -> u64 {
+        chrono::Utc::now().timestamp() as u64
+    }
+
+    #[test]
+    fn anonymous_reads_are_allowed_by_default() {
+        let config = config(&[]);
+        assert!(ReadAuthorization::Anonymous.authorize(&config).is_ok());
+        assert!(ReadAuthorization::Anonymous
+            .authorize_download(&config, "img.png", None, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn anonymous_reads_are_refused_with_require_read_auth() {
+        let config = config(&[("REQUIRE_READ_AUTH", "true")]);
+        let error = ReadAuthorization::Anonymous.authorize(&config).unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        let error = ReadAuthorization::Anonymous
+            .authorize_download(&config, "img.png", None, None)
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn signed_links_allow_anonymous_downloads_with_require_read_auth() {
+        let config = config(&[("REQUIRE_READ_AUTH", "true")]);
+        let expires = now() + 600;
+        let token = sign_download_link(SECRET, "img.png", expires);
+        assert!(ReadAuthorization::Anonymous
+            .authorize_download(&config, "img.png", Some(&token), Some(expires))
+            .is_ok());
+
+        // Signed for another asset
+        let error = ReadAuthorization::Anonymous
+            .authorize_download(&config, "other.png", Some(&token), Some(expires))
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn expired_signed_links_are_refused_even_where_reads_are_public() {
+        for config in [config(&[]), config(&[("REQUIRE_READ_AUTH", "true")])] {
+            let expires = now() - 1;
+            let token = sign_download_link(SECRET, "img.png", expires);
+            let error = ReadAuthorization::Anonymous
+                .authorize_download(&config, "img.png", Some(&token), Some(expires))
+                .unwrap_err();
+            assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        }
+    }
+}
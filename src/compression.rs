@@ -0,0 +1,126 @@
+use poem::http::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+};
+use poem::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use poem::web::{Compress, CompressionAlgo};
+use poem::{Endpoint, IntoResponse, Request, Response, Result};
+
+use crate::config::AppConfig;
+
+/// Bodies smaller than this aren't worth the framing overhead
+const MIN_COMPRESS_BYTES: u64 = 1024;
+
+/// Content types that shrink when compressed. Media formats are compressed
+/// already and left alone.
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/yaml",
+    "image/svg+xml",
+    "image/bmp",
+    "image/vnd.microsoft.icon",
+    "audio/wav",
+];
+
+/// Compress text-like responses with brotli or gzip, as the client's
+/// `Accept-Encoding` allows, when `COMPRESS_RESPONSES` is set.
+///
+/// Partial content is never compressed, as ranges refer to the stored
+/// bytes. A compressed response carries a weak `ETag`, since it isn't
+/// byte-for-byte the object the tag names. `If-None-Match` compares tags
+/// weakly, so revalidation keeps working.
+pub async fn compress_responses<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let enabled = req.data::<AppConfig>().is_some_and(|config| config.compress_responses);
+    if !enabled {
+        return next.call(req).await.map(IntoResponse::into_response);
+    }
+
+    let algo = negotiate(req.headers());
+    let is_head = req.method() == Method::HEAD;
+    let mut response = next.call(req).await?.into_response();
+
+    if !is_compressible(&response) {
+        return Ok(response);
+    }
+    response
+        .headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let Some(algo) = algo else {
+        return Ok(response);
+    };
+    let is_small = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|length| length < MIN_COMPRESS_BYTES);
+    if is_head || is_small {
+        return Ok(response);
+    }
+
+    if let Some(etag) = response.headers().get(ETAG).and_then(|value| value.to_str().ok())
+        && !etag.starts_with("W/")
+        && let Ok(weak) = HeaderValue::from_str(&format!("W/{etag}"))
+    {
+        response.headers_mut().insert(ETAG, weak);
+    }
+
+    Ok(Compress::new(response, algo).into_response())
+}
+
+/// Whether a response is a full, not yet encoded body of a compressible type
+fn is_compressible(response: &Response) -> bool {
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(CONTENT_ENCODING)
+        || response.headers().contains_key(CONTENT_RANGE)
+    {
+        return false;
+    }
+
+    let Some(content_type) = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.contains(&essence)
+}
+
+/// Preferred encoding the client accepts, brotli winning ties
+fn negotiate(headers: &HeaderMap) -> Option<CompressionAlgo> {
+    let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+
+    let mut best: Option<(CompressionAlgo, f32)> = None;
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let coding = params.next().unwrap_or_default().trim().to_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let algo = match coding.as_str() {
+            "br" => CompressionAlgo::BR,
+            "gzip" => CompressionAlgo::GZIP,
+            _ => continue,
+        };
+        let is_better = match best {
+            None => true,
+            Some((_, best_quality)) => {
+                quality > best_quality || (quality == best_quality && algo == CompressionAlgo::BR)
+            }
+        };
+        if quality > 0.0 && is_better {
+            best = Some((algo, quality));
+        }
+    }
+
+    best.map(|(algo, _)| algo)
+}
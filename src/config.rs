@@ -1,17 +1,979 @@
-use once_cell::sync::Lazy;
+use jsonwebtoken::DecodingKey;
+use minio::s3::http::BaseUrl;
+use once_cell::sync::OnceCell;
+use poem::http::HeaderValue;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
 
+use crate::routes::sanitize_asset_key;
+
+#[derive(Clone)]
 pub struct AppConfig {
     pub minio_url: String,
     pub minio_access: String,
     pub minio_secret: String,
-    pub jwt_public_key: String
+    /// Region requests are signed for, taken from the URL for AWS hosts and
+    /// MinIO's default otherwise when unset
+    pub minio_region: Option<String>,
+    /// Address buckets as `host/bucket` rather than `bucket.host`. When unset
+    /// AWS hosts use virtual-hosted style and anything else path style.
+    pub minio_path_style: Option<bool>,
+    /// Connect over TLS, overriding the scheme of `minio_url`
+    pub minio_tls: Option<bool>,
+    /// PEM file of CA certificates to trust besides the system ones, for
+    /// MinIO behind a private CA
+    pub minio_ca_bundle: Option<PathBuf>,
+    /// Where assets, thumbnails and the hash index are kept
+    pub storage_backend: StorageBackend,
+    /// Directory the `fs` backend keeps buckets in
+    pub storage_fs_root: PathBuf,
+    pub jwt_public_keys: Vec<JwtKey>,
+    pub max_upload_bytes: u64,
+    /// Size limit of uploads streamed as a raw request body
+    pub max_stream_upload_bytes: u64,
+    /// Largest request body accepted on any route, unlimited when unset
+    pub max_request_body_bytes: Option<u64>,
+    /// Requests handled at once, unlimited when unset
+    pub max_concurrent_requests: Option<usize>,
+    /// Buffered uploads handled at once, unlimited when unset
+    pub max_concurrent_uploads: Option<usize>,
+    /// Memory downloads of small assets may be cached in, 0 to disable
+    pub asset_cache_max_bytes: u64,
+    /// Largest asset kept in the download cache
+    pub asset_cache_max_object_bytes: u64,
+    /// Storage lookups batch routes run at once
+    pub batch_stat_concurrency: usize,
+    /// Times a failed read from object storage is retried when the error
+    /// looks transient
+    pub storage_retries: u32,
+    /// Pause before the first retry in milliseconds, doubled for each one after
+    pub storage_retry_backoff_ms: u64,
+    /// Time a single object storage request may take before it is abandoned,
+    /// in seconds, unlimited when 0
+    pub storage_timeout_secs: u64,
+    /// Remove EXIF and similar metadata from uploaded images
+    pub strip_image_metadata: bool,
+    /// Re-encode uploaded JPEG and PNG images as WebP when that is smaller
+    pub transcode_to_webp: bool,
+    /// Filter directives for the log output, e.g. `info` or `images_service=debug`
+    pub log_level: String,
+    /// Origins allowed to call the API from a browser, `*` for any. CORS is
+    /// disabled when empty.
+    pub cors_allowed_origins: Vec<String>,
+    /// Required `iss` claim of bearer tokens, unchecked when unset
+    pub jwt_issuer: Option<String>,
+    /// Required `aud` claim of bearer tokens, unchecked when unset
+    pub jwt_audience: Option<String>,
+    /// Clock skew tolerated when checking `exp` and `nbf`, in seconds
+    pub jwt_leeway_secs: u64,
+    /// How long in-flight requests get to finish once a shutdown is requested
+    pub shutdown_timeout_secs: u64,
+    /// Bucket assets are stored in
+    pub assets_bucket: String,
+    /// Bucket rendered thumbnails, and dimensions and play lengths read from
+    /// assets uploaded without them, are cached in
+    pub thumbnails_bucket: String,
+    /// Bucket holding the content hash index used to deduplicate uploads
+    pub hash_index_bucket: String,
+    /// File extensions uploads may have, e.g. `.png`. The built in image,
+    /// audio and video extensions when unset.
+    pub allowed_extensions: Option<Vec<String>>,
+    /// File extensions rejected even when allowed. SVG can carry scripts that
+    /// run when it is opened inline, so deployments serving untrusted
+    /// uploads may want to block `.svg`.
+    pub blocked_extensions: Vec<String>,
+    /// Longest name new assets may get, in bytes
+    pub max_asset_name_length: usize,
+    /// What happens to characters outside the safe set in new asset names
+    pub asset_name_characters: NameCharacters,
+    /// Hosts assets may be imported from by URL, any public host when unset.
+    /// Subdomains of a listed host are included.
+    pub url_upload_allowed_hosts: Option<Vec<String>>,
+    /// Hosts assets are never imported from, with their subdomains
+    pub url_upload_denied_hosts: Vec<String>,
+    /// Allow importing from loopback, private and other internal addresses
+    pub url_upload_allow_private: bool,
+    /// Time allowed for fetching an asset imported by URL, in seconds
+    pub url_upload_timeout_secs: u64,
+    /// Fall back to a case-insensitive match when a downloaded asset doesn't
+    /// exist, at the cost of listing the bucket on every miss
+    pub case_insensitive_lookup: bool,
+    /// Asset downloads of missing assets are redirected to, e.g. a
+    /// placeholder image
+    pub default_asset_key: Option<String>,
+    /// Namespace prepended to every asset key, e.g. `blog-a/`, so several
+    /// deployments can share buckets. Empty or ending in `/`.
+    pub key_prefix: String,
+    /// Move deleted assets to the trash instead of removing them
+    pub soft_delete: bool,
+    /// Compare the checksum of stored uploads against what was sent
+    pub verify_uploads: bool,
+    /// Compress text-like responses for clients that accept it
+    pub compress_responses: bool,
+    /// `Cache-Control` sent with asset downloads, e.g. `public, max-age=86400`
+    pub download_cache_control: Option<String>,
+    /// `Cache-Control` sent with asset info, usually shorter than downloads
+    pub info_cache_control: Option<String>,
+    /// Custom metadata keys uploads may set, lowercase
+    pub allowed_metadata_keys: Vec<String>,
+    /// Scheme, host and any path prefix the service is reached at publicly,
+    /// without a trailing slash. Upload responses return absolute URLs with it.
+    pub public_base_url: Option<String>,
+    /// Receives a POST for every created or deleted asset
+    pub webhook_url: Option<String>,
+    /// Shared secret webhook bodies are signed with, unsigned when unset
+    pub webhook_secret: Option<String>,
+    /// How long the result of an upload sent with an `Idempotency-Key` is
+    /// replayed to retries, in seconds
+    pub idempotency_key_ttl_secs: u64,
+    /// Requests each client may make per minute, unlimited when unset
+    pub rate_limit_per_minute: Option<u32>,
+    /// Require a `read asset` permission to download, list or inspect assets
+    pub require_read_auth: bool,
+    /// Secret signed download links are keyed with, no links can be signed
+    /// when unset
+    pub download_link_secret: Option<String>,
+}
+
+/// A key bearer tokens may be signed with
+#[derive(Clone, Deserialize)]
+pub struct JwtKey {
+    /// Matched against the `kid` header of tokens, keys without one are
+    /// tried for any token
+    #[serde(default)]
+    pub kid: Option<String>,
+    pub pem: String,
+}
+
+/// Characters new asset names may have, for `ASSET_NAME_CHARACTERS`. The
+/// safe set is ASCII letters, digits, `.`, `-`, `_` and the `/` between
+/// folders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameCharacters {
+    /// Any character a name can have
+    Any,
+    /// Names with characters outside the safe set are refused
+    Reject,
+    /// Characters outside the safe set are replaced with `_`
+    Replace,
+}
+
+impl FromStr for NameCharacters {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "any" => Ok(Self::Any),
+            "reject" => Ok(Self::Reject),
+            "replace" => Ok(Self::Replace),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Object storage implementation, for `STORAGE_BACKEND`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// The MinIO or S3 server at `MINIO_URL`
+    Minio,
+    /// Files below `STORAGE_FS_ROOT`
+    Fs,
 }
 
-pub static CONFIG: Lazy<AppConfig> = Lazy::new(|| AppConfig {
-    minio_url: env::var("MINIO_URL").expect("Could not get minio url"),
-    minio_access: env::var("MINIO_ACCESS").expect("Could not get minio access key"),
-    minio_secret: env::var("MINIO_SECRET").expect("Could not get minio secret key"),
+impl FromStr for StorageBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "minio" | "s3" => Ok(Self::Minio),
+            "fs" => Ok(Self::Fs),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Default for `MAX_ASSET_NAME_LENGTH`, what most file systems allow
+const DEFAULT_MAX_ASSET_NAME_LENGTH: usize = 255;
+
+/// Default for `JWT_LEEWAY_SECS`, matching jsonwebtoken's own default
+const DEFAULT_JWT_LEEWAY_SECS: u64 = 60;
+
+/// Default for `SHUTDOWN_TIMEOUT_SECS`
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+
+/// Default for `MAX_UPLOAD_BYTES`, 50 MiB
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Default for `MAX_STREAM_UPLOAD_BYTES`, 10 GiB
+const DEFAULT_MAX_STREAM_UPLOAD_BYTES: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Default for `ASSET_CACHE_MAX_OBJECT_BYTES`, 256 KiB
+const DEFAULT_ASSET_CACHE_MAX_OBJECT_BYTES: u64 = 256 * 1024;
+
+/// Default for `URL_UPLOAD_TIMEOUT_SECS`
+const DEFAULT_URL_UPLOAD_TIMEOUT_SECS: u64 = 30;
+
+/// Default for `BATCH_STAT_CONCURRENCY`
+const DEFAULT_BATCH_STAT_CONCURRENCY: usize = 16;
+
+/// Default for `STORAGE_RETRIES`
+const DEFAULT_STORAGE_RETRIES: u32 = 2;
+
+/// Default for `STORAGE_RETRY_BACKOFF_MS`
+const DEFAULT_STORAGE_RETRY_BACKOFF_MS: u64 = 100;
+
+/// Default for `STORAGE_TIMEOUT_SECS`
+const DEFAULT_STORAGE_TIMEOUT_SECS: u64 = 30;
 
-    jwt_public_key: env::var("JWT_PUBLIC_KEY").expect("JWT public key not set").replace("\\n", "\n"),
-});
+/// Default for `IDEMPOTENCY_KEY_TTL_SECS`, a day
+const DEFAULT_IDEMPOTENCY_KEY_TTL_SECS: u64 = 24 * 60 * 60;
+
+impl AppConfig {
+    /// Read the configuration from the environment, reporting every missing or
+    /// invalid variable at once instead of stopping at the first one.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut errors = Vec::new();
+
+        let storage_backend =
+            parsed(&mut errors, "STORAGE_BACKEND", "minio or fs", StorageBackend::Minio);
+        // The MinIO connection only has to be configured when it is used
+        let minio_setting = |errors: &mut Vec<String>, name: &str, example: &str| {
+            if storage_backend == StorageBackend::Minio {
+                required(errors, name, example)
+            } else {
+                optional(name).unwrap_or_default()
+            }
+        };
+
+        let minio_url = minio_setting(&mut errors, "MINIO_URL", "http://minio:9000");
+        if !minio_url.is_empty() && minio_url.parse::<BaseUrl>().is_err() {
+            errors.push(format!(
+                "MINIO_URL is not a valid URL (got {minio_url:?}, e.g. MINIO_URL=\"http://minio:9000\")"
+            ));
+        }
+
+        // LOG_LEVEL wins, RUST_LOG is honoured for the usual tooling
+        let log_level = var("LOG_LEVEL")
+            .or_else(|| var("RUST_LOG"))
+            .unwrap_or_else(|| "info".to_string());
+        if EnvFilter::try_new(&log_level).is_err() {
+            errors.push(format!(
+                "LOG_LEVEL is not a valid filter (got {log_level:?}, e.g. LOG_LEVEL=\"info\")"
+            ));
+        }
+
+        let cors_allowed_origins: Vec<String> = var("CORS_ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::to_string)
+            .collect();
+        for origin in &cors_allowed_origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                errors.push(format!(
+                    "CORS_ALLOWED_ORIGINS contains an invalid origin (got {origin:?}, e.g. CORS_ALLOWED_ORIGINS=\"https://blog.example.com\")"
+                ));
+            }
+        }
+
+        // JWT_PUBLIC_KEYS holds every key valid during a rotation, the single
+        // JWT_PUBLIC_KEY is kept for deployments that never rotate.
+        let jwt_public_keys = match optional("JWT_PUBLIC_KEYS") {
+            Some(keys) => match serde_json::from_str::<Vec<JwtKey>>(&keys) {
+                Ok(keys) if !keys.is_empty() => keys,
+                Ok(_) => {
+                    errors.push("JWT_PUBLIC_KEYS must contain at least one key".to_string());
+                    Vec::new()
+                }
+                Err(why) => {
+                    errors.push(format!(
+                        "JWT_PUBLIC_KEYS must be a JSON array like [{{\"kid\": \"2025-01\", \"pem\": \"-----BEGIN PUBLIC KEY-----\\n...\"}}] ({why})"
+                    ));
+                    Vec::new()
+                }
+            },
+            None => vec![JwtKey {
+                kid: None,
+                pem: required(
+                    &mut errors,
+                    "JWT_PUBLIC_KEY",
+                    "-----BEGIN PUBLIC KEY-----\\n...\\n-----END PUBLIC KEY-----",
+                )
+                .replace("\\n", "\n"),
+            }],
+        };
+        for key in &jwt_public_keys {
+            if !key.pem.is_empty() && DecodingKey::from_rsa_pem(key.pem.as_bytes()).is_err() {
+                errors.push(format!(
+                    "JWT public key {} is not a valid RSA public key in PEM format",
+                    key.kid.as_deref().unwrap_or("without a kid")
+                ));
+            }
+        }
+
+        let assets_bucket = bucket(&mut errors, "ASSETS_BUCKET", "assets-files");
+        let thumbnails_bucket = bucket(&mut errors, "THUMBNAILS_BUCKET", "assets-thumbnails");
+        let hash_index_bucket = bucket(&mut errors, "HASH_INDEX_BUCKET", "assets-hashes");
+        if assets_bucket == thumbnails_bucket
+            || assets_bucket == hash_index_bucket
+            || thumbnails_bucket == hash_index_bucket
+        {
+            errors.push(
+                "ASSETS_BUCKET, THUMBNAILS_BUCKET and HASH_INDEX_BUCKET must all differ".to_string(),
+            );
+        }
+
+        let allowed_extensions = optional("ALLOWED_EXTENSIONS")
+            .map(|extensions| extension_list(&mut errors, "ALLOWED_EXTENSIONS", &extensions));
+        let blocked_extensions = optional("BLOCKED_EXTENSIONS")
+            .map(|extensions| extension_list(&mut errors, "BLOCKED_EXTENSIONS", &extensions))
+            .unwrap_or_default();
+
+        let url_upload_allowed_hosts = optional("URL_UPLOAD_ALLOWED_HOSTS")
+            .map(|hosts| host_list(&mut errors, "URL_UPLOAD_ALLOWED_HOSTS", &hosts));
+        let url_upload_denied_hosts = optional("URL_UPLOAD_DENIED_HOSTS")
+            .map(|hosts| host_list(&mut errors, "URL_UPLOAD_DENIED_HOSTS", &hosts))
+            .unwrap_or_default();
+
+        let webhook_url = optional("WEBHOOK_URL");
+        if let Some(url) = &webhook_url
+            && !reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        {
+            errors.push(format!(
+                "WEBHOOK_URL is not an http(s) URL (got {url:?}, e.g. WEBHOOK_URL=\"https://cms.example.com/hooks/assets\")"
+            ));
+        }
+
+        let public_base_url = optional("PUBLIC_BASE_URL")
+            .map(|url| url.trim_end_matches('/').to_string());
+        if let Some(url) = &public_base_url
+            && !reqwest::Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        {
+            errors.push(format!(
+                "PUBLIC_BASE_URL is not an http(s) URL (got {url:?}, e.g. PUBLIC_BASE_URL=\"https://cdn.example.com\")"
+            ));
+        }
+
+        let key_prefix = optional("KEY_PREFIX")
+            .map(|prefix| prefix.trim_matches('/').to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{prefix}/"))
+            .unwrap_or_default();
+        if key_prefix.starts_with(".trash/") || key_prefix.contains(['\\', '\0']) {
+            errors.push(format!(
+                "KEY_PREFIX must be a plain path like blog-a (got {key_prefix:?})"
+            ));
+        }
+
+        // Held to the rules downloads are looked up with, so a nested key
+        // such as `defaults/missing.png` works
+        let default_asset_key = optional("DEFAULT_ASSET_KEY").and_then(|key| {
+            let sanitized = sanitize_asset_key(&key);
+            if sanitized.is_none() {
+                errors.push(format!(
+                    "DEFAULT_ASSET_KEY must be a valid asset key, without `..` segments or control characters (got {key:?})"
+                ));
+            }
+            sanitized
+        });
+
+        let download_cache_control = header_value(&mut errors, "DOWNLOAD_CACHE_CONTROL");
+        let info_cache_control = header_value(&mut errors, "INFO_CACHE_CONTROL");
+        let allowed_metadata_keys = optional("ALLOWED_METADATA_KEYS")
+            .map(|keys| metadata_key_list(&mut errors, "ALLOWED_METADATA_KEYS", &keys))
+            .unwrap_or_default();
+
+        let minio_ca_bundle = optional("MINIO_CA_BUNDLE").map(PathBuf::from);
+        if let Some(path) = &minio_ca_bundle {
+            match fs::read(path) {
+                Ok(pem) => {
+                    if !reqwest::Certificate::from_pem_bundle(&pem).is_ok_and(|certs| !certs.is_empty()) {
+                        errors.push(format!(
+                            "MINIO_CA_BUNDLE does not contain PEM encoded certificates (got {})",
+                            path.display()
+                        ));
+                    }
+                }
+                Err(why) => errors.push(format!(
+                    "MINIO_CA_BUNDLE can't be read (got {}: {why})",
+                    path.display()
+                )),
+            }
+        }
+
+        let config = Self {
+            minio_url,
+            minio_access: minio_setting(&mut errors, "MINIO_ACCESS", "<minio access key>"),
+            minio_secret: minio_setting(&mut errors, "MINIO_SECRET", "<minio secret key>"),
+            minio_region: optional("MINIO_REGION"),
+            minio_path_style: parsed_optional(&mut errors, "MINIO_PATH_STYLE", "true or false"),
+            minio_tls: parsed_optional(&mut errors, "MINIO_TLS", "true or false"),
+            minio_ca_bundle,
+            storage_backend,
+            storage_fs_root: optional("STORAGE_FS_ROOT")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("data")),
+            jwt_public_keys,
+            max_upload_bytes: parsed(
+                &mut errors,
+                "MAX_UPLOAD_BYTES",
+                "a number of bytes",
+                DEFAULT_MAX_UPLOAD_BYTES,
+            ),
+            max_stream_upload_bytes: parsed(
+                &mut errors,
+                "MAX_STREAM_UPLOAD_BYTES",
+                "a number of bytes",
+                DEFAULT_MAX_STREAM_UPLOAD_BYTES,
+            ),
+            strip_image_metadata: parsed(
+                &mut errors,
+                "STRIP_IMAGE_METADATA",
+                "true or false",
+                false,
+            ),
+            transcode_to_webp: parsed(&mut errors, "TRANSCODE_TO_WEBP", "true or false", false),
+            log_level,
+            cors_allowed_origins,
+            jwt_issuer: optional("JWT_ISSUER"),
+            jwt_audience: optional("JWT_AUDIENCE"),
+            jwt_leeway_secs: parsed(
+                &mut errors,
+                "JWT_LEEWAY_SECS",
+                "a number of seconds",
+                DEFAULT_JWT_LEEWAY_SECS,
+            ),
+            shutdown_timeout_secs: parsed(
+                &mut errors,
+                "SHUTDOWN_TIMEOUT_SECS",
+                "a number of seconds",
+                DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            ),
+            assets_bucket,
+            thumbnails_bucket,
+            hash_index_bucket,
+            allowed_extensions,
+            blocked_extensions,
+            max_asset_name_length: parsed(
+                &mut errors,
+                "MAX_ASSET_NAME_LENGTH",
+                "a number of bytes",
+                DEFAULT_MAX_ASSET_NAME_LENGTH,
+            ),
+            asset_name_characters: parsed(
+                &mut errors,
+                "ASSET_NAME_CHARACTERS",
+                "any, reject or replace",
+                NameCharacters::Any,
+            ),
+            url_upload_allowed_hosts,
+            url_upload_denied_hosts,
+            url_upload_allow_private: parsed(
+                &mut errors,
+                "URL_UPLOAD_ALLOW_PRIVATE",
+                "true or false",
+                false,
+            ),
+            url_upload_timeout_secs: parsed(
+                &mut errors,
+                "URL_UPLOAD_TIMEOUT_SECS",
+                "a number of seconds",
+                DEFAULT_URL_UPLOAD_TIMEOUT_SECS,
+            ),
+            case_insensitive_lookup: parsed(
+                &mut errors,
+                "CASE_INSENSITIVE_LOOKUP",
+                "true or false",
+                false,
+            ),
+            default_asset_key,
+            key_prefix,
+            soft_delete: parsed(&mut errors, "SOFT_DELETE", "true or false", false),
+            verify_uploads: parsed(&mut errors, "VERIFY_UPLOADS", "true or false", false),
+            compress_responses: parsed(&mut errors, "COMPRESS_RESPONSES", "true or false", false),
+            download_cache_control,
+            info_cache_control,
+            allowed_metadata_keys,
+            public_base_url,
+            webhook_url,
+            webhook_secret: optional("WEBHOOK_SECRET"),
+            idempotency_key_ttl_secs: parsed(
+                &mut errors,
+                "IDEMPOTENCY_KEY_TTL_SECS",
+                "a number of seconds",
+                DEFAULT_IDEMPOTENCY_KEY_TTL_SECS,
+            ),
+            asset_cache_max_bytes: parsed(
+                &mut errors,
+                "ASSET_CACHE_MAX_BYTES",
+                "a number of bytes",
+                0,
+            ),
+            asset_cache_max_object_bytes: parsed(
+                &mut errors,
+                "ASSET_CACHE_MAX_OBJECT_BYTES",
+                "a number of bytes",
+                DEFAULT_ASSET_CACHE_MAX_OBJECT_BYTES,
+            ),
+            batch_stat_concurrency: parsed(
+                &mut errors,
+                "BATCH_STAT_CONCURRENCY",
+                "a number of requests",
+                DEFAULT_BATCH_STAT_CONCURRENCY,
+            )
+            .max(1),
+            storage_retries: parsed(
+                &mut errors,
+                "STORAGE_RETRIES",
+                "a number of retries",
+                DEFAULT_STORAGE_RETRIES,
+            ),
+            storage_retry_backoff_ms: parsed(
+                &mut errors,
+                "STORAGE_RETRY_BACKOFF_MS",
+                "a number of milliseconds",
+                DEFAULT_STORAGE_RETRY_BACKOFF_MS,
+            ),
+            storage_timeout_secs: parsed(
+                &mut errors,
+                "STORAGE_TIMEOUT_SECS",
+                "a number of seconds",
+                DEFAULT_STORAGE_TIMEOUT_SECS,
+            ),
+            // 0 turns limiting off as well
+            max_request_body_bytes: parsed_optional(
+                &mut errors,
+                "MAX_REQUEST_BODY_BYTES",
+                "a number of bytes",
+            )
+            .filter(|limit| *limit > 0),
+            max_concurrent_requests: Some(parsed(
+                &mut errors,
+                "MAX_CONCURRENT_REQUESTS",
+                "a number of requests",
+                0,
+            ))
+            .filter(|limit| *limit > 0),
+            max_concurrent_uploads: Some(parsed(
+                &mut errors,
+                "MAX_CONCURRENT_UPLOADS",
+                "a number of uploads",
+                0,
+            ))
+            .filter(|limit| *limit > 0),
+            // 0 turns limiting off as well
+            rate_limit_per_minute: Some(parsed(
+                &mut errors,
+                "RATE_LIMIT_PER_MINUTE",
+                "a number of requests",
+                0,
+            ))
+            .filter(|limit| *limit > 0),
+            require_read_auth: parsed(&mut errors, "REQUIRE_READ_AUTH", "true or false", false),
+            download_link_secret: optional("DOWNLOAD_LINK_SECRET"),
+        };
+
+        if !errors.is_empty() {
+            anyhow::bail!("invalid configuration:\n  - {}", errors.join("\n  - "));
+        }
+
+        Ok(config)
+    }
+
+    /// Like `from_env`, reading `vars` instead of the environment so a
+    /// configuration can be built in-process without touching it
+    #[cfg(test)]
+    pub fn from_vars(vars: &[(&str, &str)]) -> anyhow::Result<Self> {
+        let vars = vars.iter().map(|(name, value)| (name.to_string(), value.to_string()));
+        VARS.set(Some(vars.collect()));
+        let config = Self::from_env();
+        VARS.set(None);
+        config
+    }
+
+    /// Storage key of an asset in `ASSETS_BUCKET`, namespaced by `KEY_PREFIX`
+    pub fn object_key(&self, asset: &str) -> String {
+        format!("{}{asset}", self.key_prefix)
+    }
+
+    /// Asset name of a key listed from `ASSETS_BUCKET`, without `KEY_PREFIX`
+    pub fn asset_name<'a>(&self, key: &'a str) -> &'a str {
+        key.strip_prefix(self.key_prefix.as_str()).unwrap_or(key)
+    }
+}
+
+/// Shown in place of secrets
+const REDACTED: &str = "<redacted>";
+
+/// Every setting except secrets, for the startup log. Credentials are
+/// replaced with `<redacted>`, as are user info and queries of URLs, which
+/// may carry credentials as well. Destructuring makes a new field a
+/// compile error here until it is decided whether it may be shown.
+impl fmt::Debug for AppConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let AppConfig {
+            minio_url,
+            minio_access: _,
+            minio_secret: _,
+            minio_region,
+            minio_path_style,
+            minio_tls,
+            minio_ca_bundle,
+            storage_backend,
+            storage_fs_root,
+            jwt_public_keys,
+            max_upload_bytes,
+            max_stream_upload_bytes,
+            max_request_body_bytes,
+            max_concurrent_requests,
+            max_concurrent_uploads,
+            asset_cache_max_bytes,
+            asset_cache_max_object_bytes,
+            batch_stat_concurrency,
+            storage_retries,
+            storage_retry_backoff_ms,
+            storage_timeout_secs,
+            strip_image_metadata,
+            transcode_to_webp,
+            log_level,
+            cors_allowed_origins,
+            jwt_issuer,
+            jwt_audience,
+            jwt_leeway_secs,
+            shutdown_timeout_secs,
+            assets_bucket,
+            thumbnails_bucket,
+            hash_index_bucket,
+            allowed_extensions,
+            blocked_extensions,
+            max_asset_name_length,
+            asset_name_characters,
+            url_upload_allowed_hosts,
+            url_upload_denied_hosts,
+            url_upload_allow_private,
+            url_upload_timeout_secs,
+            case_insensitive_lookup,
+            default_asset_key,
+            key_prefix,
+            soft_delete,
+            verify_uploads,
+            compress_responses,
+            download_cache_control,
+            info_cache_control,
+            allowed_metadata_keys,
+            public_base_url,
+            webhook_url,
+            webhook_secret,
+            idempotency_key_ttl_secs,
+            rate_limit_per_minute,
+            require_read_auth,
+            download_link_secret,
+        } = self;
+
+        let key_ids: Vec<&str> = jwt_public_keys
+            .iter()
+            .map(|key| key.kid.as_deref().unwrap_or("<no kid>"))
+            .collect();
+
+        f.debug_struct("AppConfig")
+            .field("minio_url", &redact_url(minio_url))
+            .field("minio_access", &REDACTED)
+            .field("minio_secret", &REDACTED)
+            .field("minio_region", minio_region)
+            .field("minio_path_style", minio_path_style)
+            .field("minio_tls", minio_tls)
+            .field("minio_ca_bundle", minio_ca_bundle)
+            .field("storage_backend", storage_backend)
+            .field("storage_fs_root", storage_fs_root)
+            .field("jwt_public_keys", &key_ids)
+            .field("max_upload_bytes", max_upload_bytes)
+            .field("max_stream_upload_bytes", max_stream_upload_bytes)
+            .field("max_request_body_bytes", max_request_body_bytes)
+            .field("max_concurrent_requests", max_concurrent_requests)
+            .field("max_concurrent_uploads", max_concurrent_uploads)
+            .field("asset_cache_max_bytes", asset_cache_max_bytes)
+            .field("asset_cache_max_object_bytes", asset_cache_max_object_bytes)
+            .field("batch_stat_concurrency", batch_stat_concurrency)
+            .field("storage_retries", storage_retries)
+            .field("storage_retry_backoff_ms", storage_retry_backoff_ms)
+            .field("storage_timeout_secs", storage_timeout_secs)
+            .field("strip_image_metadata", strip_image_metadata)
+            .field("transcode_to_webp", transcode_to_webp)
+            .field("log_level", log_level)
+            .field("cors_allowed_origins", cors_allowed_origins)
+            .field("jwt_issuer", jwt_issuer)
+            .field("jwt_audience", jwt_audience)
+            .field("jwt_leeway_secs", jwt_leeway_secs)
+            .field("shutdown_timeout_secs", shutdown_timeout_secs)
+            .field("assets_bucket", assets_bucket)
+            .field("thumbnails_bucket", thumbnails_bucket)
+            .field("hash_index_bucket", hash_index_bucket)
+            .field("allowed_extensions", allowed_extensions)
+            .field("blocked_extensions", blocked_extensions)
+            .field("max_asset_name_length", max_asset_name_length)
+            .field("asset_name_characters", asset_name_characters)
+            .field("url_upload_allowed_hosts", url_upload_allowed_hosts)
+            .field("url_upload_denied_hosts", url_upload_denied_hosts)
+            .field("url_upload_allow_private", url_upload_allow_private)
+            .field("url_upload_timeout_secs", url_upload_timeout_secs)
+            .field("case_insensitive_lookup", case_insensitive_lookup)
+            .field("default_asset_key", default_asset_key)
+            .field("key_prefix", key_prefix)
+            .field("soft_delete", soft_delete)
+            .field("verify_uploads", verify_uploads)
+            .field("compress_responses", compress_responses)
+            .field("download_cache_control", download_cache_control)
+            .field("info_cache_control", info_cache_control)
+            .field("allowed_metadata_keys", allowed_metadata_keys)
+            .field("public_base_url", &public_base_url.as_deref().map(redact_url))
+            .field("webhook_url", &webhook_url.as_deref().map(redact_url))
+            .field("webhook_secret", &webhook_secret.as_ref().map(|_| REDACTED))
+            .field("idempotency_key_ttl_secs", idempotency_key_ttl_secs)
+            .field("rate_limit_per_minute", rate_limit_per_minute)
+            .field("require_read_auth", require_read_auth)
+            .field("download_link_secret", &download_link_secret.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+/// A URL without user info and query. Unparseable values are hidden
+/// entirely, they could be anything.
+fn redact_url(url: &str) -> String {
+    let Ok(mut url) = reqwest::Url::parse(url) else {
+        return REDACTED.to_string();
+    };
+    // Plain words, as brackets would be percent-encoded in a URL
+    if url.query().is_some() {
+        url.set_query(Some("redacted"));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        // Only fails for URLs that can't have credentials in the first place
+        let _ = url.set_username("redacted");
+        let _ = url.set_password(None);
+    }
+    url.to_string()
+}
+
+/// A variable that must be set, `example` shows what a valid value looks like
+fn required(errors: &mut Vec<String>, name: &str, example: &str) -> String {
+    match var(name) {
+        Some(value) if !value.trim().is_empty() => value,
+        _ => {
+            errors.push(format!("{name} is not set (e.g. {name}=\"{example}\")"));
+            String::new()
+        }
+    }
+}
+
+/// A bucket name, falling back to `default` when unset. Names must follow
+/// the S3 rules: 3 to 63 lowercase letters, digits, dots and hyphens.
+fn bucket(errors: &mut Vec<String>, name: &str, default: &str) -> String {
+    let bucket = optional(name).unwrap_or_else(|| default.to_string());
+
+    let is_valid = (3..=63).contains(&bucket.len())
+        && bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+        && bucket.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && bucket.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if !is_valid {
+        errors.push(format!(
+            "{name} is not a valid bucket name (got {bucket:?}, e.g. {name}=\"{default}\")"
+        ));
+    }
+
+    bucket
+}
+
+/// A comma separated list of file extensions, normalised to lowercase with a
+/// leading dot so `PNG` and `.png` mean the same
+fn extension_list(errors: &mut Vec<String>, name: &str, value: &str) -> Vec<String> {
+    let mut extensions = Vec::new();
+    for extension in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+            errors.push(format!(
+                "{name} contains an invalid extension (got {extension:?}, e.g. {name}=\".png,.pdf\")"
+            ));
+            continue;
+        }
+        extensions.push(format!(".{extension}"));
+    }
+    extensions
+}
+
+/// A comma separated list of user metadata keys, lowercased since S3 stores
+/// them as lowercase headers
+fn metadata_key_list(errors: &mut Vec<String>, name: &str, value: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    for key in value.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+        let key = key.to_lowercase();
+        if !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            errors.push(format!(
+                "{name} contains an invalid key (got {key:?}, e.g. {name}=\"author,source\")"
+            ));
+            continue;
+        }
+        keys.push(key);
+    }
+    keys
+}
+
+thread_local! {
+    /// Variables `AppConfig::from_vars` reads instead of the environment
+    static VARS: RefCell<Option<HashMap<String, String>>> = const { RefCell::new(None) };
+}
+
+/// A variable as it is set, from the environment or from what
+/// `AppConfig::from_vars` was given
+fn var(name: &str) -> Option<String> {
+    VARS.with_borrow(|vars| match vars {
+        Some(vars) => vars.get(name).cloned(),
+        None => env::var(name).ok(),
+    })
+}
+
+/// An optional variable, `None` when unset or blank
+fn optional(name: &str) -> Option<String> {
+    var(name)
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// An optional variable sent as a response header, which has to be visible
+/// ASCII to be valid there
+fn header_value(errors: &mut Vec<String>, name: &str) -> Option<String> {
+    let value = optional(name)?;
+    if !value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        errors.push(format!("{name} must be a valid header value (got {value:?})"));
+        return None;
+    }
+    Some(value)
+}
+
+/// An optional variable parsed into `T`, falling back to `default` when unset
+fn parsed<T: FromStr>(errors: &mut Vec<String>, name: &str, expected: &str, default: T) -> T {
+    match var(name) {
+        Some(value) => value.trim().parse().unwrap_or_else(|_| {
+            errors.push(format!("{name} must be {expected} (got {value:?})"));
+            default
+        }),
+        None => default,
+    }
+}
+
+/// A comma separated list of host names, normalised to lowercase without
+/// leading dots so `.Example.com` and `example.com` mean the same
+fn host_list(errors: &mut Vec<String>, name: &str, value: &str) -> Vec<String> {
+    let mut hosts = Vec::new();
+    for host in value.split(',').map(str::trim).filter(|h| !h.is_empty()) {
+        let host = host.trim_start_matches('.').to_lowercase();
+        let is_valid = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !is_valid {
+            errors.push(format!(
+                "{name} contains an invalid host (got {host:?}, e.g. {name}=\"images.example.com,cdn.example.org\")"
+            ));
+            continue;
+        }
+        hosts.push(host);
+    }
+    hosts
+}
+
+/// Like `parsed`, `None` when unset
+fn parsed_optional<T: FromStr>(errors: &mut Vec<String>, name: &str, expected: &str) -> Option<T> {
+    let value = optional(name)?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            errors.push(format!("{name} must be {expected} (got {value:?})"));
+            None
+        }
+    }
+}
+
+static LOADED: OnceCell<AppConfig> = OnceCell::new();
+
+/// Validate and install the configuration. Called once during startup so a
+/// misconfigured deployment fails before serving any request.
+pub fn load() -> anyhow::Result<&'static AppConfig> {
+    LOADED.get_or_try_init(AppConfig::from_env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> anyhow::Result<AppConfig> {
+        let mut all = vec![
+            ("STORAGE_BACKEND", "fs"),
+            ("JWT_PUBLIC_KEY", include_str!("../testdata/jwt.pub.pem")),
+        ];
+        all.extend_from_slice(vars);
+        AppConfig::from_vars(&all)
+    }
+
+    #[test]
+    fn keys_are_asset_names_without_a_prefix() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config.object_key("posts/img.png"), "posts/img.png");
+        assert_eq!(config.object_key(""), "");
+        assert_eq!(config.asset_name("posts/img.png"), "posts/img.png");
+    }
+
+    #[test]
+    fn prefix_is_added_to_keys_and_stripped_from_names() {
+        for prefix in ["blog-a", "/blog-a/", "blog-a/"] {
+            let config = config(&[("KEY_PREFIX", prefix)]).unwrap();
+            assert_eq!(config.object_key("posts/img.png"), "blog-a/posts/img.png");
+            assert_eq!(config.object_key(""), "blog-a/");
+            assert_eq!(config.asset_name("blog-a/posts/img.png"), "posts/img.png");
+        }
+    }
+
+    #[test]
+    fn names_of_other_prefixes_keep_their_key() {
+        let config = config(&[("KEY_PREFIX", "blog-a")]).unwrap();
+        assert_eq!(config.asset_name("blog-b/img.png"), "blog-b/img.png");
+        // A prefix is a whole folder, not the start of a name
+        assert_eq!(config.asset_name("blog-ab/img.png"), "blog-ab/img.png");
+    }
+
+    #[test]
+    fn nested_prefixes_are_accepted() {
+        let config = config(&[("KEY_PREFIX", "tenants/blog-a")]).unwrap();
+        assert_eq!(config.object_key("img.png"), "tenants/blog-a/img.png");
+        assert_eq!(config.asset_name("tenants/blog-a/img.png"), "img.png");
+    }
+
+    #[test]
+    fn prefixes_inside_the_trash_are_refused() {
+        let error = config(&[("KEY_PREFIX", ".trash/blog")]).unwrap_err().to_string();
+        assert!(error.contains("KEY_PREFIX"), "{error}");
+    }
+
+    #[test]
+    fn nested_default_asset_keys_are_accepted() {
+        let config = config(&[("DEFAULT_ASSET_KEY", "defaults/missing.png")]).unwrap();
+        assert_eq!(config.default_asset_key.as_deref(), Some("defaults/missing.png"));
+    }
+
+    #[test]
+    fn default_asset_keys_leaving_the_bucket_are_refused() {
+        for key in ["../missing.png", "defaults/../../missing.png"] {
+            let error = config(&[("DEFAULT_ASSET_KEY", key)]).unwrap_err().to_string();
+            assert!(error.contains("DEFAULT_ASSET_KEY"), "{error}");
+        }
+    }
+}
@@ -0,0 +1,822 @@
+use std::collections::HashMap;
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, TryStreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::error;
+
+use crate::connections::storage::{
+    ByteStream, ListPage, ListQuery, ObjectInfo, Storage, StorageError,
+};
+use crate::metrics;
+
+/// Objects returned by one `list` call, like an S3 page
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Bytes read from a file at a time while streaming it
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Names temporary files, unique within the process
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps objects as plain files under `STORAGE_FS_ROOT`, for development
+/// and single node installs, `STORAGE_BACKEND=fs`.
+///
+/// An object is `<root>/<bucket>/<key>`, and what S3 would keep alongside it
+/// is a JSON sidecar at `<root>/.meta/<bucket>/<key>.json`. Writes go to
+/// `<root>/.tmp` first and are renamed into place, so readers never see a
+/// partial object.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Sidecar {
+    etag: String,
+    content_type: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn bucket_path(&self, bucket: &str) -> Result<PathBuf, StorageError> {
+        Ok(self.root.join(relative(bucket)?))
+    }
+
+    fn object_path(&self, bucket: &str, key: &str) -> Result<PathBuf, StorageError> {
+        Ok(self.bucket_path(bucket)?.join(relative(key)?))
+    }
+
+    fn sidecar_path(&self, bucket: &str, key: &str) -> Result<PathBuf, StorageError> {
+        let mut path = self.root.join(".meta").join(relative(bucket)?).join(relative(key)?);
+        path.as_mut_os_string().push(".json");
+        Ok(path)
+    }
+
+    async fn read_sidecar(&self, bucket: &str, key: &str) -> Result<Sidecar, StorageError> {
+        match tokio::fs::read(self.sidecar_path(bucket, key)?).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|why| io_error("read_metadata", bucket, key, why.into())),
+            // Files copied in by hand have none
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Sidecar::default()),
+            Err(why) => Err(io_error("read_metadata", bucket, key, why)),
+        }
+    }
+
+    async fn info(&self, bucket: &str, key: &str) -> Result<ObjectInfo, StorageError> {
+        let path = self.object_path(bucket, key)?;
+        let file = tokio::fs::metadata(&path)
+            .await
+            .map_err(|why| io_error("stat_object", bucket, key, why))?;
+        if !file.is_file() {
+            return Err(StorageError::NotFound);
+        }
+        let sidecar = self.read_sidecar(bucket, key).await?;
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: file.len(),
+            etag: stored_etag(sidecar.etag, &file),
+            last_modified: file.modified().ok().map(DateTime::<Utc>::from),
+            content_type: sidecar.content_type,
+            metadata: sidecar.metadata,
+        })
+    }
+
+    async fn write_sidecar(
+        &self,
+        operation: &str,
+        bucket: &str,
+        key: &str,
+        sidecar: &Sidecar,
+    ) -> Result<(), StorageError> {
+        let contents = serde_json::to_vec(sidecar)
+            .map_err(|why| io_error(operation, bucket, key, why.into()))?;
+        self.write_atomic(&self.sidecar_path(bucket, key)?, &contents)
+            .await
+            .map_err(|why| io_error(operation, bucket, key, why))
+    }
+
+    async fn ensure_bucket(&self, bucket: &str) -> Result<(), StorageError> {
+        if tokio::fs::try_exists(self.bucket_path(bucket)?).await.unwrap_or(false) {
+            Ok(())
+        } else {
+            Err(StorageError::NotFound)
+        }
+    }
+
+    /// A path in `<root>/.tmp` nothing else writes to
+    async fn tmp_path(&self) -> io::Result<PathBuf> {
+        let tmp_dir = self.root.join(".tmp");
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        Ok(tmp_dir.join(format!(
+            "{}-{}",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+
+    /// Write `contents` to a temporary file and move it to `path`
+    async fn write_atomic(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let tmp = self.tmp_path().await?;
+        tokio::fs::write(&tmp, contents).await?;
+        move_into_place(&tmp, path).await
+    }
+}
+
+/// Move a finished temporary file to `path`, removing it if that fails
+async fn move_into_place(tmp: &Path, path: &Path) -> io::Result<()> {
+    let moved = match path.parent() {
+        Some(parent) => tokio::fs::create_dir_all(parent).await,
+        None => Ok(()),
+    };
+    if let Err(why) = match moved {
+        Ok(()) => tokio::fs::rename(tmp, path).await,
+        Err(why) => Err(why),
+    } {
+        let _ = tokio::fs::remove_file(tmp).await;
+        return Err(why);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for FsStorage {
+    async fn get(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<(ObjectInfo, ByteStream), StorageError> {
+        let info = self.info(bucket, key).await?;
+        let mut file = tokio::fs::File::open(self.object_path(bucket, key)?)
+            .await
+            .map_err(|why| io_error("get_object", bucket, key, why))?;
+        let (offset, length) = range.unwrap_or((0, info.size));
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|why| io_error("get_object", bucket, key, why))?;
+
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        let chunks = stream::try_unfold(file.take(length), |mut reader| async move {
+            let mut chunk = BytesMut::with_capacity(READ_CHUNK_BYTES);
+            let read = reader.read_buf(&mut chunk).await?;
+            Ok((read > 0).then(|| (chunk.freeze(), reader)))
+        });
+        let chunks = chunks.map_err(move |why| io_error("get_object", &bucket, &key, why));
+        Ok((info, Box::pin(chunks)))
+    }
+
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        contents: Bytes,
+        content_type: Option<&str>,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<ObjectInfo, StorageError> {
+        self.ensure_bucket(bucket).await?;
+
+        // Same etag a single part S3 upload gets
+        let sidecar = Sidecar {
+            etag: format!("{:x}", md5::compute(&contents)),
+            content_type: content_type.map(str::to_string),
+            metadata: metadata.clone(),
+            tags: tags.clone(),
+        };
+        // The sidecar goes first, an object is never without its content type
+        self.write_sidecar("put_object", bucket, key, &sidecar).await?;
+        self.write_atomic(&self.object_path(bucket, key)?, &contents)
+            .await
+            .map_err(|why| io_error("put_object", bucket, key, why))?;
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: contents.len() as u64,
+            etag: sidecar.etag,
+            last_modified: Some(Utc::now()),
+            content_type: sidecar.content_type,
+            metadata: sidecar.metadata,
+        })
+    }
+
+    async fn put_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        mut contents: ByteStream,
+        _size: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<ObjectInfo, StorageError> {
+        self.ensure_bucket(bucket).await?;
+        let path = self.object_path(bucket, key)?;
+
+        let tmp = self
+            .tmp_path()
+            .await
+            .map_err(|why| io_error("put_object", bucket, key, why))?;
+        let mut written = 0u64;
+        let mut digest = md5::Context::new();
+        let copied: Result<(), StorageError> = async {
+            let mut file = tokio::fs::File::create(&tmp)
+                .await
+                .map_err(|why| io_error("put_object", bucket, key, why))?;
+            while let Some(chunk) = contents.next().await {
+                let chunk = chunk?;
+                digest.consume(&chunk);
+                written += chunk.len() as u64;
+                file.write_all(&chunk)
+                    .await
+                    .map_err(|why| io_error("put_object", bucket, key, why))?;
+            }
+            file.flush().await.map_err(|why| io_error("put_object", bucket, key, why))
+        }
+        .await;
+        if let Err(why) = copied {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(why);
+        }
+
+        let sidecar = Sidecar {
+            etag: format!("{:x}", digest.compute()),
+            content_type: content_type.map(str::to_string),
+            ..Sidecar::default()
+        };
+        if let Err(why) = self.write_sidecar("put_object", bucket, key, &sidecar).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(why);
+        }
+        move_into_place(&tmp, &path)
+            .await
+            .map_err(|why| io_error("put_object", bucket, key, why))?;
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: written,
+            etag: sidecar.etag,
+            last_modified: Some(Utc::now()),
+            content_type: sidecar.content_type,
+            metadata: HashMap::new(),
+        })
+    }
+
+    async fn stat(&self, bucket: &str, key: &str) -> Result<ObjectInfo, StorageError> {
+        self.info(bucket, key).await
+    }
+
+    async fn list(&self, bucket: &str, query: &ListQuery) -> Result<ListPage, StorageError> {
+        let bucket_path = self.bucket_path(bucket)?;
+        let walked = tokio::task::spawn_blocking(move || walk(&bucket_path))
+            .await
+            .map_err(|why| StorageError::Failed(why.into()))?
+            .map_err(|why| io_error("list_objects", bucket, &query.prefix, why))?;
+
+        let mut keys: Vec<_> = walked
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(&query.prefix))
+            .filter(|(key, _)| query.start_after.as_ref().is_none_or(|after| key > after))
+            .collect();
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Keys sharing a common prefix come out as one entry, in key order
+        let mut entries = Vec::new();
+        for (key, file) in keys {
+            let delimiter = query.delimiter.as_deref().filter(|delimiter| !delimiter.is_empty());
+            let common_prefix = delimiter.and_then(|delimiter| {
+                let rest = &key[query.prefix.len()..];
+                let end = rest.find(delimiter)? + delimiter.len();
+                Some(format!("{}{}", query.prefix, &rest[..end]))
+            });
+            match common_prefix {
+                Some(common_prefix) => {
+                    if !matches!(entries.last(), Some(Entry::Prefix(last)) if *last == common_prefix) {
+                        entries.push(Entry::Prefix(common_prefix));
+                    }
+                }
+                None => entries.push(Entry::Object(key, file)),
+            }
+        }
+
+        // Tokens are the last key or common prefix of the previous page
+        if let Some(after) = &query.continuation_token {
+            entries.retain(|entry| entry.name() > after.as_str());
+        }
+
+        let page_size = query
+            .max_keys
+            .map_or(LIST_PAGE_SIZE, |max_keys| usize::from(max_keys).clamp(1, LIST_PAGE_SIZE));
+        let next_token = if entries.len() > page_size {
+            entries.truncate(page_size);
+            entries.last().map(|entry| entry.name().to_string())
+        } else {
+            None
+        };
+
+        let mut objects = Vec::new();
+        let mut common_prefixes = Vec::new();
+        for entry in entries {
+            match entry {
+                Entry::Prefix(prefix) => common_prefixes.push(prefix),
+                Entry::Object(key, file) => {
+                    let sidecar = self.read_sidecar(bucket, &key).await?;
+                    objects.push(ObjectInfo {
+                        key,
+                        size: file.len(),
+                        etag: stored_etag(sidecar.etag, &file),
+                        last_modified: file.modified().ok().map(DateTime::<Utc>::from),
+                        content_type: None,
+                        metadata: HashMap::new(),
+                    });
+                }
+            }
+        }
+        Ok(ListPage {
+            objects,
+            common_prefixes,
+            next_token,
+        })
+    }
+
+    async fn tags(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        self.info(bucket, key).await?;
+        Ok(self.read_sidecar(bucket, key).await?.tags)
+    }
+
+    async fn set_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), StorageError> {
+        let info = self.info(bucket, key).await?;
+        let mut sidecar = self.read_sidecar(bucket, key).await?;
+        // Keep the etag a file copied in by hand was served with
+        sidecar.etag = info.etag;
+        sidecar.tags = tags.clone();
+        self.write_sidecar("put_object_tagging", bucket, key, &sidecar).await
+    }
+
+    async fn copy(
+        &self,
+        source_bucket: &str,
+        source_key: &str,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(), StorageError> {
+        let info = self.info(source_bucket, source_key).await?;
+        self.ensure_bucket(bucket).await?;
+        let mut sidecar = self.read_sidecar(source_bucket, source_key).await?;
+        sidecar.etag = info.etag;
+
+        let tmp = self
+            .tmp_path()
+            .await
+            .map_err(|why| io_error("copy_object", bucket, key, why))?;
+        if let Err(why) = tokio::fs::copy(self.object_path(source_bucket, source_key)?, &tmp).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(io_error("copy_object", source_bucket, source_key, why));
+        }
+        if let Err(why) = self.write_sidecar("copy_object", bucket, key, &sidecar).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(why);
+        }
+        move_into_place(&tmp, &self.object_path(bucket, key)?)
+            .await
+            .map_err(|why| io_error("copy_object", bucket, key, why))
+    }
+
+    async fn remove(&self, bucket: &str, key: &str) -> Result<(), StorageError> {
+        for path in [self.object_path(bucket, key)?, self.sidecar_path(bucket, key)?] {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(why) if why.kind() == io::ErrorKind::NotFound => {}
+                Err(why) => return Err(io_error("delete_object", bucket, key, why)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, StorageError> {
+        tokio::fs::try_exists(self.bucket_path(bucket)?)
+            .await
+            .map_err(|why| io_error("bucket_exists", bucket, "", why))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> Result<(), StorageError> {
+        tokio::fs::create_dir_all(self.bucket_path(bucket)?)
+            .await
+            .map_err(|why| io_error("create_bucket", bucket, "", why))
+    }
+}
+
+/// One entry of a listing, before it is split into objects and prefixes
+enum Entry {
+    Object(String, std::fs::Metadata),
+    Prefix(String),
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        match self {
+            Entry::Object(key, _) | Entry::Prefix(key) => key,
+        }
+    }
+}
+
+/// `key` as a path that can't leave the directory it is joined to
+fn relative(key: &str) -> Result<&Path, StorageError> {
+    let path = Path::new(key);
+    let mut components = path.components().peekable();
+    if components.peek().is_none() || !components.all(|part| matches!(part, Component::Normal(_))) {
+        return Err(StorageError::NotFound);
+    }
+    Ok(path)
+}
+
+/// The etag recorded when the object was put, or one made up from the
+/// modification time and size of a file that was copied in by hand
+fn stored_etag(etag: String, file: &std::fs::Metadata) -> String {
+    if !etag.is_empty() {
+        return etag;
+    }
+    let modified = file
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("{:x}{:016x}", modified.as_nanos(), file.len())
+}
+
+/// Every file below `bucket_path` with its key, `/` separated
+fn walk(bucket_path: &Path) -> io::Result<Vec<(String, std::fs::Metadata)>> {
+    let mut found = Vec::new();
+    let mut pending = vec![bucket_path.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(why) if why.kind() == io::ErrorKind::NotFound && dir == bucket_path => {
+                return Err(why);
+            }
+            // Removed while walking
+            Err(why) if why.kind() == io::ErrorKind::NotFound => continue,
+            Err(why) => return Err(why),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_file() {
+                let Ok(relative) = path.strip_prefix(bucket_path) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                found.push((key, metadata));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Map an io error onto a `StorageError`, logging and counting anything but
+/// a missing file
+fn io_error(operation: &str, bucket: &str, key: &str, why: io::Error) -> StorageError {
+    if why.kind() == io::ErrorKind::NotFound {
+        return StorageError::NotFound;
+    }
+    metrics::record_storage_error(operation);
+    error!(bucket, key, operation, "Error accessing storage directory: {}", why);
+    StorageError::Failed(why.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in a fresh directory holding one bucket, removed on drop
+    struct TestStore {
+        storage: FsStorage,
+        root: PathBuf,
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    const BUCKET: &str = "assets";
+
+    async fn store(name: &str) -> TestStore {
+        let root = std::env::temp_dir().join(format!("fs-storage-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let storage = FsStorage::new(&root);
+        storage.create_bucket(BUCKET).await.unwrap();
+        TestStore { storage, root }
+    }
+
+    async fn put(storage: &FsStorage, key: &str, contents: &'static [u8]) -> ObjectInfo {
+        storage
+            .put(BUCKET, key, Bytes::from_static(contents), None, &HashMap::new(), &HashMap::new())
+            .await
+            .unwrap()
+    }
+
+    async fn read(storage: &FsStorage, key: &str, range: Option<(u64, u64)>) -> (ObjectInfo, Bytes) {
+        let (info, stream) = storage.get(BUCKET, key, range).await.unwrap();
+        (info, crate::connections::storage::collect_bytes(stream).await.unwrap())
+    }
+
+    fn keys(page: &ListPage) -> Vec<&str> {
+        page.objects.iter().map(|object| object.key.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn put_then_get_keeps_content_and_metadata() {
+        let store = store("roundtrip").await;
+        let metadata = HashMap::from([("sha256".to_string(), "abc".to_string())]);
+
+        let put = store
+            .storage
+            .put(
+                BUCKET,
+                "posts/a.png",
+                Bytes::from_static(b"contents"),
+                Some("image/png"),
+                &metadata,
+                &HashMap::new(),
+            )
+            .await
+            .unwrap();
+        let (info, contents) = read(&store.storage, "posts/a.png", None).await;
+
+        assert_eq!(contents, Bytes::from_static(b"contents"));
+        assert_eq!(info.size, 8);
+        assert_eq!(info.etag, put.etag);
+        assert_eq!(info.etag, format!("{:x}", md5::compute(b"contents")));
+        assert_eq!(info.content_type.as_deref(), Some("image/png"));
+        assert_eq!(info.metadata, metadata);
+        assert_eq!(store.storage.stat(BUCKET, "posts/a.png").await.unwrap().metadata, metadata);
+    }
+
+    #[tokio::test]
+    async fn get_streams_a_slice() {
+        let store = store("slice").await;
+        put(&store.storage, "a.txt", b"0123456789").await;
+
+        let (info, contents) = read(&store.storage, "a.txt", Some((2, 3))).await;
+
+        assert_eq!(contents, Bytes::from_static(b"234"));
+        assert_eq!(info.size, 10);
+    }
+
+    #[tokio::test]
+    async fn put_replaces_an_object() {
+        let store = store("replace").await;
+        put(&store.storage, "a.txt", b"first").await;
+        put(&store.storage, "a.txt", b"second").await;
+
+        let (_, contents) = read(&store.storage, "a.txt", None).await;
+
+        assert_eq!(contents, Bytes::from_static(b"second"));
+    }
+
+    #[tokio::test]
+    async fn missing_objects_and_buckets_are_not_found() {
+        let store = store("missing").await;
+
+        assert!(matches!(store.storage.stat(BUCKET, "a.txt").await, Err(StorageError::NotFound)));
+        assert!(matches!(store.storage.get(BUCKET, "a.txt", None).await, Err(StorageError::NotFound)));
+        assert!(matches!(
+            store
+                .storage
+                .put("other", "a.txt", Bytes::new(), None, &HashMap::new(), &HashMap::new())
+                .await,
+            Err(StorageError::NotFound)
+        ));
+        assert!(matches!(
+            store.storage.list("other", &ListQuery::default()).await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn keys_leaving_the_bucket_are_refused() {
+        let store = store("traversal").await;
+        std::fs::write(store.root.join("secret"), b"outside").unwrap();
+
+        for key in ["", "../secret", "a/../../secret", "/etc/passwd", "./a.txt"] {
+            assert!(
+                matches!(store.storage.get(BUCKET, key, None).await, Err(StorageError::NotFound)),
+                "{key:?} was read"
+            );
+            assert!(
+                matches!(
+                    store
+                        .storage
+                        .put(BUCKET, key, Bytes::new(), None, &HashMap::new(), &HashMap::new())
+                        .await,
+                    Err(StorageError::NotFound)
+                ),
+                "{key:?} was written"
+            );
+        }
+        assert!(matches!(store.storage.stat("..", "secret").await, Err(StorageError::NotFound)));
+        assert_eq!(std::fs::read(store.root.join("secret")).unwrap(), b"outside");
+    }
+
+    #[tokio::test]
+    async fn list_filters_by_prefix_in_key_order() {
+        let store = store("list").await;
+        for key in ["posts/b.png", "posts/a.png", "pages/c.png", "d.png"] {
+            put(&store.storage, key, b"x").await;
+        }
+
+        let all = store.storage.list(BUCKET, &ListQuery::default()).await.unwrap();
+        let posts = store.storage.list(BUCKET, &ListQuery::prefix("posts/")).await.unwrap();
+        let after = ListQuery {
+            continuation_token: Some("pages/c.png".to_string()),
+            ..ListQuery::default()
+        };
+        let after = store.storage.list(BUCKET, &after).await.unwrap();
+
+        assert_eq!(keys(&all), ["d.png", "pages/c.png", "posts/a.png", "posts/b.png"]);
+        assert_eq!(all.next_token, None);
+        assert_eq!(keys(&posts), ["posts/a.png", "posts/b.png"]);
+        assert_eq!(keys(&after), ["posts/a.png", "posts/b.png"]);
+    }
+
+    #[tokio::test]
+    async fn list_pages_through_large_buckets() {
+        let store = store("pages").await;
+        let bucket_path = store.root.join(BUCKET);
+        for index in 0..=LIST_PAGE_SIZE {
+            std::fs::write(bucket_path.join(format!("{index:05}.txt")), b"x").unwrap();
+        }
+
+        let first = store.storage.list(BUCKET, &ListQuery::default()).await.unwrap();
+        let second = ListQuery {
+            continuation_token: first.next_token.clone(),
+            ..ListQuery::default()
+        };
+        let second = store.storage.list(BUCKET, &second).await.unwrap();
+
+        assert_eq!(first.objects.len(), LIST_PAGE_SIZE);
+        assert_eq!(first.next_token.as_deref(), Some("00999.txt"));
+        assert_eq!(keys(&second), ["01000.txt"]);
+        assert_eq!(second.next_token, None);
+    }
+
+    #[tokio::test]
+    async fn files_copied_in_by_hand_are_served() {
+        let store = store("by-hand").await;
+        std::fs::write(store.root.join(BUCKET).join("a.txt"), b"by hand").unwrap();
+
+        let (info, contents) = read(&store.storage, "a.txt", None).await;
+
+        assert_eq!(contents, Bytes::from_static(b"by hand"));
+        assert_eq!(info.content_type, None);
+        assert!(!info.etag.is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_succeeds_whether_or_not_there_is_an_object() {
+        let store = store("remove").await;
+        put(&store.storage, "a.txt", b"x").await;
+
+        store.storage.remove(BUCKET, "a.txt").await.unwrap();
+        store.storage.remove(BUCKET, "a.txt").await.unwrap();
+
+        assert!(matches!(store.storage.stat(BUCKET, "a.txt").await, Err(StorageError::NotFound)));
+        assert!(!store.storage.sidecar_path(BUCKET, "a.txt").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn create_bucket_succeeds_when_it_exists() {
+        let store = store("buckets").await;
+
+        assert!(store.storage.bucket_exists(BUCKET).await.unwrap());
+        assert!(!store.storage.bucket_exists("other").await.unwrap());
+        store.storage.create_bucket(BUCKET).await.unwrap();
+        store.storage.create_bucket("other").await.unwrap();
+        assert!(store.storage.bucket_exists("other").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn list_with_a_delimiter_groups_folders() {
+        let store = store("delimiter").await;
+        for key in ["posts/a/1.png", "posts/a/2.png", "posts/b.png", "posts/c/3.png", "d.png"] {
+            put(&store.storage, key, b"x").await;
+        }
+        let query = |continuation_token| ListQuery {
+            prefix: "posts/".to_string(),
+            delimiter: Some("/".to_string()),
+            max_keys: Some(2),
+            continuation_token,
+            ..ListQuery::default()
+        };
+
+        let first = store.storage.list(BUCKET, &query(None)).await.unwrap();
+        let second = store.storage.list(BUCKET, &query(first.next_token.clone())).await.unwrap();
+
+        assert_eq!(first.common_prefixes, ["posts/a/"]);
+        assert_eq!(keys(&first), ["posts/b.png"]);
+        assert_eq!(first.next_token.as_deref(), Some("posts/b.png"));
+        assert_eq!(second.common_prefixes, ["posts/c/"]);
+        assert!(second.objects.is_empty());
+        assert_eq!(second.next_token, None);
+    }
+
+    #[tokio::test]
+    async fn tags_are_kept_and_replaced() {
+        let store = store("tags").await;
+        let tags = HashMap::from([("album".to_string(), "summer".to_string())]);
+        store
+            .storage
+            .put(BUCKET, "a.png", Bytes::from_static(b"x"), None, &HashMap::new(), &tags)
+            .await
+            .unwrap();
+
+        assert_eq!(store.storage.tags(BUCKET, "a.png").await.unwrap(), tags);
+        store.storage.set_tags(BUCKET, "a.png", &HashMap::new()).await.unwrap();
+        assert!(store.storage.tags(BUCKET, "a.png").await.unwrap().is_empty());
+        assert!(matches!(store.storage.tags(BUCKET, "b.png").await, Err(StorageError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn copy_keeps_content_metadata_and_tags() {
+        let store = store("copy").await;
+        let metadata = HashMap::from([("sha256".to_string(), "abc".to_string())]);
+        let tags = HashMap::from([("album".to_string(), "summer".to_string())]);
+        let put = store
+            .storage
+            .put(BUCKET, "a.png", Bytes::from_static(b"x"), Some("image/png"), &metadata, &tags)
+            .await
+            .unwrap();
+
+        store.storage.copy(BUCKET, "a.png", BUCKET, "copies/a.png").await.unwrap();
+        let (info, contents) = read(&store.storage, "copies/a.png", None).await;
+
+        assert_eq!(contents, Bytes::from_static(b"x"));
+        assert_eq!(info.etag, put.etag);
+        assert_eq!(info.content_type.as_deref(), Some("image/png"));
+        assert_eq!(info.metadata, metadata);
+        assert_eq!(store.storage.tags(BUCKET, "copies/a.png").await.unwrap(), tags);
+        assert!(matches!(
+            store.storage.copy(BUCKET, "missing.png", BUCKET, "b.png").await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn put_stream_writes_every_chunk() {
+        let store = store("stream").await;
+        let chunks: ByteStream = Box::pin(stream::iter([
+            Ok(Bytes::from_static(b"con")),
+            Ok(Bytes::from_static(b"tents")),
+        ]));
+
+        let put = store
+            .storage
+            .put_stream(BUCKET, "a.mp4", chunks, None, Some("video/mp4"))
+            .await
+            .unwrap();
+        let (info, contents) = read(&store.storage, "a.mp4", None).await;
+
+        assert_eq!(contents, Bytes::from_static(b"contents"));
+        assert_eq!(put.size, 8);
+        assert_eq!(info.etag, format!("{:x}", md5::compute(b"contents")));
+        assert_eq!(info.content_type.as_deref(), Some("video/mp4"));
+    }
+
+    #[tokio::test]
+    async fn failed_put_stream_leaves_no_object() {
+        let store = store("stream-failure").await;
+        let chunks: ByteStream = Box::pin(stream::iter([
+            Ok(Bytes::from_static(b"partial")),
+            Err(StorageError::Failed(anyhow::anyhow!("client went away"))),
+        ]));
+
+        let put = store.storage.put_stream(BUCKET, "a.mp4", chunks, None, None).await;
+
+        assert!(matches!(put, Err(StorageError::Failed(_))));
+        assert!(matches!(store.storage.stat(BUCKET, "a.mp4").await, Err(StorageError::NotFound)));
+    }
+}
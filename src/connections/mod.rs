@@ -1,3 +1,6 @@
 pub use object_storage::ObjectStorage;
+pub use storage::{SharedStorage, Storage};
 
+pub mod fs_storage;
 pub mod object_storage;
+pub mod storage;
@@ -1,20 +1,644 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
 use std::ops::{Deref, DerefMut};
 
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use minio::s3::builders::{CopySource, ObjectContent, ObjectToDelete, Size};
+use minio::s3::error::ErrorCode;
+use minio::s3::multimap::Multimap;
+use minio::s3::response::DeleteResult;
+use minio::s3::segmented_bytes::SegmentedBytes;
+use futures_util::{Stream, StreamExt};
+use minio::s3::types::{S3Api, ToStream};
 use minio::s3::{creds::StaticProvider, http::BaseUrl, Client as MinioClient, ClientBuilder};
+use poem::http::Method;
+use tracing::{error, warn};
 
-pub const ASSETS_FILE_BUCKET: &str = "assets-files";
+use crate::config::AppConfig;
+use crate::connections::storage::{
+    ByteStream, ListPage, ListQuery, ObjectInfo, ObjectVersion, PresignedUrl, Storage,
+    StorageError, UNVERSIONED,
+};
+use crate::metrics;
+
+/// Size of the parts streamed uploads are sent to MinIO in, which bounds the
+/// memory one upload holds. 10000 parts of this size allow about 160 GiB.
+pub(crate) const STREAM_PART_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Most keys S3 accepts in a single multi-object delete
+const MAX_DELETE_BATCH_SIZE: usize = 1000;
 
 #[derive(Clone)]
-pub struct ObjectStorage(MinioClient);
+pub struct ObjectStorage {
+    client: MinioClient,
+    /// `STORAGE_TIMEOUT_SECS`, none when it is 0
+    timeout: Option<std::time::Duration>,
+    /// `STORAGE_RETRIES`
+    retries: u32,
+    /// `STORAGE_RETRY_BACKOFF_MS`
+    retry_backoff_ms: u64,
+}
 
 impl ObjectStorage {
-    pub fn new(url: String, access_key: String, secret: String) -> Result<Self, minio::s3::error::Error> {
-        let provider = StaticProvider::new(&access_key, &secret, None);
+    /// Client for the configured MinIO or S3 endpoint. The region,
+    /// addressing style and TLS are derived from `MINIO_URL` unless
+    /// overridden.
+    #[allow(clippy::result_large_err)]
+    pub fn new(config: &AppConfig) -> Result<Self, minio::s3::error::Error> {
+        let provider = StaticProvider::new(&config.minio_access, &config.minio_secret, None);
+
+        let mut base_url = config.minio_url.parse::<BaseUrl>()?;
+        if let Some(region) = &config.minio_region {
+            base_url.region = region.clone();
+        }
+        if let Some(path_style) = config.minio_path_style {
+            base_url.virtual_style = !path_style;
+        }
+        if let Some(tls) = config.minio_tls {
+            base_url.https = tls;
+        }
+
+        let client = ClientBuilder::new(base_url)
+            .provider(Some(Box::new(provider)))
+            .ssl_cert_file(config.minio_ca_bundle.as_deref())
+            .build()?;
+
+        Ok(Self {
+            client,
+            timeout: (config.storage_timeout_secs > 0)
+                .then(|| std::time::Duration::from_secs(config.storage_timeout_secs)),
+            retries: config.storage_retries,
+            retry_backoff_ms: config.storage_retry_backoff_ms,
+        })
+    }
+
+    /// Send a request that is safe to repeat, trying it again up to
+    /// `STORAGE_RETRIES` times while it fails with a transient error. The
+    /// pause starts at `STORAGE_RETRY_BACKOFF_MS` and doubles with every retry.
+    async fn with_retries<T, F, Fut>(
+        &self,
+        operation: &str,
+        mut request: F,
+    ) -> Result<T, minio::s3::error::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, minio::s3::error::Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(why) if attempt < self.retries && is_transient(&why) => {
+                    let backoff = self.retry_backoff_ms.saturating_mul(1 << attempt.min(16));
+                    attempt += 1;
+                    metrics::record_storage_retry(operation);
+                    warn!(
+                        operation,
+                        attempt,
+                        backoff_ms = backoff,
+                        "Retrying storage request: {}",
+                        why
+                    );
+                    tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Look up the region of `bucket` ahead of a request, bounded by the
+    /// timeout. The client would otherwise look it up on first use while
+    /// blocking the thread, where a hung server can't be timed out. The
+    /// region is cached after that, or known up front with `MINIO_REGION`.
+    async fn resolve_region(
+        &self,
+        operation: &str,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(), StorageError> {
+        with_timeout(self.timeout, self.client.get_region_cached_async(bucket, &None))
+            .await
+            .map(|_| ())
+            .map_err(|why| storage_error(operation, bucket, key, why))
+    }
+}
+
+/// The MinIO backend, `STORAGE_BACKEND=minio`. Reads are retried, and
+/// every request but server-side copies and streamed uploads is bounded by
+/// `STORAGE_TIMEOUT_SECS`.
+#[async_trait]
+impl Storage for ObjectStorage {
+    async fn get(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<(ObjectInfo, ByteStream), StorageError> {
+        self.get_version(bucket, key, None, range).await
+    }
+
+    async fn get_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        range: Option<(u64, u64)>,
+    ) -> Result<(ObjectInfo, ByteStream), StorageError> {
+        self.resolve_region("get_object", bucket, key).await?;
+        let response = self
+            .with_retries("get_object", || {
+                self.get_object(bucket, key)
+                    .version_id(version_id.map(str::to_string))
+                    .offset(range.map(|(offset, _)| offset))
+                    .length(range.map(|(_, length)| length))
+                    .send_timeout(self.timeout)
+            })
+            .await
+            .map_err(|why| storage_error("get_object", bucket, key, why))?;
+
+        let header = |name: &str| {
+            response
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let metadata = response
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix("x-amz-meta-")?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        // A slice answers with its own length, the whole size follows the
+        // `/` of `Content-Range: bytes 0-99/1234`
+        let size = header("content-range")
+            .and_then(|value| value.rsplit_once('/')?.1.parse().ok())
+            .unwrap_or(response.object_size);
+        let info = ObjectInfo {
+            key: key.to_string(),
+            size,
+            etag: response.etag.clone().unwrap_or_default().trim_matches('"').to_string(),
+            last_modified: header("last-modified")
+                .and_then(|value| DateTime::parse_from_rfc2822(&value).ok())
+                .map(|value| value.with_timezone(&Utc)),
+            content_type: header("content-type"),
+            metadata,
+        };
+
+        let read_error = {
+            let (bucket, key) = (bucket.to_string(), key.to_string());
+            move |why: io::Error| {
+                metrics::record_storage_error("get_object");
+                error!(bucket, key, "Error reading object: {}", why);
+                StorageError::Failed(why.into())
+            }
+        };
+        let (stream, _) = response.content.to_stream().await.map_err(read_error.clone())?;
+        Ok((info, Box::pin(stream.map(move |chunk| chunk.map_err(read_error.clone())))))
+    }
+
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        contents: Bytes,
+        content_type: Option<&str>,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<ObjectInfo, StorageError> {
+        self.resolve_region("put_object", bucket, key).await?;
+        let size = contents.len() as u64;
+        let mut user_metadata = Multimap::new();
+        for (name, value) in metadata {
+            user_metadata.insert(format!("x-amz-meta-{name}"), value.clone());
+        }
+        let mut headers = Multimap::new();
+        if let Some(content_type) = content_type {
+            headers.insert("Content-Type".to_string(), content_type.to_string());
+        }
+
+        let response = self
+            .put_object(bucket, key, SegmentedBytes::from(contents))
+            .user_metadata(Some(user_metadata))
+            .extra_headers(Some(headers))
+            .tags(Some(tags.clone()).filter(|tags| !tags.is_empty()))
+            .send_timeout(self.timeout)
+            .await
+            .map_err(|why| storage_error("put_object", bucket, key, why))?;
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size,
+            etag: response.etag.trim_matches('"').to_string(),
+            last_modified: Some(Utc::now()),
+            content_type: content_type.map(str::to_string),
+            metadata: metadata.clone(),
+        })
+    }
+
+    async fn put_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        contents: ByteStream,
+        size: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<ObjectInfo, StorageError> {
+        self.resolve_region("put_object_content", bucket, key).await?;
+        let contents = contents.map(|chunk| chunk.map_err(io::Error::other));
+        let mut request = self
+            .put_object_content(bucket, key, ObjectContent::new_from_stream(contents, size))
+            .part_size(Size::Known(STREAM_PART_BYTES));
+        if let Some(content_type) = content_type {
+            request = request.content_type(content_type.to_string());
+        }
+
+        // Not bounded, how long it takes depends on the client sending it
+        let response = request
+            .send()
+            .await
+            .map_err(|why| storage_error("put_object_content", bucket, key, why))?;
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: response.object_size,
+            etag: response.etag.trim_matches('"').to_string(),
+            last_modified: Some(Utc::now()),
+            content_type: content_type.map(str::to_string),
+            metadata: HashMap::new(),
+        })
+    }
+
+    async fn stat(&self, bucket: &str, key: &str) -> Result<ObjectInfo, StorageError> {
+        self.stat_version(bucket, key, None).await
+    }
+
+    async fn stat_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<ObjectInfo, StorageError> {
+        self.resolve_region("stat_object", bucket, key).await?;
+        let response = self
+            .with_retries("stat_object", || {
+                self.stat_object(bucket, key)
+                    .version_id(version_id.map(str::to_string))
+                    .send_timeout(self.timeout)
+            })
+            .await
+            .map_err(|why| storage_error("stat_object", bucket, key, why))?;
+
+        Ok(ObjectInfo {
+            key: key.to_string(),
+            size: response.size,
+            etag: response.etag.trim_matches('"').to_string(),
+            last_modified: response.last_modified,
+            content_type: response
+                .headers
+                .get("content-type")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            metadata: response.user_metadata,
+        })
+    }
+
+    async fn versions(&self, bucket: &str, key: &str) -> Result<Vec<ObjectVersion>, StorageError> {
+        self.resolve_region("list_objects", bucket, key).await?;
+        let mut stream = self
+            .list_objects(bucket)
+            .recursive(true)
+            .prefix(Some(key.to_string()))
+            .include_versions(true)
+            .to_stream()
+            .await;
+
+        // The prefix also matches longer keys, only exact matches count
+        let mut versions = Vec::new();
+        while let Some(result) = next_page(self.timeout, &mut stream).await {
+            let response = result.map_err(|why| storage_error("list_objects", bucket, key, why))?;
+            for entry in response.contents {
+                if entry.name != key {
+                    continue;
+                }
+                versions.push(ObjectVersion {
+                    version_id: entry.version_id.unwrap_or_else(|| UNVERSIONED.to_string()),
+                    is_latest: entry.is_latest,
+                    is_delete_marker: entry.is_delete_marker,
+                    size: entry.size,
+                    last_modified: entry.last_modified,
+                });
+            }
+        }
+        Ok(versions)
+    }
+
+    async fn list(&self, bucket: &str, query: &ListQuery) -> Result<ListPage, StorageError> {
+        self.resolve_region("list_objects", bucket, &query.prefix).await?;
+        let mut stream = self
+            .list_objects(bucket)
+            .recursive(query.delimiter.is_none())
+            .prefix(Some(query.prefix.clone()).filter(|prefix| !prefix.is_empty()))
+            .delimiter(query.delimiter.clone())
+            .disable_url_encoding(true)
+            .use_api_v1(false) // use v2
+            .max_keys(query.max_keys)
+            .continuation_token(query.continuation_token.clone())
+            .start_after(query.start_after.clone())
+            .to_stream()
+            .await;
+
+        // The stream would go on to the following pages, only one is wanted
+        let Some(result) = next_page(self.timeout, &mut stream).await else {
+            return Ok(ListPage {
+                objects: Vec::new(),
+                common_prefixes: Vec::new(),
+                next_token: None,
+            });
+        };
+        let response =
+            result.map_err(|why| storage_error("list_objects", bucket, &query.prefix, why))?;
+
+        let mut objects = Vec::new();
+        let mut common_prefixes = Vec::new();
+        for entry in response.contents {
+            if entry.is_prefix {
+                common_prefixes.push(entry.name);
+                continue;
+            }
+            objects.push(ObjectInfo {
+                key: entry.name,
+                size: entry.size.unwrap_or_default(),
+                etag: entry.etag.unwrap_or_default().trim_matches('"').to_string(),
+                last_modified: entry.last_modified,
+                content_type: None,
+                metadata: HashMap::new(),
+            });
+        }
+        Ok(ListPage {
+            objects,
+            common_prefixes,
+            next_token: if response.is_truncated {
+                response.next_continuation_token
+            } else {
+                None
+            },
+        })
+    }
 
-        
-        let  client = ClientBuilder::new(url.parse::<BaseUrl>()?).provider(Some(Box::new(provider))).build()?;
+    async fn tags(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>, StorageError> {
+        self.resolve_region("get_object_tagging", bucket, key).await?;
+        self.with_retries("get_object_tagging", || {
+            self.get_object_tagging(bucket, key).send_timeout(self.timeout)
+        })
+        .await
+        .map(|response| response.tags)
+        .map_err(|why| storage_error("get_object_tagging", bucket, key, why))
+    }
+
+    async fn set_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), StorageError> {
+        self.resolve_region("put_object_tagging", bucket, key).await?;
+        self.put_object_tagging(bucket, key)
+            .tags(tags.clone())
+            .send_timeout(self.timeout)
+            .await
+            .map(|_| ())
+            .map_err(|why| storage_error("put_object_tagging", bucket, key, why))
+    }
+
+    async fn copy(
+        &self,
+        source_bucket: &str,
+        source_key: &str,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(), StorageError> {
+        self.resolve_region("copy_object", source_bucket, source_key).await?;
+        self.resolve_region("copy_object", bucket, key).await?;
+        let source = CopySource::new(source_bucket, source_key)
+            .map_err(|why| storage_error("copy_object", source_bucket, source_key, why))?;
+        // Not bounded, copying a large object can take a while
+        self.copy_object(bucket, key)
+            .source(source)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|why| storage_error("copy_object", bucket, key, why))
+    }
+
+    async fn remove(&self, bucket: &str, key: &str) -> Result<(), StorageError> {
+        self.resolve_region("delete_object", bucket, key).await?;
+        match self.delete_object(bucket, key).send_timeout(self.timeout).await {
+            Ok(_) => Ok(()),
+            Err(why) if is_not_found(&why) => Ok(()),
+            Err(why) => Err(storage_error("delete_object", bucket, key, why)),
+        }
+    }
+
+    async fn remove_many(&self, bucket: &str, keys: &[String]) -> HashMap<String, String> {
+        if let Err(why) = self.resolve_region("delete_objects", bucket, "").await {
+            return keys.iter().map(|key| (key.clone(), why.to_string())).collect();
+        }
+        let mut failures = HashMap::new();
+        for chunk in keys.chunks(MAX_DELETE_BATCH_SIZE) {
+            let objects = chunk.iter().map(|key| ObjectToDelete::from(key.as_str())).collect();
+
+            // Quiet mode only reports the keys that could not be deleted
+            match self
+                .delete_objects::<_, ObjectToDelete>(bucket, objects)
+                .send_timeout(self.timeout)
+                .await
+            {
+                Ok(response) => {
+                    for result in response.result {
+                        if let DeleteResult::Error(error) = result {
+                            failures.insert(error.object_name, error.message);
+                        }
+                    }
+                }
+                Err(why) => {
+                    metrics::record_storage_error("delete_objects");
+                    error!(bucket, "Error deleting objects: {}", why);
+                    for key in chunk {
+                        failures.insert(key.clone(), why.to_string());
+                    }
+                }
+            }
+        }
+        failures
+    }
+
+    async fn presigned_url(
+        &self,
+        bucket: &str,
+        key: &str,
+        method: Method,
+        expiry_seconds: u32,
+    ) -> Result<PresignedUrl, StorageError> {
+        self.resolve_region("presign", bucket, key).await?;
+        let request_time = Utc::now();
+
+        let response = self
+            .get_presigned_object_url(bucket, key, method)
+            .expiry_seconds(expiry_seconds)
+            .request_time(request_time)
+            .send()
+            .await
+            .map_err(|why| storage_error("presign", bucket, key, why))?;
+
+        Ok(PresignedUrl {
+            url: response.url,
+            expires_at: request_time + Duration::seconds(expiry_seconds.into()),
+        })
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, StorageError> {
+        self.resolve_region("bucket_exists", bucket, "").await?;
+        // `self.bucket_exists` would be this method rather than the client's
+        self.client
+            .bucket_exists(bucket)
+            .send_timeout(self.timeout)
+            .await
+            .map(|response| response.exists)
+            .map_err(|why| storage_error("bucket_exists", bucket, "", why))
+    }
+
+    async fn create_bucket(&self, bucket: &str) -> Result<(), StorageError> {
+        match self.client.create_bucket(bucket).send_timeout(self.timeout).await {
+            Ok(_) => Ok(()),
+            // Another replica booting at the same time got there first
+            Err(minio::s3::error::Error::S3Error(response))
+                if response.code == ErrorCode::BucketAlreadyOwnedByYou =>
+            {
+                Ok(())
+            }
+            Err(why) => Err(storage_error("create_bucket", bucket, "", why)),
+        }
+    }
+}
+
+/// Map a failed MinIO request onto a `StorageError`, logging and counting
+/// anything but a missing object
+fn storage_error(
+    operation: &str,
+    bucket: &str,
+    key: &str,
+    why: minio::s3::error::Error,
+) -> StorageError {
+    if is_not_found(&why) {
+        return StorageError::NotFound;
+    }
+    metrics::record_storage_error(operation);
+    error!(bucket, key, operation, "Error accessing object storage: {}", why);
+    if is_storage_timeout(&why) {
+        StorageError::Timeout
+    } else {
+        StorageError::Failed(why.into())
+    }
+}
+
+/// Fail `request` with a timeout error once it takes longer than `limit`,
+/// `ObjectStorage::timeout`, so a hung connection doesn't hold up a handler
+/// forever. Errors from it are answered with `504`.
+#[allow(clippy::result_large_err)]
+async fn with_timeout<T>(
+    limit: Option<std::time::Duration>,
+    request: impl Future<Output = Result<T, minio::s3::error::Error>>,
+) -> Result<T, minio::s3::error::Error> {
+    let Some(limit) = limit else {
+        return request.await;
+    };
+    tokio::time::timeout(limit, request).await.unwrap_or_else(|_| {
+        Err(minio::s3::error::Error::IOError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("object storage did not answer within {}s", limit.as_secs()),
+        )))
+    })
+}
+
+/// `send` bounded by `limit`, see `with_timeout`
+trait SendTimeout: S3Api + Send + Sized + 'static {
+    fn send_timeout(
+        self,
+        limit: Option<std::time::Duration>,
+    ) -> impl Future<Output = Result<Self::S3Response, minio::s3::error::Error>> {
+        with_timeout(limit, self.send())
+    }
+}
+
+impl<T: S3Api + Send + 'static> SendTimeout for T {}
+
+/// The next page of a listing, bounded by `limit` like single requests are
+async fn next_page<T>(
+    limit: Option<std::time::Duration>,
+    stream: &mut (impl Stream<Item = Result<T, minio::s3::error::Error>> + Unpin),
+) -> Option<Result<T, minio::s3::error::Error>> {
+    match with_timeout(limit, async { Ok(stream.next().await) }).await {
+        Ok(page) => page,
+        Err(why) => Some(Err(why)),
+    }
+}
+
+/// Whether a MinIO error is a request abandoned by `with_timeout`
+fn is_storage_timeout(error: &minio::s3::error::Error) -> bool {
+    matches!(error, minio::s3::error::Error::IOError(error) if error.kind() == io::ErrorKind::TimedOut)
+}
+
+/// Whether a MinIO error is likely to go away when the request is repeated:
+/// dropped or timed out connections, throttling and 5xx answers. Anything
+/// else, such as missing objects or denied access, fails the same way again.
+fn is_transient(error: &minio::s3::error::Error) -> bool {
+    use minio::s3::error::Error;
+
+    let is_transient_status = |status: u16| status == 429 || (500..600).contains(&status);
+    match error {
+        Error::HttpError(error) => {
+            error.is_connect()
+                || error.is_timeout()
+                || error.is_request()
+                || error.is_body()
+                || error.status().is_some_and(|status| is_transient_status(status.as_u16()))
+        }
+        // Requests that ran into the timeout aren't repeated, that would
+        // only multiply how long the client waits
+        Error::IOError(error) => error.kind() != io::ErrorKind::TimedOut,
+        Error::ServerError(status) | Error::InvalidResponse(status, _) => is_transient_status(*status),
+        Error::S3Error(response) => matches!(
+            &response.code,
+            ErrorCode::OtherError(code) if matches!(
+                code.as_str(),
+                "slowdown"
+                    | "internalerror"
+                    | "serviceunavailable"
+                    | "requesttimeout"
+                    | "xminioservernotinitialized"
+            )
+        ),
+        _ => false,
+    }
+}
 
-        Ok(Self(client))
+/// Whether a MinIO error means the requested object (or bucket, or version)
+/// does not exist
+fn is_not_found(error: &minio::s3::error::Error) -> bool {
+    match error {
+        minio::s3::error::Error::S3Error(response) => matches!(
+            response.code,
+            ErrorCode::NoSuchKey | ErrorCode::NoSuchBucket | ErrorCode::ResourceNotFound
+        ) || matches!(&response.code, ErrorCode::OtherError(code) if code == "nosuchversion"),
+        minio::s3::error::Error::HttpError(error) => {
+            error.status().is_some_and(|status| status.as_u16() == 404)
+        }
+        _ => false,
     }
 }
 
@@ -22,12 +646,12 @@ impl Deref for ObjectStorage {
     type Target = MinioClient;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.client
     }
 }
 
 impl DerefMut for ObjectStorage {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.client
     }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use poem::error::InternalServerError;
+use poem::http::Method;
+
+use crate::error::ApiError;
+
+/// Where objects are kept, independent of whether that is MinIO or a local
+/// directory. Keys are full object keys, including `KEY_PREFIX`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Metadata of an object and its content, or the `(offset, length)`
+    /// slice of it, streamed as it is read. `ObjectInfo::size` is the size
+    /// of the whole object.
+    async fn get(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<(ObjectInfo, ByteStream), StorageError>;
+
+    /// Like `get`, reading `version_id` instead of the latest version when
+    /// given. Backends without versioning only know the `null` version.
+    async fn get_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        range: Option<(u64, u64)>,
+    ) -> Result<(ObjectInfo, ByteStream), StorageError> {
+        match version_id {
+            None | Some(UNVERSIONED) => self.get(bucket, key, range).await,
+            Some(_) => Err(StorageError::NotFound),
+        }
+    }
+
+    /// Store `contents` under `key`, replacing any object there. `metadata`
+    /// keys are lowercase, without an `x-amz-meta-` prefix.
+    async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        contents: Bytes,
+        content_type: Option<&str>,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<ObjectInfo, StorageError>;
+
+    /// Like `put`, for contents too large to hold in memory. `size` is the
+    /// length when known up front. The content type is the only metadata,
+    /// nothing about the object is known before it has been read.
+    async fn put_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+        contents: ByteStream,
+        size: Option<u64>,
+        content_type: Option<&str>,
+    ) -> Result<ObjectInfo, StorageError>;
+
+    /// Metadata of an object without its content
+    async fn stat(&self, bucket: &str, key: &str) -> Result<ObjectInfo, StorageError>;
+
+    /// Like `stat`, for `version_id` instead of the latest version when given
+    async fn stat_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+    ) -> Result<ObjectInfo, StorageError> {
+        match version_id {
+            None | Some(UNVERSIONED) => self.stat(bucket, key).await,
+            Some(_) => Err(StorageError::NotFound),
+        }
+    }
+
+    /// Stored versions of an object, newest first, empty when there is none.
+    /// Backends without versioning list the current object as `null`.
+    async fn versions(&self, bucket: &str, key: &str) -> Result<Vec<ObjectVersion>, StorageError> {
+        match self.stat(bucket, key).await {
+            Ok(info) => Ok(vec![ObjectVersion {
+                version_id: UNVERSIONED.to_string(),
+                is_latest: true,
+                is_delete_marker: false,
+                size: Some(info.size),
+                last_modified: info.last_modified,
+            }]),
+            Err(StorageError::NotFound) => Ok(Vec::new()),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// A page of the objects `query` asks for, in key order
+    async fn list(&self, bucket: &str, query: &ListQuery) -> Result<ListPage, StorageError>;
+
+    /// Tags attached to an object
+    async fn tags(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>, StorageError>;
+
+    /// Replace the tags of an object
+    async fn set_tags(
+        &self,
+        bucket: &str,
+        key: &str,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), StorageError>;
+
+    /// Copy an object with its metadata and tags, replacing any object at
+    /// the destination. `NotFound` when there is no source.
+    async fn copy(
+        &self,
+        source_bucket: &str,
+        source_key: &str,
+        bucket: &str,
+        key: &str,
+    ) -> Result<(), StorageError>;
+
+    /// Delete an object, succeeding when there was none
+    async fn remove(&self, bucket: &str, key: &str) -> Result<(), StorageError>;
+
+    /// Delete several objects, answering the keys that could not be deleted
+    /// with why
+    async fn remove_many(&self, bucket: &str, keys: &[String]) -> HashMap<String, String> {
+        let mut failures = HashMap::new();
+        for key in keys {
+            if let Err(why) = self.remove(bucket, key).await {
+                failures.insert(key.clone(), why.to_string());
+            }
+        }
+        failures
+    }
+
+    /// URL letting its holder perform `method` on an object directly against
+    /// the backend for `expiry_seconds`
+    async fn presigned_url(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _method: Method,
+        _expiry_seconds: u32,
+    ) -> Result<PresignedUrl, StorageError> {
+        Err(StorageError::Unsupported("presigned URLs"))
+    }
+
+    async fn bucket_exists(&self, bucket: &str) -> Result<bool, StorageError>;
+
+    /// Create a bucket, succeeding when it exists already
+    async fn create_bucket(&self, bucket: &str) -> Result<(), StorageError>;
+}
+
+/// Version id S3 gives objects stored while versioning was off
+pub const UNVERSIONED: &str = "null";
+
+/// The backend chosen with `STORAGE_BACKEND`, shared with handlers as `Data`
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// Content of an object as the backend reads it
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, StorageError>> + Send>>;
+
+/// Buffer all of `stream`, for objects known to be small
+pub async fn collect_bytes(mut stream: ByteStream) -> Result<Bytes, StorageError> {
+    let mut contents = BytesMut::new();
+    while let Some(chunk) = stream.next().await {
+        contents.extend_from_slice(&chunk?);
+    }
+    Ok(contents.freeze())
+}
+
+/// What a backend knows about a stored object
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    /// Unquoted
+    pub etag: String,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub content_type: Option<String>,
+    /// User metadata, keys lowercase without the `x-amz-meta-` prefix. Left
+    /// empty in listings.
+    pub metadata: HashMap<String, String>,
+}
+
+/// Which objects `Storage::list` returns
+#[derive(Clone, Debug, Default)]
+pub struct ListQuery {
+    pub prefix: String,
+    /// Group keys containing it after the prefix into `common_prefixes`,
+    /// listing one level of a hierarchy. All keys below the prefix without.
+    pub delimiter: Option<String>,
+    /// Most objects and common prefixes on the page, the backend's own page
+    /// size when unset
+    pub max_keys: Option<u16>,
+    /// `next_token` of the previous page
+    pub continuation_token: Option<String>,
+    /// Only keys after this one, for the first page
+    pub start_after: Option<String>,
+}
+
+impl ListQuery {
+    /// Every object under `prefix`
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            ..Self::default()
+        }
+    }
+}
+
+pub struct ListPage {
+    pub objects: Vec<ObjectInfo>,
+    /// Keys up to the next delimiter, with it, when listing with one
+    pub common_prefixes: Vec<String>,
+    /// Pass back as `continuation_token` for the following page, `None` on
+    /// the last one
+    pub next_token: Option<String>,
+}
+
+/// One version of an object as `Storage::versions` lists it
+#[derive(Clone, Debug)]
+pub struct ObjectVersion {
+    pub version_id: String,
+    pub is_latest: bool,
+    pub is_delete_marker: bool,
+    pub size: Option<u64>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+pub struct PresignedUrl {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a storage request failed
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    /// The object, or its bucket, doesn't exist
+    #[error("object not found")]
+    NotFound,
+    /// The backend didn't answer within `STORAGE_TIMEOUT_SECS`
+    #[error("object storage did not answer in time")]
+    Timeout,
+    /// The backend can't do this, e.g. hand out presigned URLs
+    #[error("{0} are not supported by this storage backend")]
+    Unsupported(&'static str),
+    /// Anything else, already logged and counted
+    #[error(transparent)]
+    Failed(anyhow::Error),
+}
+
+impl From<StorageError> for poem::Error {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::NotFound => ApiError::not_found("object not found").into(),
+            StorageError::Timeout => ApiError::storage_timeout().into(),
+            StorageError::Unsupported(feature) => ApiError::not_supported(feature).into(),
+            StorageError::Failed(why) => InternalServerError(io::Error::other(why)),
+        }
+    }
+}
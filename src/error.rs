@@ -0,0 +1,294 @@
+use poem::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use poem::http::{HeaderValue, Method, StatusCode};
+use poem::error::ResponseError;
+use poem::{Endpoint, Error, IntoResponse, PathPattern, Request, Response, Result};
+use serde_json::json;
+use tracing::error;
+
+/// An error returned to clients as
+/// `{ "error": { "code": "...", "message": "..." } }`
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    /// The caller's token lacks the `action` permission on `resource`
+    pub fn missing_permission(action: &str, resource: &str) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "missing_permission",
+            format!("missing permission: {action} {resource}"),
+        )
+    }
+
+    /// An asset name that `sanitize_asset_name` or the naming policy refused
+    pub fn invalid_asset_name() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_asset_name",
+            "asset name is empty, too long or contains characters that are not allowed",
+        )
+    }
+
+    /// A multipart upload part without a `filename`
+    pub fn missing_filename() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "missing_filename",
+            "the upload part must include a filename in its Content-Disposition",
+        )
+    }
+
+    /// A form body that couldn't be read as multipart, or lacks a required
+    /// field such as `asset`
+    pub fn invalid_multipart(reason: impl std::fmt::Display) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_multipart",
+            format!("the request body is not a valid multipart form: {reason}"),
+        )
+    }
+
+    /// Upload metadata that isn't allowed, see `ALLOWED_METADATA_KEYS`
+    pub fn invalid_metadata(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_metadata", message)
+    }
+
+    /// An import URL the `URL_UPLOAD_*` policy refuses
+    pub fn url_not_allowed(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "url_not_allowed", message)
+    }
+
+    /// An import URL that couldn't be fetched
+    pub fn remote_unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "remote_unavailable", message)
+    }
+
+    /// A soft delete of an object too large for a server-side copy
+    pub fn too_large_for_trash() -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "too_large_for_trash",
+            "assets over 5 GiB can't be moved to the trash, delete them with permanent=true",
+        )
+    }
+
+    /// An upload whose stored checksum disagreed with the bytes sent, see
+    /// `VERIFY_UPLOADS`
+    pub fn upload_corrupted() -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "upload_corrupted",
+            "the stored asset did not match the uploaded bytes and was removed, retry the upload",
+        )
+    }
+
+    /// A garbage collection whose `confirmation` no longer matches the
+    /// assets it would delete
+    pub fn gc_confirmation_mismatch() -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "gc_confirmation_mismatch",
+            "the unreferenced assets changed since the dry run, run it again and use its confirmation",
+        )
+    }
+
+    /// A signed download link that has expired or doesn't match its asset
+    pub fn invalid_download_link() -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "invalid_download_link",
+            "the download link is invalid or has expired",
+        )
+    }
+
+    /// Signing a download link without `DOWNLOAD_LINK_SECRET` set
+    pub fn download_links_disabled() -> Self {
+        Self::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "download_links_disabled",
+            "signed download links are not configured",
+        )
+    }
+
+    /// Object storage didn't answer within `STORAGE_TIMEOUT_SECS`
+    pub fn storage_timeout() -> Self {
+        Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "storage_timeout",
+            "object storage did not answer in time",
+        )
+    }
+
+    /// Something the configured storage backend can't do
+    pub fn not_supported(feature: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "not_supported",
+            format!("{feature} are not supported by this storage backend"),
+        )
+    }
+
+    /// A retry arriving while the first request with its `Idempotency-Key`
+    /// is still being handled
+    pub fn idempotency_key_in_use() -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "idempotency_key_in_use",
+            "a request with this Idempotency-Key is still in progress, retry later",
+        )
+    }
+
+    /// An `Idempotency-Key` sent again for a different request
+    pub fn idempotency_key_reused() -> Self {
+        Self::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "idempotency_key_reused",
+            "this Idempotency-Key was already used for a different upload",
+        )
+    }
+
+    /// Error for a status that carries no more specific explanation
+    fn from_status(status: StatusCode) -> Self {
+        let reason = status.canonical_reason().unwrap_or("error").to_lowercase();
+        Self::new(status, status_code_name(status), reason)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn as_response(&self) -> Response {
+        let body = json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            }
+        });
+
+        Response::builder()
+            .status(self.status)
+            .content_type("application/json")
+            .body(body.to_string())
+    }
+}
+
+/// Machine readable code for statuses without an `ApiError` of their own
+fn status_code_name(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        StatusCode::RANGE_NOT_SATISFIABLE => "range_not_satisfiable",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        StatusCode::BAD_GATEWAY => "bad_gateway",
+        StatusCode::SERVICE_UNAVAILABLE => "service_unavailable",
+        StatusCode::GATEWAY_TIMEOUT => "gateway_timeout",
+        status if status.is_server_error() => "internal_error",
+        _ => "error",
+    }
+}
+
+/// Turn every error into the JSON envelope.
+///
+/// `ApiError`s render themselves. Errors raised by poem, such as parse or
+/// authorization failures, keep their message unless they're server errors,
+/// whose details are logged instead of leaked. Error statuses returned by
+/// handlers without a body get a generic message.
+pub async fn render_errors<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let is_head = req.method() == Method::HEAD;
+
+    let response = match next.call(req).await {
+        Ok(response) => response.into_response(),
+        Err(why) => {
+            let pattern = why.data::<PathPattern>().cloned();
+            let mut response = render_error(why);
+            if let Some(pattern) = pattern {
+                response.set_data(pattern);
+            }
+            response
+        }
+    };
+
+    let status = response.status();
+    if is_head || !(status.is_client_error() || status.is_server_error()) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    if !body.is_empty() {
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let mut envelope = ApiError::from_status(status).as_response();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, envelope.take_body()))
+}
+
+fn render_error(why: Error) -> Response {
+    if let Some(api_error) = why.downcast_ref::<ApiError>() {
+        return api_error.as_response();
+    }
+
+    // Reported by poem-openapi for missing or unparsable fields, and by poem
+    // for bodies that aren't multipart at all, e.g. truncated ones
+    if let Some(multipart) = why.downcast_ref::<poem_openapi::error::ParseMultipartError>() {
+        return ApiError::invalid_multipart(&multipart.reason).as_response();
+    }
+    if why.status() == StatusCode::BAD_REQUEST
+        && let Some(multipart) = why.downcast_ref::<poem::error::ParseMultipartError>()
+    {
+        return ApiError::invalid_multipart(multipart).as_response();
+    }
+
+    let status = why.status();
+    if status.is_server_error() {
+        error!("Error handling request: {}", why);
+        return ApiError::from_status(status).as_response();
+    }
+
+    let mut response =
+        ApiError::new(status, status_code_name(status), why.to_string()).as_response();
+    // Keep headers poem attached, e.g. `WWW-Authenticate` on a 401
+    let original = why.into_response();
+    for (name, value) in original.headers() {
+        if name != CONTENT_TYPE && name != CONTENT_LENGTH {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
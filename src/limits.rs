@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use once_cell::sync::OnceCell;
+use poem::error::ResponseError;
+use poem::http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use poem::http::{HeaderValue, StatusCode};
+use poem::{Body, Endpoint, IntoResponse, Request, Response, Result};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::config::AppConfig;
+use crate::error::ApiError;
+
+/// Probes, which have to answer even while the service is saturated
+const EXEMPT_PATHS: &[&str] = &["/healthcheck", "/readyz"];
+
+/// How long a request waits for a slot before it is turned away
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static PERMITS: OnceCell<Semaphore> = OnceCell::new();
+
+/// Slots for uploads that are read into memory before they are stored,
+/// `MAX_CONCURRENT_UPLOADS` of them. Shared with handlers as `Data`.
+#[derive(Clone)]
+pub struct UploadSlots(Option<Arc<Semaphore>>);
+
+/// A taken upload slot, given back when dropped
+pub struct UploadSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl UploadSlots {
+    pub fn new(config: &AppConfig) -> Self {
+        Self(config.max_concurrent_uploads.map(|limit| Arc::new(Semaphore::new(limit))))
+    }
+
+    /// Wait up to `QUEUE_TIMEOUT` for a free slot, `None` if none frees up
+    pub async fn acquire(&self) -> Option<UploadSlot> {
+        let Some(slots) = &self.0 else {
+            return Some(UploadSlot { _permit: None });
+        };
+        match tokio::time::timeout(QUEUE_TIMEOUT, slots.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Some(UploadSlot { _permit: Some(permit) }),
+            _ => {
+                debug!("turned away upload while at the upload concurrency limit");
+                None
+            }
+        }
+    }
+}
+
+/// Reject bodies over `MAX_REQUEST_BODY_BYTES` with 413 before any handler
+/// runs. Bodies without a `Content-Length` are cut off once they exceed the
+/// limit, failing the read in the handler.
+pub async fn limit_body_size<E: Endpoint>(next: E, mut req: Request) -> Result<Response> {
+    let Some(limit) = req
+        .data::<AppConfig>()
+        .and_then(|config| config.max_request_body_bytes)
+    else {
+        return next.call(req).await.map(IntoResponse::into_response);
+    };
+
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    match content_length {
+        Some(length) if length > limit => {
+            warn!(size = length, limit, "rejected oversized request body");
+            return Ok(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                format!("request body exceeds the limit of {limit} bytes"),
+            )
+            .as_response());
+        }
+        Some(_) => {}
+        None => {
+            let mut received = 0u64;
+            let body = req.take_body().into_bytes_stream().map(move |chunk| {
+                let chunk = chunk?;
+                received += chunk.len() as u64;
+                if received > limit {
+                    return Err(std::io::Error::other("request body exceeds the maximum size"));
+                }
+                Ok(chunk)
+            });
+            req.set_body(Body::from_bytes_stream(body));
+        }
+    }
+
+    next.call(req).await.map(IntoResponse::into_response)
+}
+
+/// Handle at most `MAX_CONCURRENT_REQUESTS` requests at once. Requests over
+/// the limit wait for a slot, and get 503 with `Retry-After` if none frees up
+/// within `QUEUE_TIMEOUT`.
+pub async fn limit_concurrency<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let Some(limit) = req
+        .data::<AppConfig>()
+        .and_then(|config| config.max_concurrent_requests)
+    else {
+        return next.call(req).await.map(IntoResponse::into_response);
+    };
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.call(req).await.map(IntoResponse::into_response);
+    }
+
+    let permits = PERMITS.get_or_init(|| Semaphore::new(limit));
+    let _permit = match tokio::time::timeout(QUEUE_TIMEOUT, permits.acquire()).await {
+        Ok(Ok(permit)) => permit,
+        _ => {
+            debug!(limit, "turned away request while at the concurrency limit");
+            let mut response = ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too_many_concurrent_requests",
+                "the service is at capacity, retry later",
+            )
+            .as_response();
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(1));
+            return Ok(response);
+        }
+    };
+
+    next.call(req).await.map(IntoResponse::into_response)
+}
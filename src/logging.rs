@@ -0,0 +1,42 @@
+use std::time::Instant;
+
+use poem::{Endpoint, IntoResponse, Request, Response, Result};
+use tracing::{Instrument, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Install the global subscriber, filtered by the configured log level
+pub fn init(log_level: &str) {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(log_level))
+        .init();
+}
+
+/// Log the method, path, status and duration of every request. Events
+/// emitted while handling it are recorded inside the request's span.
+pub async fn log_request<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let span = info_span!("request", %method, %path);
+
+    async move {
+        let started = Instant::now();
+        let result = next.call(req).await.map(IntoResponse::into_response);
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+
+        match &result {
+            Ok(response) => {
+                info!(status = response.status().as_u16(), elapsed_ms, "request finished")
+            }
+            Err(why) => warn!(
+                status = why.status().as_u16(),
+                elapsed_ms,
+                error = %why,
+                "request failed"
+            ),
+        }
+
+        result
+    }
+    .instrument(span)
+    .await
+}
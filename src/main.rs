@@ -1,40 +1,111 @@
-use poem::{EndpointExt, Route, listener::TcpListener};
+use poem::{Endpoint, EndpointExt, Route, listener::TcpListener};
 use poem_openapi::OpenApiService;
 use routes::api;
-use tracing::info;
+use std::time::Duration;
+use tokio::signal;
+use tracing::{error, info};
 
+use crate::config::AppConfig;
+use crate::connections::SharedStorage;
 use crate::setup::SetupResult;
 
 mod auth;
+mod compression;
 mod config;
 mod connections;
+mod error;
+mod limits;
+mod logging;
+mod metrics;
+mod rate_limit;
 mod routes;
 mod setup;
+#[cfg(test)]
+mod tests;
+mod webhooks;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    tracing_subscriber::fmt().init();
-    let SetupResult {  object_storage } = setup::setup_all().await.expect("setup failed");
+    let config = config::load().expect("invalid configuration");
+    logging::init(&config.log_level);
 
-    let api_service =
-        OpenApiService::new(api(), "Story Time", "1.0").server("http://localhost:5000/");
+    let SetupResult { config, storage } = setup::setup_all().await.expect("setup failed");
+
+    let app = build_app(config, storage);
+
+    info!("listening at: http://0.0.0.0:5000");
+    poem::Server::new(TcpListener::bind("0.0.0.0:5000"))
+        .run_with_graceful_shutdown(
+            app,
+            shutdown_signal(),
+            Some(Duration::from_secs(config.shutdown_timeout_secs)),
+        )
+        .await
+}
+
+/// The API with its docs and middleware, independent of how it is served so
+/// it can also be driven in-process, e.g. with poem's `TestClient`
+fn build_app(config: &AppConfig, storage: SharedStorage) -> impl Endpoint + use<> {
+    // "Try it" requests in the docs go wherever clients reach the service
+    let server = config.public_base_url.as_deref().unwrap_or("http://localhost:5000");
+    let api_service = OpenApiService::new(api(), "Story Time", "1.0").server(server);
 
     let spec_endpoint = api_service.spec_endpoint();
+    let root_spec_endpoint = api_service.spec_endpoint();
     let spec_yaml_endpoint = api_service.spec_endpoint_yaml();
 
     let swagger = api_service.swagger_ui();
     let scalar = api_service.scalar();
 
-    let app = Route::new()
+    Route::new()
         .nest("/", api_service)
         .nest("/docs/swagger", swagger)
         .nest("/docs/", scalar)
         .nest("/docs/api.json", spec_endpoint)
         .nest("/docs/api.yaml", spec_yaml_endpoint)
-        .data(object_storage);
+        .nest("/openapi.json", root_spec_endpoint)
+        .around(error::render_errors)
+        .around(compression::compress_responses)
+        .around(limits::limit_body_size)
+        .around(rate_limit::limit_requests)
+        .around(limits::limit_concurrency)
+        .with_if(!config.cors_allowed_origins.is_empty(), setup::get_cors(config))
+        .around(metrics::record_request)
+        .data(storage)
+        .data(limits::UploadSlots::new(config))
+        .data(config.clone())
+        .around(logging::log_request)
+}
 
-    info!("listening at: http://0.0.0.0:5000");
-    poem::Server::new(TcpListener::bind("0.0.0.0:5000"))
-        .run(app)
-        .await
+/// Resolves on Ctrl-C or SIGTERM, after which the server stops accepting
+/// connections and lets in-flight requests finish
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(why) = signal::ctrl_c().await {
+            error!("Error listening for Ctrl-C: {}", why);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(why) => {
+                error!("Error listening for SIGTERM: {}", why);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+
+    info!("shutting down, draining in-flight requests");
 }
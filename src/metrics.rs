@@ -0,0 +1,120 @@
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use poem::{Endpoint, IntoResponse, PathPattern, Request, Response, Result};
+use prometheus::{
+    Encoder, HistogramVec, IntCounter, IntCounterVec, TextEncoder, register_histogram_vec,
+    register_int_counter, register_int_counter_vec,
+};
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "http_requests_total",
+        "Requests handled, by route, method and status",
+        &["route", "method", "status"]
+    )
+    .expect("metric can be registered")
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time spent handling a request, by route and method",
+        &["route", "method"]
+    )
+    .expect("metric can be registered")
+});
+
+static UPLOADED_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("asset_uploaded_bytes_total", "Bytes stored through uploads")
+        .expect("metric can be registered")
+});
+
+static DOWNLOADED_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("asset_downloaded_bytes_total", "Bytes sent to clients in downloads")
+        .expect("metric can be registered")
+});
+
+static ASSET_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asset_cache_lookups_total",
+        "Downloads looked up in the in-memory asset cache, by result",
+        &["result"]
+    )
+    .expect("metric can be registered")
+});
+
+static STORAGE_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "storage_errors_total",
+        "Failed object storage operations, by operation",
+        &["operation"]
+    )
+    .expect("metric can be registered")
+});
+
+static STORAGE_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "storage_retries_total",
+        "Object storage operations retried after a transient error, by operation",
+        &["operation"]
+    )
+    .expect("metric can be registered")
+});
+
+pub fn record_upload(bytes: usize) {
+    UPLOADED_BYTES.inc_by(bytes as u64);
+}
+
+pub fn record_download(bytes: usize) {
+    DOWNLOADED_BYTES.inc_by(bytes as u64);
+}
+
+/// Count a download served from the asset cache, or one that missed it
+pub fn record_asset_cache_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    ASSET_CACHE_LOOKUPS.with_label_values(&[result]).inc();
+}
+
+/// Count a failed MinIO call, `operation` names the S3 call that failed
+pub fn record_storage_error(operation: &str) {
+    STORAGE_ERRORS.with_label_values(&[operation]).inc();
+}
+
+/// Count a MinIO call that is tried again after a transient failure
+pub fn record_storage_retry(operation: &str) {
+    STORAGE_RETRIES.with_label_values(&[operation]).inc();
+}
+
+/// Count and time every request. Routes are labelled by their pattern,
+/// e.g. `/assets/:asset`, so asset names don't blow up the label set.
+pub async fn record_request<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let result = next.call(req).await.map(IntoResponse::into_response);
+
+    let (route, status) = match &result {
+        Ok(response) => (response.data::<PathPattern>(), response.status()),
+        Err(why) => (why.data::<PathPattern>(), why.status()),
+    };
+    let route = route.map_or("unmatched", |pattern| &pattern.0);
+
+    REQUESTS
+        .with_label_values(&[route, &method, status.as_str()])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[route, &method])
+        .observe(started.elapsed().as_secs_f64());
+
+    result
+}
+
+/// Everything registered so far in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(why) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("Error encoding metrics: {}", why);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use poem::error::ResponseError;
+use poem::http::header::{AUTHORIZATION, RETRY_AFTER};
+use poem::http::{HeaderValue, StatusCode};
+use poem::{Endpoint, IntoResponse, Request, Response, Result};
+use tracing::debug;
+
+use crate::auth;
+use crate::config::AppConfig;
+use crate::error::ApiError;
+
+/// Probes and scrapes, which have to keep working while clients are throttled
+const EXEMPT_PATHS: &[&str] = &["/healthcheck", "/readyz", "/metrics"];
+
+/// Buckets kept before refilled ones are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// A client's allowance, refilled continuously up to a minute's worth
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, capacity: f64) {
+        let per_second = capacity / 60.0;
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+    }
+}
+
+static BUCKETS: Lazy<Mutex<HashMap<String, Bucket>>> = Lazy::new(Default::default);
+
+/// Throttle each client to `RATE_LIMIT_PER_MINUTE` requests, answering with
+/// 429 and `Retry-After` once its allowance is used up.
+///
+/// Clients are told apart by the subject of a valid bearer token, or by
+/// their IP address otherwise.
+pub async fn limit_requests<E: Endpoint>(next: E, req: Request) -> Result<Response> {
+    let Some(config) = req.data::<AppConfig>() else {
+        return next.call(req).await.map(IntoResponse::into_response);
+    };
+    let Some(limit) = config.rate_limit_per_minute else {
+        return next.call(req).await.map(IntoResponse::into_response);
+    };
+    if EXEMPT_PATHS.contains(&req.uri().path()) {
+        return next.call(req).await.map(IntoResponse::into_response);
+    }
+
+    let client = client_key(config, &req);
+    if let Err(retry_after) = take_token(&client, limit) {
+        debug!(%client, retry_after, "rate limited request");
+        let mut response = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_requests",
+            "rate limit exceeded, retry later",
+        )
+        .as_response();
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        return Ok(response);
+    }
+
+    next.call(req).await.map(IntoResponse::into_response)
+}
+
+fn client_key(config: &AppConfig, req: &Request) -> String {
+    let subject = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| auth::verified_subject(config, token));
+    if let Some(subject) = subject {
+        return format!("sub:{subject}");
+    }
+
+    match req.remote_addr().as_socket_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => format!("addr:{}", req.remote_addr()),
+    }
+}
+
+/// Spend one of the client's tokens, or the seconds until one is available
+fn take_token(client: &str, limit: u32) -> std::result::Result<(), u64> {
+    let capacity = f64::from(limit);
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    if buckets.len() >= MAX_TRACKED_CLIENTS {
+        buckets.retain(|_, bucket| {
+            bucket.refill(now, capacity);
+            bucket.tokens < capacity
+        });
+    }
+
+    let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+        tokens: capacity,
+        updated: now,
+    });
+    bucket.refill(now, capacity);
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        let per_second = capacity / 60.0;
+        Err(((1.0 - bucket.tokens) / per_second).ceil() as u64)
+    }
+}
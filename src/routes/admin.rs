@@ -0,0 +1,415 @@
+use poem::web::Data;
+use poem::Result;
+use poem_openapi::payload::Json;
+use poem_openapi::{ApiResponse, Object, OpenApi};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use tracing::{error, info, warn};
+
+use crate::auth::BearerAuthorization;
+use crate::config::AppConfig;
+use crate::connections::{SharedStorage, Storage};
+use crate::connections::storage::{ListQuery, StorageError};
+use crate::error::ApiError;
+use crate::routes::ApiTags;
+use crate::routes::assets::{
+    MAX_SINGLE_COPY_BYTES, SHA256_METADATA_KEY, delete_keys, is_trashed, sanitize_asset_key,
+};
+
+/// Objects copied between progress log lines
+const PROGRESS_INTERVAL: usize = 1000;
+
+pub struct AdminApi;
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct MigrateRequest {
+    /// Bucket to copy from, the assets bucket when omitted
+    pub source_bucket: Option<String>,
+    pub destination_bucket: String,
+    /// Only objects whose key starts with this are copied
+    pub source_prefix: Option<String>,
+    /// Replaces `source_prefix` at the start of destination keys, keys are
+    /// kept as they are when omitted
+    pub destination_prefix: Option<String>,
+    /// Replace destination objects whose content differs instead of
+    /// reporting them
+    #[oai(default)]
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct MigrateFailure {
+    pub source_key: String,
+    pub destination_key: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct MigrateResponse {
+    /// Objects found under the source prefix
+    pub scanned: usize,
+    pub copied: usize,
+    /// Objects already present in the destination with the same content
+    pub skipped: usize,
+    pub failures: Vec<MigrateFailure>,
+}
+
+#[derive(ApiResponse)]
+enum MigrateApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<MigrateResponse>),
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct GcRequest {
+    /// Asset keys still in use, with their folders, every other asset is deleted
+    pub referenced: Vec<String>,
+    /// Only report what would be deleted, on unless turned off explicitly
+    #[oai(default = "default_dry_run")]
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+    /// `confirmation` from the dry run, required when `dry_run` is false
+    pub confirmation: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct GcFailure {
+    pub name: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Object)]
+pub struct GcResponse {
+    pub dry_run: bool,
+    /// Assets found in `referenced`
+    pub kept: usize,
+    /// Assets deleted, or that would be on a dry run
+    pub deleted: usize,
+    /// Names of the unreferenced assets
+    pub unreferenced: Vec<String>,
+    /// Pass this back to delete exactly the assets this run reported
+    pub confirmation: String,
+    pub failures: Vec<GcFailure>,
+}
+
+#[derive(ApiResponse)]
+enum GcApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<GcResponse>),
+}
+
+/// Identifies a set of unreferenced assets, so the deleting run can tell
+/// whether it still sees what the dry run reported
+fn gc_confirmation(unreferenced: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for name in unreferenced {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// What to do with one object of a migration
+enum MigrateStep {
+    Copy,
+    Skip,
+    Conflict,
+}
+
+async fn bucket_must_exist(storage: &dyn Storage, bucket: &str) -> Result<()> {
+    match storage.bucket_exists(bucket).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::bad_request(format!("bucket {bucket:?} does not exist")).into()),
+        Err(why) => {
+            error!(bucket, "Error checking bucket: {}", why);
+            Err(why.into())
+        }
+    }
+}
+
+/// Compare a source object against what is already at its destination key.
+/// Copies get a new etag when the source was a multipart upload, so the
+/// stored content hash is compared when there is one.
+async fn plan_copy(
+    storage: &dyn Storage,
+    source_bucket: &str,
+    source_key: &str,
+    source_etag: &str,
+    destination_bucket: &str,
+    destination_key: &str,
+) -> std::result::Result<MigrateStep, StorageError> {
+    let destination = match storage.stat(destination_bucket, destination_key).await {
+        Ok(stat) => stat,
+        Err(StorageError::NotFound) => return Ok(MigrateStep::Copy),
+        Err(why) => return Err(why),
+    };
+    if source_etag == destination.etag {
+        return Ok(MigrateStep::Skip);
+    }
+
+    let source = storage.stat(source_bucket, source_key).await?;
+    let same_hash = match (
+        source.metadata.get(SHA256_METADATA_KEY),
+        destination.metadata.get(SHA256_METADATA_KEY),
+    ) {
+        (Some(source), Some(destination)) => source == destination,
+        _ => false,
+    };
+    if same_hash && source.size == destination.size {
+        Ok(MigrateStep::Skip)
+    } else {
+        Ok(MigrateStep::Conflict)
+    }
+}
+
+#[OpenApi(prefix_path = "/admin", tag = "ApiTags::Admin")]
+impl AdminApi {
+    /// Server-side copy of every object under `source_prefix` from one bucket
+    /// to another, for consolidating buckets. No bytes pass through this
+    /// service and the source is left as it is.
+    ///
+    /// Safe to re-run: objects already copied are skipped, and destination
+    /// objects with different content are reported rather than replaced
+    /// unless `overwrite` is set. Requires the `admin migrate` permission
+    /// with `any` scope.
+    #[oai(method = "post", path = "/migrate")]
+    async fn migrate(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<MigrateRequest>,
+    ) -> Result<MigrateApiResponse> {
+        if !claims.has_permission_with_scope("admin", "migrate", "any") {
+            return Err(ApiError::missing_permission("admin", "migrate").into());
+        }
+
+        let source_bucket = request
+            .source_bucket
+            .clone()
+            .unwrap_or_else(|| config.assets_bucket.clone());
+        let destination_bucket = request.destination_bucket.as_str();
+        let source_prefix = request.source_prefix.clone().unwrap_or_default();
+        if source_bucket == destination_bucket
+            && request
+                .destination_prefix
+                .as_ref()
+                .is_none_or(|prefix| *prefix == source_prefix)
+        {
+            return Err(ApiError::bad_request("source and destination are the same").into());
+        }
+        bucket_must_exist(storage.as_ref(), &source_bucket).await?;
+        bucket_must_exist(storage.as_ref(), destination_bucket).await?;
+
+        info!(
+            %source_bucket,
+            destination_bucket,
+            %source_prefix,
+            destination_prefix = ?request.destination_prefix,
+            "starting bucket migration"
+        );
+
+        let mut response = MigrateResponse {
+            scanned: 0,
+            copied: 0,
+            skipped: 0,
+            failures: Vec::new(),
+        };
+
+        let mut query = ListQuery::prefix(source_prefix.clone());
+        loop {
+            let page = storage.list(&source_bucket, &query).await.inspect_err(|why| {
+                error!(%source_bucket, "Error listing objects to migrate: {}", why);
+            })?;
+
+            for object in page.objects {
+                response.scanned += 1;
+                let source_key = object.key;
+                let destination_key = match &request.destination_prefix {
+                    Some(prefix) => format!(
+                        "{prefix}{}",
+                        source_key.strip_prefix(&source_prefix).unwrap_or(&source_key)
+                    ),
+                    None => source_key.clone(),
+                };
+
+                let step = plan_copy(
+                    storage.as_ref(),
+                    &source_bucket,
+                    &source_key,
+                    &object.etag,
+                    destination_bucket,
+                    &destination_key,
+                )
+                .await;
+                let failure = match step {
+                    Ok(MigrateStep::Skip) => {
+                        response.skipped += 1;
+                        continue;
+                    }
+                    Ok(MigrateStep::Conflict) if !request.overwrite => {
+                        "destination exists with different content".to_string()
+                    }
+                    Ok(_) => match storage
+                        .copy(&source_bucket, &source_key, destination_bucket, &destination_key)
+                        .await
+                    {
+                        Ok(()) => {
+                            response.copied += 1;
+                            if response.copied.is_multiple_of(PROGRESS_INTERVAL) {
+                                info!(
+                                    copied = response.copied,
+                                    skipped = response.skipped,
+                                    failed = response.failures.len(),
+                                    "bucket migration in progress"
+                                );
+                            }
+                            continue;
+                        }
+                        Err(why) => why.to_string(),
+                    },
+                    Err(why) => why.to_string(),
+                };
+
+                warn!(%source_key, %destination_key, "Error migrating object: {}", failure);
+                response.failures.push(MigrateFailure {
+                    source_key,
+                    destination_key,
+                    message: failure,
+                });
+            }
+
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
+            }
+        }
+
+        info!(
+            scanned = response.scanned,
+            copied = response.copied,
+            skipped = response.skipped,
+            failed = response.failures.len(),
+            "finished bucket migration"
+        );
+        Ok(MigrateApiResponse::Ok(Json(response)))
+    }
+
+    /// Delete every asset not named in `referenced`, for clearing out files
+    /// no post uses anymore. Trashed assets are left alone.
+    ///
+    /// Runs dry by default and only reports what it would delete. Deleting
+    /// needs `dry_run=false` together with the `confirmation` of a dry run,
+    /// and is refused with `409` if the unreferenced assets changed since,
+    /// for example because something was uploaded in between. Requires the
+    /// `admin gc` permission with `any` scope.
+    #[oai(method = "post", path = "/gc")]
+    async fn gc(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<GcRequest>,
+    ) -> Result<GcApiResponse> {
+        if !claims.has_permission_with_scope("admin", "gc", "any") {
+            return Err(ApiError::missing_permission("admin", "gc").into());
+        }
+        if !request.dry_run && request.confirmation.is_none() {
+            return Err(ApiError::bad_request(
+                "deleting requires the confirmation of a dry run",
+            )
+            .into());
+        }
+
+        let referenced: HashSet<String> = request
+            .referenced
+            .iter()
+            .filter_map(|name| sanitize_asset_key(name))
+            .collect();
+
+        let mut kept = 0;
+        let mut unreferenced = Vec::new();
+        let mut oversized = HashSet::new();
+        let mut query = ListQuery::prefix(config.object_key(""));
+        loop {
+            let page = storage.list(&config.assets_bucket, &query).await.inspect_err(|why| {
+                error!("Error listing assets to collect: {}", why);
+            })?;
+            for object in page.objects {
+                let name = config.asset_name(&object.key);
+                if is_trashed(name) {
+                    continue;
+                }
+                if referenced.contains(name) {
+                    kept += 1;
+                    continue;
+                }
+                if object.size > MAX_SINGLE_COPY_BYTES {
+                    oversized.insert(name.to_string());
+                }
+                unreferenced.push(name.to_string());
+            }
+
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
+            }
+        }
+        // The confirmation must not depend on listing order
+        unreferenced.sort();
+        let confirmation = gc_confirmation(&unreferenced);
+
+        if request.dry_run {
+            info!(kept, unreferenced = unreferenced.len(), "garbage collection dry run");
+            return Ok(GcApiResponse::Ok(Json(GcResponse {
+                dry_run: true,
+                kept,
+                deleted: unreferenced.len(),
+                unreferenced,
+                confirmation,
+                failures: Vec::new(),
+            })));
+        }
+        if request.confirmation.as_deref() != Some(confirmation.as_str()) {
+            return Err(ApiError::gc_confirmation_mismatch().into());
+        }
+
+        let mut failures = Vec::new();
+        let mut keys = Vec::with_capacity(unreferenced.len());
+        for name in &unreferenced {
+            if config.soft_delete && oversized.contains(name) {
+                failures.push(GcFailure {
+                    name: name.clone(),
+                    message: "too large for the trash".to_string(),
+                });
+            } else {
+                keys.push(name.clone());
+            }
+        }
+        let found = keys.len();
+        let mut errors: Vec<_> = delete_keys(&config, storage.as_ref(), keys, config.soft_delete)
+            .await
+            .into_iter()
+            .map(|(name, message)| GcFailure { name, message })
+            .collect();
+        let deleted = found.saturating_sub(errors.len());
+        errors.sort_by(|a, b| a.name.cmp(&b.name));
+        failures.append(&mut errors);
+
+        info!(kept, deleted, failed = failures.len(), "collected unreferenced assets");
+        Ok(GcApiResponse::Ok(Json(GcResponse {
+            dry_run: false,
+            kept,
+            deleted,
+            unreferenced,
+            confirmation,
+            failures,
+        })))
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+
+use crate::config::AppConfig;
+
+/// How long an entry is served before it is read from storage again. This
+/// bounds staleness when another replica or a presigned upload replaces the
+/// object, which this process can't invalidate.
+const ENTRY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// A downloaded asset kept in memory
+#[derive(Clone)]
+pub(crate) struct CachedAsset {
+    pub contents: Bytes,
+    pub content_type: String,
+    /// Hex SHA-256 of `contents`, for `Digest` headers
+    pub sha256: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// `Cache-Control` to send with it
+    pub cache_control: Option<String>,
+}
+
+struct Entry {
+    asset: CachedAsset,
+    stored: Instant,
+    /// Position in `Lru::order`, larger is more recently used
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, the first one is evicted next
+    order: BTreeMap<u64, String>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.used);
+        self.total_bytes -= entry.asset.contents.len() as u64;
+        Some(entry)
+    }
+}
+
+static CACHE: Lazy<Mutex<Lru>> = Lazy::new(Default::default);
+
+fn cache() -> std::sync::MutexGuard<'static, Lru> {
+    CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Whether an object of `size` bytes may be kept, going by
+/// `ASSET_CACHE_MAX_BYTES` and `ASSET_CACHE_MAX_OBJECT_BYTES`
+pub(crate) fn accepts(config: &AppConfig, size: u64) -> bool {
+    config.asset_cache_max_bytes > 0
+        && size <= config.asset_cache_max_object_bytes
+        && size <= config.asset_cache_max_bytes
+}
+
+/// The cached copy of `asset`, marking it as recently used
+pub(crate) fn get(asset: &str) -> Option<CachedAsset> {
+    let mut cache = cache();
+    let expired = cache
+        .entries
+        .get(asset)
+        .is_some_and(|entry| entry.stored.elapsed() > ENTRY_TTL);
+    if expired {
+        cache.remove(asset);
+        return None;
+    }
+
+    cache.clock += 1;
+    let used = cache.clock;
+    let entry = cache.entries.get_mut(asset)?;
+    let previous = std::mem::replace(&mut entry.used, used);
+    let cached = entry.asset.clone();
+    cache.order.remove(&previous);
+    cache.order.insert(used, asset.to_string());
+    Some(cached)
+}
+
+/// Keep a copy of `asset`, evicting the least recently used entries to stay
+/// within `ASSET_CACHE_MAX_BYTES`
+pub(crate) fn insert(config: &AppConfig, asset: &str, cached: CachedAsset) {
+    let size = cached.contents.len() as u64;
+    if !accepts(config, size) {
+        return;
+    }
+
+    let mut cache = cache();
+    cache.remove(asset);
+    while cache.total_bytes + size > config.asset_cache_max_bytes {
+        let Some((_, oldest)) = cache.order.pop_first() else {
+            break;
+        };
+        cache.remove(&oldest);
+    }
+
+    cache.clock += 1;
+    let used = cache.clock;
+    cache.order.insert(used, asset.to_string());
+    cache.total_bytes += size;
+    cache.entries.insert(
+        asset.to_string(),
+        Entry {
+            asset: cached,
+            stored: Instant::now(),
+            used,
+        },
+    );
+}
+
+/// Drop the copy of an asset that was replaced or deleted
+pub(crate) fn invalidate(asset: &str) {
+    cache().remove(asset);
+}
@@ -1,345 +1,4481 @@
-use crate::auth::BearerAuthorization;
-use crate::connections::ObjectStorage;
-use crate::connections::object_storage::ASSETS_FILE_BUCKET;
+use crate::auth::{BearerAuthorization, ReadAuthorization, sign_download_link};
+use crate::config::{AppConfig, NameCharacters};
+use crate::error::ApiError;
+use crate::limits::UploadSlots;
+use crate::connections::{SharedStorage, Storage};
+use crate::connections::storage::{ListQuery, ObjectInfo, StorageError, collect_bytes};
+use crate::connections::object_storage::STREAM_PART_BYTES;
+use crate::metrics;
 use crate::routes::ApiTags;
+use crate::routes::asset_cache::{self, CachedAsset};
+use crate::routes::checksum::UploadChecksum;
+use crate::routes::remote::{self, RemoteFetchError};
+use crate::routes::idempotency::{self, CachedUpload, Claim, Pending};
+use crate::routes::svg::sanitize_svg;
+use crate::webhooks;
 use bytes::Bytes;
-use futures_util::StreamExt;
-use minio::s3::segmented_bytes::SegmentedBytes;
-use minio::s3::types::{S3Api, ToStream};
-use poem::Error;
-use poem::http::StatusCode;
-use poem::{Result, error::InternalServerError, web::Data};
+use chrono::{DateTime, Utc};
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use futures_util::{AsyncWriteExt, StreamExt};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use img_parts::jpeg::{Jpeg, markers as jpeg_markers};
+use img_parts::png::Png;
+use img_parts::webp::WebP;
+use img_parts::ImageEXIF;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use poem::{Body, Request};
+use poem::http::{Method, StatusCode};
+use poem::{Result, error::BadRequest, error::InternalServerError, web::Data};
 use poem_openapi::Multipart;
-use poem_openapi::payload::{Attachment, Json, PlainText};
-use poem_openapi::types::multipart::Upload;
+use poem_openapi::payload::{Attachment, AttachmentType, Binary, Json, PlainText};
+use poem_openapi::types::multipart::{JsonField, Upload};
+use poem_openapi::param::{Header, Query};
 use poem_openapi::{ApiResponse, OpenApi, param::Path};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tracing::{debug, error, info, warn};
+use unicode_normalization::UnicodeNormalization;
 
 pub struct AssetsApi;
 
-fn is_valid_asset_type(filename: &str) -> bool {
+/// Largest page `list_assets` returns, also the S3 per-request maximum
+const MAX_LIST_PAGE_SIZE: u16 = 1000;
+
+/// Assets `recent_assets` returns when no `limit` is given
+const DEFAULT_RECENT_LIMIT: usize = 20;
+
+/// Most assets `recent_assets` returns
+const MAX_RECENT_LIMIT: usize = 100;
+
+/// Most tags S3 allows on one object
+const MAX_TAGS: usize = 10;
+
+/// Longest tag key and value S3 accepts
+const MAX_TAG_KEY_LENGTH: usize = 128;
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// User metadata key the content hash is stored under, sent as
+/// `x-amz-meta-sha256`
+pub(crate) const SHA256_METADATA_KEY: &str = "sha256";
+
+/// User metadata keys image dimensions are recorded under at upload
+const WIDTH_METADATA_KEY: &str = "width";
+const HEIGHT_METADATA_KEY: &str = "height";
+
+/// User metadata key the play length of audio and video is recorded under
+const DURATION_METADATA_KEY: &str = "duration";
+
+/// User metadata key a `Cache-Control` sent with an upload is stored under
+const CACHE_CONTROL_METADATA_KEY: &str = "cache-control";
+
+/// User metadata keys the service sets itself, uploads can't set them
+const RESERVED_METADATA_KEYS: &[&str] = &[
+    SHA256_METADATA_KEY,
+    WIDTH_METADATA_KEY,
+    HEIGHT_METADATA_KEY,
+    DURATION_METADATA_KEY,
+    CACHE_CONTROL_METADATA_KEY,
+];
+
+/// Most user metadata, keys and values together, uploads may set. S3 stores
+/// 2 KiB in total, the rest is left for the service's own keys.
+const MAX_UPLOAD_METADATA_BYTES: usize = 1024;
+
+/// `Retry-After` sent with uploads turned away at `MAX_CONCURRENT_UPLOADS`
+const UPLOAD_RETRY_AFTER_SECONDS: u64 = 1;
+
+/// Bytes read from the start of an image to find its dimensions. Generous
+/// enough to get past large EXIF blocks in front of a JPEG frame header.
+const DIMENSIONS_PROBE_BYTES: u64 = 256 * 1024;
+
+/// Bytes fetched at a time while looking for the play length of an asset
+const DURATION_PROBE_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// Most bytes read to find the play length of one asset. Containers record
+/// it in headers, a file that needs more isn't probed any further.
+const DURATION_PROBE_MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Objects larger than this can't be copied in one request
+pub(crate) const MAX_SINGLE_COPY_BYTES: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Most keys one search scans before handing back a `next_token`, so a
+/// rare match can't turn a request into a walk over the whole bucket
+const MAX_SEARCH_SCAN: usize = 10_000;
+
+/// Bytes of a streamed upload read up front to check its type
+const SNIFF_BYTES: usize = 8 * 1024;
+
+/// Bytes buffered between the zip writer and the response body
+const ZIP_PIPE_CAPACITY: usize = 64 * 1024;
+
+/// How long storage stats are served before the bucket is listed again
+const STATS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Largest width or height a thumbnail can be requested at
+const MAX_THUMBNAIL_DIMENSION: u32 = 4096;
+
+/// Prefix soft-deleted assets are kept under in the assets bucket.
+/// `sanitize_asset_key` refuses keys below it, so nothing in the trash can be
+/// requested directly.
+const TRASH_PREFIX: &str = ".trash/";
+
+/// Lifetime of presigned URLs when the client doesn't ask for one
+const DEFAULT_PRESIGN_EXPIRY_SECONDS: u32 = 900;
+
+/// Longest lifetime S3 accepts for a presigned URL (7 days)
+const MAX_PRESIGN_EXPIRY_SECONDS: u32 = 7 * 24 * 60 * 60;
+
+// Image file extensions and the MIME type each is served with
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    (".jpg", "image/jpeg"),
+    (".jpeg", "image/jpeg"),
+    (".png", "image/png"),
+    (".gif", "image/gif"),
+    (".bmp", "image/bmp"),
+    (".webp", "image/webp"),
+    (".svg", "image/svg+xml"),
+    (".tiff", "image/tiff"),
+    (".tif", "image/tiff"),
+    (".ico", "image/vnd.microsoft.icon"),
+];
+
+// Audio file extensions and the MIME type each is served with
+const AUDIO_TYPES: &[(&str, &str)] = &[
+    (".mp3", "audio/mpeg"),
+    (".wav", "audio/wav"),
+    (".flac", "audio/flac"),
+    (".aac", "audio/aac"),
+    (".ogg", "audio/ogg"),
+    (".m4a", "audio/mp4"),
+    (".wma", "audio/x-ms-wma"),
+    (".opus", "audio/opus"),
+];
+
+// Video file extensions and the MIME type each is served with
+const VIDEO_TYPES: &[(&str, &str)] = &[
+    (".mp4", "video/mp4"),
+    (".avi", "video/x-msvideo"),
+    (".mov", "video/quicktime"),
+    (".wmv", "video/x-ms-wmv"),
+    (".flv", "video/x-flv"),
+    (".webm", "video/webm"),
+    (".mkv", "video/x-matroska"),
+    (".m4v", "video/x-m4v"),
+    (".3gp", "video/3gpp"),
+    (".ogv", "video/ogg"),
+];
+
+/// MIME type of a supported asset, inferred from its file extension
+fn content_type_for(filename: &str) -> Option<&'static str> {
     let filename_lower = filename.to_lowercase();
 
-    // Image file extensions
-    let image_extensions = [
-        ".jpg", ".jpeg", ".png", ".gif", ".bmp", ".webp", ".svg", ".tiff", ".tif", ".ico",
-    ];
+    IMAGE_TYPES
+        .iter()
+        .chain(AUDIO_TYPES)
+        .chain(VIDEO_TYPES)
+        .find(|(ext, _)| filename_lower.ends_with(ext))
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Lowercase extension of a file name including the dot, e.g. `.png`
+fn extension_of(filename: &str) -> Option<String> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    Some(format!(".{}", extension.to_lowercase()))
+}
+
+/// Whether uploads with this file name are accepted, going by
+/// `ALLOWED_EXTENSIONS` and `BLOCKED_EXTENSIONS`
+fn is_valid_asset_type(config: &AppConfig, filename: &str) -> bool {
+    let Some(extension) = extension_of(filename) else {
+        return false;
+    };
+    if config.blocked_extensions.contains(&extension) {
+        return false;
+    }
+
+    match &config.allowed_extensions {
+        Some(allowed) => allowed.contains(&extension),
+        None => content_type_for(filename).is_some(),
+    }
+}
+
+/// Names `infer` gives types that are listed under another name above
+const SNIFFED_TYPE_ALIASES: &[(&str, &str)] = &[
+    ("audio/x-wav", "audio/wav"),
+    ("audio/x-flac", "audio/flac"),
+    ("audio/m4a", "audio/mp4"),
+];
+
+/// Extension for content whose name doesn't have a usable one, going by its
+/// leading bytes. The first extension listed for the detected type is used,
+/// e.g. `.jpg` for JPEG.
+fn sniffed_extension(contents: &[u8]) -> Option<&'static str> {
+    let detected = infer::get(contents)?.mime_type();
+    let detected = SNIFFED_TYPE_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == detected)
+        .map_or(detected, |(_, content_type)| *content_type);
+
+    IMAGE_TYPES
+        .iter()
+        .chain(AUDIO_TYPES)
+        .chain(VIDEO_TYPES)
+        .find(|(_, content_type)| *content_type == detected)
+        .map(|(ext, _)| *ext)
+}
+
+/// Whether an asset is audio or video
+fn is_media_asset(filename: &str) -> bool {
+    content_type_for(filename)
+        .is_some_and(|content_type| matches!(media_category(content_type), "audio" | "video"))
+}
+
+/// Whether an asset is a raster image whose dimensions can be decoded. SVG
+/// is left out, its size is whatever the page renders it at.
+fn is_image_asset(filename: &str) -> bool {
+    content_type_for(filename)
+        .is_some_and(|content_type| media_category(content_type) == "image" && content_type != "image/svg+xml")
+}
+
+/// Whether the leading bytes of an upload really are the media type its
+/// extension claims.
+///
+/// Image formats must match exactly. Ogg, MP4, ASF and Matroska containers can
+/// hold either audio or video, so those two categories are accepted for one
+/// another. Extensions allowed only through `ALLOWED_EXTENSIONS` have no
+/// known signature and are taken as they are.
+fn content_matches_type(config: &AppConfig, filename: &str, contents: &[u8]) -> bool {
+    let Some(declared) = content_type_for(filename) else {
+        return is_valid_asset_type(config, filename);
+    };
+
+    // SVG is plain XML without a magic number, so look for the root element
+    if declared == "image/svg+xml" {
+        return looks_like_svg(contents);
+    }
+
+    let Some(detected) = infer::get(contents) else {
+        return false;
+    };
+    let detected = detected.mime_type();
+
+    match (media_category(declared), media_category(detected)) {
+        ("image", "image") => declared == detected,
+        ("audio" | "video", "audio" | "video") => true,
+        _ => false,
+    }
+}
+
+fn media_category(content_type: &str) -> &str {
+    content_type.split('/').next().unwrap_or_default()
+}
+
+fn looks_like_svg(contents: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&contents[..contents.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    head.starts_with('<') && head.contains("<svg")
+}
+
+/// Turn a client supplied name into a safe object key.
+///
+/// Backslashes are treated as separators and only the final path component is
+/// kept, so `../../secret` and `C:\tmp\a.png` become `secret` and `a.png`.
+/// Names are normalized to Unicode NFC, so `café.png` typed on Linux finds
+/// the same key as the decomposed form macOS uploads. Returns `None` when
+/// nothing usable is left or the name contains characters that aren't
+/// allowed.
+pub(crate) fn sanitize_asset_name(name: &str) -> Option<String> {
+    let normalized = name.replace('\\', "/");
+    let name = normalized.rsplit('/').next().unwrap_or_default().trim();
+
+    if name.is_empty() || name == "." || name == ".." || name.chars().any(is_disallowed_char) {
+        return None;
+    }
+
+    Some(name.nfc().collect())
+}
+
+/// Turn a requested asset key into a safe object key. Unlike
+/// `sanitize_asset_name` folders are kept, so `posts/123/img.png` names
+/// that nested key rather than `img.png`. Routes match one path segment,
+/// so clients send the slashes of nested keys encoded as `%2F`. Empty and
+/// `.` segments are dropped, `None` if a segment climbs up with `..`, holds
+/// characters that aren't allowed or the key is in the trash.
+pub(crate) fn sanitize_asset_key(key: &str) -> Option<String> {
+    let folder = normalize_folder(key.trim())?;
+    if is_trashed(&folder) {
+        return None;
+    }
+    let key = folder.strip_suffix('/')?;
+    Some(key.trim().to_string()).filter(|key| !key.is_empty())
+}
+
+/// Name a new asset gets: sanitized, then held to `MAX_ASSET_NAME_LENGTH`
+/// and `ASSET_NAME_CHARACTERS`. `None` when it breaks the policy and can't
+/// be fixed. Existing assets are looked up with `sanitize_asset_key` alone,
+/// so those stored before the policy stay reachable.
+fn new_asset_name(config: &AppConfig, name: &str) -> Option<String> {
+    apply_name_policy(config, sanitize_asset_name(name)?)
+}
+
+/// Like `new_asset_name` for a key that may be nested in folders
+fn new_asset_key(config: &AppConfig, key: &str) -> Option<String> {
+    apply_name_policy(config, sanitize_asset_key(key)?)
+}
+
+fn apply_name_policy(config: &AppConfig, name: String) -> Option<String> {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/');
+    let name = match config.asset_name_characters {
+        NameCharacters::Any => name,
+        NameCharacters::Reject if !name.chars().all(is_safe) => return None,
+        NameCharacters::Reject => name,
+        NameCharacters::Replace => name
+            .chars()
+            .map(|c| if is_safe(c) { c } else { '_' })
+            .collect(),
+    };
+    (name.len() <= config.max_asset_name_length).then_some(name)
+}
+
+/// A folder path for browsing, without empty or `.` segments and ending in
+/// `/` unless it is the top level. `None` if it climbs up with `..` or holds
+/// characters names can't have.
+fn normalize_folder(path: &str) -> Option<String> {
+    let mut folder = String::new();
+    for segment in path.replace('\\', "/").split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment if segment.chars().any(is_disallowed_char) => return None,
+            segment => {
+                folder.extend(segment.nfc());
+                folder.push('/');
+            }
+        }
+    }
+    Some(folder)
+}
+
+/// Control characters, and invisible ones that make a name look like
+/// another: bidirectional overrides, zero-width characters and
+/// noncharacters
+fn is_disallowed_char(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{2069}'
+                | '\u{FEFF}'
+                | '\u{FDD0}'..='\u{FDEF}'
+        )
+        || (c as u32 & 0xFFFE) == 0xFFFE
+}
+
+/// File name downloads of an asset are saved under, without its folders
+fn download_filename(asset: &str) -> &str {
+    asset.rsplit('/').next().unwrap_or(asset)
+}
+
+/// Where an asset is served from, an absolute URL when `PUBLIC_BASE_URL` is
+/// set and a path otherwise
+fn asset_url(config: &AppConfig, name: &str) -> String {
+    let base = config.public_base_url.as_deref().unwrap_or_default();
+    // Nested keys have to stay one path segment to be routed, and spaces,
+    // `#` or `?` in names must not end the path
+    format!("{base}/assets/{}", utf8_percent_encode(name, PATH_SEGMENT))
+}
+
+/// Characters left as they are in asset paths, everything else is escaped
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Answer a download of a missing asset. With `CASE_INSENSITIVE_LOOKUP` a
+/// key differing only in case is looked for and redirected to, keeping
+/// the query. Otherwise `DEFAULT_ASSET_KEY` is redirected to when set.
+/// Specific versions are never redirected.
+async fn asset_not_found(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    asset: &str,
+    version_id: Option<&str>,
+    req: &Request,
+) -> Result<GetImageResponse> {
+    if version_id.is_some() {
+        return Ok(GetImageResponse::NotFound);
+    }
+
+    let found = if config.case_insensitive_lookup {
+        find_case_insensitive(config, storage, asset).await?
+    } else {
+        None
+    };
+    // The default itself missing must not redirect to itself
+    let fallback = config
+        .default_asset_key
+        .as_deref()
+        .filter(|default| *default != asset);
+    let Some(target) = found.as_deref().or(fallback) else {
+        return Ok(GetImageResponse::NotFound);
+    };
+    if found.is_none() {
+        debug!(asset, default = target, "redirected missing asset to the default");
+    }
+
+    let mut location = format!("/assets/{}", utf8_percent_encode(target, PATH_SEGMENT));
+    if let Some(query) = req.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Ok(GetImageResponse::Found(location))
+}
+
+/// A key equal to `asset` ignoring case.
+///
+/// Case can't be ignored in a listing prefix, so only the leading
+/// characters without case, such as digits, narrow it down. Every miss
+/// lists up to `MAX_SEARCH_SCAN` keys from there on, which is slow on large
+/// buckets.
+async fn find_case_insensitive(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    asset: &str,
+) -> Result<Option<String>> {
+    let prefix: String = asset
+        .chars()
+        .take_while(|c| c.to_lowercase().eq(c.to_uppercase()))
+        .collect();
+    let needle = asset.to_lowercase();
+
+    let mut query = ListQuery::prefix(config.object_key(&prefix));
+    let mut scanned = 0;
+    loop {
+        let page = storage.list(&config.assets_bucket, &query).await?;
+        for object in page.objects {
+            if scanned == MAX_SEARCH_SCAN {
+                return Ok(None);
+            }
+            scanned += 1;
+
+            let name = config.asset_name(&object.key);
+            if name.to_lowercase() == needle {
+                debug!(asset, found = name, "matched asset ignoring case");
+                return Ok(Some(name.to_string()));
+            }
+        }
+
+        match page.next_token {
+            Some(token) => query.continuation_token = Some(token),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Quote a bare MinIO etag for use in the `ETag` header
+fn format_etag(etag: &str) -> String {
+    format!("\"{}\"", etag)
+}
+
+/// Whether a `Want-Digest` header accepts `sha-256`, e.g.
+/// `sha-256;q=0.5, md5;q=0.1`
+fn wants_sha256(want_digest: &str) -> bool {
+    want_digest.split(',').any(|entry| {
+        let mut params = entry.split(';');
+        let algorithm = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        algorithm.eq_ignore_ascii_case("sha-256") && quality > 0.0
+    })
+}
+
+/// `Digest` header for a hex encoded SHA-256, which RFC 3230 wants in base64
+fn sha256_digest(sha256: &str) -> Option<String> {
+    let hash = hex::decode(sha256).ok()?;
+    Some(format!("sha-256={}", BASE64_STANDARD.encode(hash)))
+}
+
+/// `Cache-Control` for a download, the one stored with the asset if it was
+/// uploaded with one, `DOWNLOAD_CACHE_CONTROL` otherwise
+fn download_cache_control(config: &AppConfig, stored: Option<impl AsRef<str>>) -> Option<String> {
+    stored
+        .map(|value| value.as_ref().to_string())
+        .or_else(|| config.download_cache_control.clone())
+}
+
+/// Answer a download from the asset cache, including conditional requests
+fn cached_asset_response(
+    asset: &str,
+    cached: CachedAsset,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+    disposition: Option<Disposition>,
+    want_sha256: bool,
+) -> GetImageResponse {
+    let etag = cached.etag.as_deref().map(format_etag);
+    let last_modified = cached.last_modified.map(format_http_date);
+    if is_not_modified(
+        cached.etag.as_deref().unwrap_or_default(),
+        cached.last_modified,
+        if_none_match,
+        if_modified_since,
+    ) && let Some(etag) = etag
+    {
+        return GetImageResponse::NotModified(etag, last_modified, cached.cache_control);
+    }
+
+    debug!(asset, size = cached.contents.len(), "serving asset from the cache");
+    metrics::record_download(cached.contents.len());
+    let digest = if want_sha256 {
+        sha256_digest(&cached.sha256)
+    } else {
+        None
+    };
+    let disposition = disposition.unwrap_or_else(|| Disposition::default_for(&cached.content_type));
+    let content_length = cached.contents.len() as u64;
+    let attachment = Attachment::new(Body::from_bytes(cached.contents))
+        .attachment_type(disposition.into())
+        .filename(download_filename(asset));
+
+    GetImageResponse::Ok(
+        attachment,
+        content_length,
+        cached.content_type,
+        "bytes".to_string(),
+        etag,
+        last_modified,
+        digest,
+        cached.cache_control,
+    )
+}
+
+/// Prefer the type implied by the extension, then whatever the backend has
+/// stored for the object.
+fn resolve_content_type(asset: &str, stored: Option<&str>) -> String {
+    content_type_for(asset)
+        .or(stored)
+        .unwrap_or("application/octet-stream")
+        .to_string()
+}
+
+/// Format a timestamp as an HTTP date, e.g. `Wed, 21 Oct 2015 07:28:00 GMT`
+fn format_http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Evaluate `If-None-Match` / `If-Modified-Since` against the stored object.
+///
+/// As per RFC 9110, `If-Modified-Since` is ignored whenever `If-None-Match` is
+/// present.
+fn is_not_modified(
+    etag: &str,
+    last_modified: Option<DateTime<Utc>>,
+    if_none_match: Option<&str>,
+    if_modified_since: Option<&str>,
+) -> bool {
+    if let Some(if_none_match) = if_none_match {
+        return if_none_match.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*"
+                || candidate.trim_start_matches("W/").trim_matches('"') == etag
+        });
+    }
+
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(last_modified)) => DateTime::parse_from_rfc2822(since)
+            .map(|since| last_modified.timestamp() <= since.timestamp())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Evaluate `If-Match` against the stored object's etag, `None` when it
+/// doesn't exist. Weak tags never match, as RFC 9110 requires a strong
+/// comparison here.
+fn if_match_satisfied(if_match: &str, etag: Option<&str>) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    if_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || (!candidate.starts_with("W/") && candidate.trim_matches('"') == etag)
+    })
+}
+
+/// Whether `If-Match` holds for the current asset. There is no conditional
+/// put in S3, so another write can still land between this and the update.
+async fn check_if_match(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    asset: &str,
+    if_match: &str,
+) -> Result<bool> {
+    let etag = match storage.stat(&config.assets_bucket, &config.object_key(asset)).await {
+        Ok(stat) => Some(stat.etag),
+        Err(StorageError::NotFound) => None,
+        Err(why) => return Err(why.into()),
+    };
+    let satisfied = if_match_satisfied(if_match, etag.as_deref());
+    if !satisfied {
+        debug!(asset, if_match, current = ?etag, "If-Match precondition failed");
+    }
+    Ok(satisfied)
+}
+
+/// Inclusive byte range of an object, resolved against its size
+struct ByteRange {
+    start: u64,
+    end: u64,
+    size: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, self.size)
+    }
+}
+
+/// Parse a single-range `Range: bytes=...` header against the object size.
+///
+/// Returns `None` for malformed, multi-range or unsatisfiable ranges.
+fn parse_range(header: &str, size: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') || size == 0 {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // "bytes=-500": the last 500 bytes
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (size.saturating_sub(suffix), size - 1)
+        }
+        // "bytes=500-": everything from byte 500 on
+        (start, "") => (start.parse::<u64>().ok()?, size - 1),
+        (start, end) => {
+            let start = start.parse::<u64>().ok()?;
+            let end = end.parse::<u64>().ok()?;
+            (start, end.min(size - 1))
+        }
+    };
+
+    if start > end || start >= size {
+        return None;
+    }
+
+    Some(ByteRange { start, end, size })
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct AssetInfo {
+    pub name: String,
+    pub size: u64,
+    pub last_modified: String,
+    /// Hex SHA-256 of the stored bytes, absent for streamed uploads, uploads
+    /// that predate it and in listings
+    pub sha256: Option<String>,
+    /// Tags attached to the asset, only included when asked for
+    #[oai(skip_serializing_if_is_none)]
+    pub tags: Option<HashMap<String, String>>,
+    /// Pixel width of images, null for other assets, undecodable images and
+    /// in listings
+    pub width: Option<u32>,
+    /// Pixel height of images, see `width`
+    pub height: Option<u32>,
+    /// Play length of audio and video, null for other assets, containers
+    /// that don't record it and in listings
+    pub duration_seconds: Option<f64>,
+    /// `Cache-Control` set when the asset was uploaded
+    pub cache_control: Option<String>,
+    /// Custom metadata set when the asset was uploaded, null in listings
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+impl From<ObjectInfo> for AssetInfo {
+    fn from(object: ObjectInfo) -> Self {
+        Self {
+            name: object.key,
+            size: object.size,
+            last_modified: object
+                .last_modified
+                .map(|dt| dt.to_rfc3339())
+                .unwrap_or_default(),
+            sha256: None,
+            tags: None,
+            width: None,
+            height: None,
+            duration_seconds: None,
+            cache_control: None,
+            metadata: None,
+        }
+    }
+}
+
+impl AssetInfo {
+    /// Everything `stat` knows about an object, listings leave out the
+    /// metadata this reads
+    fn from_stat(mut object: ObjectInfo) -> Self {
+        let metadata = object
+            .metadata
+            .iter()
+            .filter(|(key, _)| !RESERVED_METADATA_KEYS.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        Self {
+            sha256: object.metadata.remove(SHA256_METADATA_KEY),
+            tags: None,
+            width: metadata_number(&object, WIDTH_METADATA_KEY),
+            height: metadata_number(&object, HEIGHT_METADATA_KEY),
+            duration_seconds: metadata_number(&object, DURATION_METADATA_KEY),
+            cache_control: object.metadata.remove(CACHE_CONTROL_METADATA_KEY),
+            metadata: Some(metadata),
+            name: object.key,
+            size: object.size,
+            last_modified: object
+                .last_modified
+                .map(|dt| dt.to_rfc3339())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct ListAssetsResponse {
+    pub assets: Vec<String>,
+    /// "Folders" directly below the prefix, only filled when a delimiter is given
+    pub common_prefixes: Vec<String>,
+    /// Size and modification time of each asset, only sent for `detailed=true`
+    #[oai(skip_serializing_if_is_none)]
+    pub details: Option<Vec<AssetInfo>>,
+    /// Number of assets in this page, `GET /assets/count` counts them all
+    pub count: usize,
+    /// Token for the next page, absent on the last one
+    pub next_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchAssetInfoRequest {
+    pub asset_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchAssetInfoResponse {
+    /// Assets that were found, in request order
+    pub assets: Vec<AssetInfo>,
+    /// Names that are invalid or couldn't be looked up. Missing assets are
+    /// left out of both lists.
+    pub failures: Vec<BatchInfoFailure>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchInfoFailure {
+    pub name: String,
+    /// HTTP status a single lookup would have answered with, `400` for an
+    /// invalid name, `504` when storage timed out
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchExistsResponse {
+    /// Whether each requested name exists, invalid names never do
+    pub results: HashMap<String, bool>,
+}
+
+#[derive(ApiResponse)]
+enum BatchExistsApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<BatchExistsResponse>),
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchDeleteRequest {
+    pub asset_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum Disposition {
+    /// Let the browser render the asset in place
+    Inline,
+    /// Make the browser download the asset
+    Attachment,
+}
+
+impl Disposition {
+    /// Images render inline, everything else downloads. SVG is the
+    /// exception, rendered in place it can run scripts on this origin.
+    fn default_for(content_type: &str) -> Self {
+        if media_category(content_type) == "image" && content_type != "image/svg+xml" {
+            Disposition::Inline
+        } else {
+            Disposition::Attachment
+        }
+    }
+}
+
+impl From<Disposition> for AttachmentType {
+    fn from(disposition: Disposition) -> Self {
+        match disposition {
+            Disposition::Inline => AttachmentType::Inline,
+            Disposition::Attachment => AttachmentType::Attachment,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum SortField {
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Order listing entries by `field`, ties keep the lexical key order
+fn sort_entries(entries: &mut [ObjectInfo], field: SortField, order: SortOrder) {
+    entries.sort_by(|a, b| {
+        let ordering = match field {
+            SortField::Name => a.key.cmp(&b.key),
+            SortField::Size => a.size.cmp(&b.size),
+            SortField::Modified => a.last_modified.cmp(&b.last_modified),
+        };
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum AssetKind {
+    Image,
+    Audio,
+    Video,
+}
+
+impl AssetKind {
+    /// Whether `name` has an extension of this kind
+    fn matches(&self, name: &str) -> bool {
+        let category = match self {
+            AssetKind::Image => "image",
+            AssetKind::Audio => "audio",
+            AssetKind::Video => "video",
+        };
+        content_type_for(name).map(media_category) == Some(category)
+    }
+}
+
+/// Number and total size of a group of assets
+#[derive(Serialize, Deserialize, poem_openapi::Object, Clone, Default)]
+pub struct UsageTotals {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.bytes += size;
+    }
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object, Clone, Default)]
+pub struct UsageByType {
+    pub image: UsageTotals,
+    pub audio: UsageTotals,
+    pub video: UsageTotals,
+    /// Extensions allowed through `ALLOWED_EXTENSIONS` outside the three
+    /// media kinds
+    pub other: UsageTotals,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object, Clone)]
+pub struct StorageStats {
+    pub total_count: u64,
+    pub total_bytes: u64,
+    pub by_type: UsageByType,
+    /// When the listing the numbers come from was taken
+    pub computed_at: String,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct AssetCountResponse {
+    pub count: u64,
+}
+
+#[derive(ApiResponse)]
+enum AssetCountApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<AssetCountResponse>),
+}
+
+#[derive(ApiResponse)]
+enum RecentAssetsApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<AssetInfo>>),
+}
+
+#[derive(ApiResponse)]
+enum StorageStatsResponse {
+    #[oai(status = 200)]
+    Ok(Json<StorageStats>),
+}
+
+/// Storage stats computed recently enough to be served again
+static STATS_CACHE: Lazy<tokio::sync::Mutex<Option<(Instant, StorageStats)>>> =
+    Lazy::new(Default::default);
+
+/// Sum the size of every asset by kind, walking the whole bucket
+async fn compute_storage_stats(config: &AppConfig, storage: &dyn Storage) -> Result<StorageStats> {
+    let mut query = ListQuery::prefix(config.object_key(""));
+    let mut totals = UsageTotals::default();
+    let mut by_type = UsageByType::default();
+    loop {
+        let page = storage.list(&config.assets_bucket, &query).await?;
+        for object in page.objects {
+            let name = config.asset_name(&object.key);
+            if is_trashed(name) {
+                continue;
+            }
+            let size = object.size;
+            totals.add(size);
+
+            let group = if AssetKind::Image.matches(name) {
+                &mut by_type.image
+            } else if AssetKind::Audio.matches(name) {
+                &mut by_type.audio
+            } else if AssetKind::Video.matches(name) {
+                &mut by_type.video
+            } else {
+                &mut by_type.other
+            };
+            group.add(size);
+        }
+
+        query.continuation_token = page.next_token;
+        if query.continuation_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(StorageStats {
+        total_count: totals.count,
+        total_bytes: totals.bytes,
+        by_type,
+        computed_at: Utc::now().to_rfc3339(),
+    })
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum BatchDeleteStatus {
+    Deleted,
+    NotFound,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchDeleteResult {
+    pub name: String,
+    pub status: BatchDeleteStatus,
+    /// What went wrong, only present for `error`
+    #[oai(skip_serializing_if_is_none)]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchDeleteResponse {
+    /// One entry per requested name, in request order
+    pub results: Vec<BatchDeleteResult>,
+}
+
+#[derive(ApiResponse)]
+enum BatchDeleteApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<BatchDeleteResponse>),
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct PrefixDeleteFailure {
+    pub name: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct PrefixDeleteResponse {
+    /// Assets removed under the prefix
+    pub deleted: usize,
+    /// Assets that are still there, with the reason
+    pub failures: Vec<PrefixDeleteFailure>,
+}
+
+#[derive(ApiResponse)]
+enum PrefixDeleteApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<PrefixDeleteResponse>),
+}
+
+#[derive(ApiResponse)]
+enum GetImageResponse {
+    #[oai(status = 200)]
+    Ok(
+        Attachment<Body>,
+        #[oai(header = "Content-Length")] u64,
+        #[oai(header = "Content-Type")] String,
+        #[oai(header = "Accept-Ranges")] String,
+        #[oai(header = "ETag")] Option<String>,
+        #[oai(header = "Last-Modified")] Option<String>,
+        /// `sha-256` of the whole object, when asked for with `Want-Digest`
+        #[oai(header = "Digest")] Option<String>,
+        /// `DOWNLOAD_CACHE_CONTROL`, when set
+        #[oai(header = "Cache-Control")] Option<String>,
+    ),
+    #[oai(status = 206)]
+    PartialContent(
+        Attachment<Body>,
+        /// Length of the range, not of the whole object
+        #[oai(header = "Content-Length")] u64,
+        #[oai(header = "Content-Type")] String,
+        #[oai(header = "Accept-Ranges")] String,
+        #[oai(header = "Content-Range")] String,
+        #[oai(header = "ETag")] Option<String>,
+        #[oai(header = "Last-Modified")] Option<String>,
+        #[oai(header = "Digest")] Option<String>,
+        #[oai(header = "Cache-Control")] Option<String>,
+    ),
+    #[oai(status = 304)]
+    NotModified(
+        #[oai(header = "ETag")] String,
+        #[oai(header = "Last-Modified")] Option<String>,
+        #[oai(header = "Cache-Control")] Option<String>,
+    ),
+    /// The asset exists under a name differing only in case, see
+    /// `CASE_INSENSITIVE_LOOKUP`, or it is missing and `DEFAULT_ASSET_KEY`
+    /// is served instead
+    #[oai(status = 302)]
+    Found(#[oai(header = "Location")] String),
+    #[oai(status = 404)]
+    NotFound,
+    #[oai(status = 416)]
+    RangeNotSatisfiable(#[oai(header = "Content-Range")] String),
+}
+
+#[derive(ApiResponse)]
+enum BatchDownloadResponse {
+    #[oai(status = 200)]
+    Ok(Attachment<Body>, #[oai(header = "Content-Type")] String),
+}
+
+#[derive(ApiResponse)]
+enum HeadAssetResponse {
+    #[oai(status = 200)]
+    Ok(
+        #[oai(header = "Content-Length")] u64,
+        #[oai(header = "Content-Type")] String,
+        #[oai(header = "ETag")] String,
+        #[oai(header = "Last-Modified")] Option<String>,
+        #[oai(header = "Cache-Control")] Option<String>,
+    ),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum ThumbnailResponse {
+    #[oai(status = 200)]
+    Ok(Binary<Vec<u8>>, #[oai(header = "Content-Type")] String),
+    #[oai(status = 404)]
+    NotFound,
+    #[oai(status = 415)]
+    UnsupportedMediaType,
+}
+
+#[derive(ApiResponse)]
+enum ListAssetsApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<ListAssetsResponse>),
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BrowseResponse {
+    /// The normalized folder listed, empty for the top level or ending in `/`
+    pub path: String,
+    /// Full paths of the folders directly inside, each ending in `/`
+    pub folders: Vec<String>,
+    /// Assets directly inside the folder
+    pub files: Vec<AssetInfo>,
+    /// Token for the next page, absent on the last one
+    pub next_token: Option<String>,
+}
+
+#[derive(ApiResponse)]
+enum BrowseApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<BrowseResponse>),
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct AssetVersion {
+    /// Pass as `version_id` to `get_asset` to download this version
+    pub version_id: String,
+    pub last_modified: String,
+    /// Absent for delete markers
+    #[oai(skip_serializing_if_is_none)]
+    pub size: Option<u64>,
+    pub is_latest: bool,
+    /// The asset was deleted at this point, there's no content to fetch
+    pub is_delete_marker: bool,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct AssetVersionsResponse {
+    /// Newest first, as MinIO lists them
+    pub versions: Vec<AssetVersion>,
+}
+
+#[derive(ApiResponse)]
+enum AssetVersionsApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<AssetVersionsResponse>),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum AssetTagsResponse {
+    #[oai(status = 200)]
+    Ok(Json<HashMap<String, String>>),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+#[allow(clippy::large_enum_variant)]
+enum AssetInfoResponse {
+    #[oai(status = 200)]
+    Ok(
+        Json<AssetInfo>,
+        /// `INFO_CACHE_CONTROL`, when set
+        #[oai(header = "Cache-Control")] Option<String>,
+    ),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(ApiResponse)]
+enum BatchAssetInfoApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<BatchAssetInfoResponse>),
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct PresignedUrlResponse {
+    pub url: String,
+    pub expires_at: String,
+}
+
+#[derive(ApiResponse)]
+enum PresignedUrlApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<PresignedUrlResponse>),
+    #[oai(status = 404)]
+    NotFound,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct UploadFromUrlRequest {
+    /// `http` or `https` URL of the file to import
+    pub url: String,
+    /// Name to store the asset under, the last segment of the URL when unset
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct PresignUploadRequest {
+    pub name: String,
+    pub expiry_seconds: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct PresignUploadResponse {
+    /// URL to `PUT` the file contents to
+    pub url: String,
+    pub expires_at: String,
+    /// Object key the upload will be stored under
+    pub key: String,
+    /// Where the asset is served from once uploaded, see `asset_url`
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct SignedLinkResponse {
+    /// Path or URL the asset can be downloaded from without a bearer token
+    pub url: String,
+    pub expires_at: String,
+}
+
+#[derive(ApiResponse)]
+enum PresignUploadApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<PresignUploadResponse>),
+    #[oai(status = 415)]
+    UnsupportedMediaType,
+}
+
+#[derive(ApiResponse)]
+enum PutAssetResponse {
+    /// Path of the asset, or its URL with `PUBLIC_BASE_URL` set.
+    /// `X-Upload-Outcome` tells whether it was created or an identical asset
+    /// was reused
+    #[oai(status = 200)]
+    Ok(PlainText<String>, #[oai(header = "X-Upload-Outcome")] String),
+    #[oai(status = 413)]
+    PayloadTooLarge,
+    #[oai(status = 415)]
+    UnsupportedMediaType,
+    /// An asset with the name exists and overwriting wasn't allowed
+    #[oai(status = 409)]
+    Conflict,
+    /// Object storage could not store the upload
+    #[oai(status = 502)]
+    StorageUnavailable,
+    /// `If-Match` didn't match the current asset
+    #[oai(status = 412)]
+    PreconditionFailed,
+    /// `MAX_CONCURRENT_UPLOADS` uploads are already in progress, retry
+    /// after the given seconds
+    #[oai(status = 503)]
+    Busy(#[oai(header = "Retry-After")] u64),
+}
+
+#[derive(ApiResponse)]
+enum PatchAssetResponse {
+    /// Path of the asset, or its URL with `PUBLIC_BASE_URL` set
+    #[oai(status = 200)]
+    Ok(PlainText<String>),
+    #[oai(status = 404)]
+    NotFound,
+    #[oai(status = 413)]
+    PayloadTooLarge,
+    /// The new content isn't of the asset's type
+    #[oai(status = 415)]
+    UnsupportedMediaType,
+    /// Object storage could not store the upload
+    #[oai(status = 502)]
+    StorageUnavailable,
+    /// `MAX_CONCURRENT_UPLOADS` uploads are already in progress, retry
+    /// after the given seconds
+    #[oai(status = 503)]
+    Busy(#[oai(header = "Retry-After")] u64),
+}
+
+#[derive(ApiResponse)]
+enum RestoreAssetResponse {
+    /// Path of the restored asset, or its URL with `PUBLIC_BASE_URL` set
+    #[oai(status = 200)]
+    Ok(PlainText<String>),
+    /// Nothing of that name is in the trash
+    #[oai(status = 404)]
+    NotFound,
+    /// An asset with the name was stored since it was deleted
+    #[oai(status = 409)]
+    Conflict,
+}
+
+#[derive(ApiResponse)]
+enum DeleteAssetResponse {
+    #[oai(status = 204)]
+    NoContent,
+    #[oai(status = 404)]
+    NotFound,
+    /// `If-Match` didn't match the current asset
+    #[oai(status = 412)]
+    PreconditionFailed,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct CopyAssetRequest {
+    pub destination: String,
+}
+
+#[derive(ApiResponse)]
+enum CopyAssetResponse {
+    #[oai(status = 200)]
+    Ok(PlainText<String>),
+    #[oai(status = 404)]
+    NotFound,
+    #[oai(status = 409)]
+    Conflict,
+    #[oai(status = 415)]
+    UnsupportedMediaType,
+}
+
+/// Result of copying one object key to another inside the assets bucket
+enum CopyOutcome {
+    Copied,
+    SourceMissing,
+    DestinationExists,
+}
+
+fn trash_key(asset: &str) -> String {
+    format!("{TRASH_PREFIX}{asset}")
+}
+
+pub(crate) fn is_trashed(key: &str) -> bool {
+    key.starts_with(TRASH_PREFIX)
+}
+
+/// Copy an asset into the trash ahead of deleting it. An earlier trashed
+/// asset of the same name is replaced.
+async fn copy_to_trash(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    asset: &str,
+) -> std::result::Result<(), StorageError> {
+    let bucket = &config.assets_bucket;
+    storage
+        .copy(bucket, &config.object_key(asset), bucket, &config.object_key(&trash_key(asset)))
+        .await
+}
+
+/// Delete existing `keys`, moving them to the trash first when
+/// `soft_delete`. Returns why each key that could not be removed failed,
+/// the others are reported as deleted.
+pub(crate) async fn delete_keys(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    mut keys: Vec<String>,
+    soft_delete: bool,
+) -> HashMap<String, String> {
+    let mut failures = HashMap::new();
+
+    if soft_delete {
+        let mut trashed = Vec::with_capacity(keys.len());
+        for key in keys {
+            match copy_to_trash(config, storage, &key).await {
+                Ok(()) => trashed.push(key),
+                Err(why) => {
+                    failures.insert(key, why.to_string());
+                }
+            }
+        }
+        keys = trashed;
+    }
+
+    let object_keys: Vec<_> = keys.iter().map(|key| config.object_key(key)).collect();
+    for (object_key, why) in storage.remove_many(&config.assets_bucket, &object_keys).await {
+        failures.insert(config.asset_name(&object_key).to_string(), why);
+    }
+
+    for key in &keys {
+        if !failures.contains_key(key) {
+            asset_cache::invalidate(key);
+            webhooks::asset_deleted(config, key);
+        }
+    }
+
+    failures
+}
+
+/// `asset_name` paired with whether it exists, invalid names never do
+async fn asset_exists(
+    config: &AppConfig,
+    storage: SharedStorage,
+    asset_name: String,
+) -> Result<(String, bool)> {
+    let Some(asset) = sanitize_asset_key(&asset_name) else {
+        return Ok((asset_name, false));
+    };
+    match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+        Ok(_) => Ok((asset_name, true)),
+        Err(StorageError::NotFound) => Ok((asset_name, false)),
+        Err(why) => Err(why.into()),
+    }
+}
+
+/// Server-side copy of `source` to `destination`, refusing to overwrite an
+/// existing destination. No bytes pass through this service.
+async fn copy_object_key(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    source: &str,
+    destination: &str,
+) -> Result<CopyOutcome> {
+    let bucket = &config.assets_bucket;
+    match storage.stat(bucket, &config.object_key(destination)).await {
+        Ok(_) => return Ok(CopyOutcome::DestinationExists),
+        Err(StorageError::NotFound) => {}
+        Err(why) => return Err(why.into()),
+    }
+
+    match storage
+        .copy(bucket, &config.object_key(source), bucket, &config.object_key(destination))
+        .await
+    {
+        Ok(()) => Ok(CopyOutcome::Copied),
+        Err(StorageError::NotFound) => Ok(CopyOutcome::SourceMissing),
+        Err(why) => Err(why.into()),
+    }
+}
+
+/// A numeric user metadata value, `None` when absent or malformed
+fn metadata_number<T: FromStr>(object: &ObjectInfo, key: &str) -> Option<T> {
+    object.metadata.get(key)?.parse().ok()
+}
+
+/// Width and height of an encoded image, read from its headers only so a
+/// prefix of the file is enough
+fn image_dimensions(source: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(source))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// What `get_asset_info` read from the content of an asset uploaded without
+/// its dimensions or play length. Kept in the thumbnails bucket under the
+/// asset's etag, so assets are never changed on a read and an overwritten
+/// one is probed again. Values that couldn't be read are kept as `None`, an
+/// unreadable file is only probed once.
+#[derive(Default, Serialize, Deserialize)]
+struct ProbedInfo {
+    width: Option<u32>,
+    height: Option<u32>,
+    duration_seconds: Option<f64>,
+}
+
+/// Key of the probed info of an asset, next to its thumbnails
+fn probed_info_key(config: &AppConfig, asset: &str, etag: &str) -> String {
+    format!("{}/{}.json", config.object_key(asset), etag)
+}
+
+/// Dimensions or play length of an asset that predates them being recorded
+/// at upload, from the side index or, the first time, from its content
+async fn probed_info(
+    config: &AppConfig,
+    storage: &SharedStorage,
+    asset: &str,
+    stat: &ObjectInfo,
+) -> Result<ProbedInfo> {
+    let key = probed_info_key(config, asset, &stat.etag);
+    if let Ok((_, stream)) = storage.get(&config.thumbnails_bucket, &key, None).await
+        && let Ok(recorded) = collect_bytes(stream).await
+        && let Ok(probed) = serde_json::from_slice(&recorded)
+    {
+        return Ok(probed);
+    }
+
+    let mut probed = ProbedInfo::default();
+    if is_image_asset(asset) {
+        if let Some((width, height)) = probe_dimensions(config, storage.as_ref(), stat).await? {
+            probed.width = Some(width);
+            probed.height = Some(height);
+        }
+    } else if is_media_asset(asset) {
+        probed.duration_seconds = probe_duration(config, storage, stat).await?;
+    }
+
+    // Failing only costs probing again next time
+    let recorded = serde_json::to_vec(&probed).map_err(InternalServerError)?;
+    if let Err(why) = storage
+        .put(
+            &config.thumbnails_bucket,
+            &key,
+            Bytes::from(recorded),
+            Some("application/json"),
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await
+    {
+        warn!(asset = %asset, "Error recording probed asset info: {}", why);
+    }
+
+    Ok(probed)
+}
+
+/// Dimensions of an image asset, only the start of the object is read
+async fn probe_dimensions(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    stat: &ObjectInfo,
+) -> Result<Option<(u32, u32)>> {
+    if stat.size == 0 {
+        return Ok(None);
+    }
+
+    let range = Some((0, DIMENSIONS_PROBE_BYTES.min(stat.size)));
+    let Some(prefix) = read_object(config, storage, &stat.key, range).await? else {
+        return Ok(None);
+    };
+
+    let dimensions = image_dimensions(&prefix);
+    if dimensions.is_none() {
+        debug!(asset = %stat.key, "could not read image dimensions");
+    }
+    Ok(dimensions)
+}
+
+/// Play length of an audio or video asset in seconds, read from its
+/// container through ranged requests
+async fn probe_duration(
+    config: &AppConfig,
+    storage: &SharedStorage,
+    stat: &ObjectInfo,
+) -> Result<Option<f64>> {
+    if stat.size == 0 {
+        return Ok(None);
+    }
+
+    let failure = Arc::new(Mutex::new(None));
+    let source = RangedObject {
+        storage: storage.clone(),
+        runtime: tokio::runtime::Handle::current(),
+        bucket: config.assets_bucket.clone(),
+        key: stat.key.clone(),
+        size: stat.size,
+        position: 0,
+        chunk_start: 0,
+        chunk: Bytes::new(),
+        fetched: 0,
+        failure: failure.clone(),
+    };
+    let name = stat.key.clone();
+    // A panic on a malformed file only loses the duration
+    let duration = tokio::task::spawn_blocking(move || media_duration(&name, Box::new(source)))
+        .await
+        .ok()
+        .flatten();
+
+    let failure = failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    match failure {
+        // Deleted since it was looked up
+        Some(StorageError::NotFound) => return Ok(None),
+        // Not a property of the file, so not worth recording
+        Some(why) => return Err(why.into()),
+        None => {}
+    }
+    if duration.is_none() {
+        debug!(asset = %stat.key, "could not read media duration");
+    }
+    Ok(duration)
+}
+
+/// An asset read with ranged requests as symphonia asks for its bytes, so
+/// only the parts of a container that are looked at are fetched, up to
+/// `DURATION_PROBE_MAX_BYTES`. Containers keeping their index at the end
+/// are reached by seeking rather than reading past the media. Used from a
+/// blocking thread, each fetch blocks on the runtime.
+struct RangedObject {
+    storage: SharedStorage,
+    runtime: tokio::runtime::Handle,
+    bucket: String,
+    key: String,
+    size: u64,
+    position: u64,
+    chunk_start: u64,
+    chunk: Bytes,
+    fetched: u64,
+    /// Why a fetch failed, symphonia only sees that reading did
+    failure: Arc<Mutex<Option<StorageError>>>,
+}
+
+impl RangedObject {
+    fn fetch(&mut self) -> std::io::Result<()> {
+        let length = DURATION_PROBE_CHUNK_BYTES.min(self.size - self.position);
+        self.fetched += length;
+        if self.fetched > DURATION_PROBE_MAX_BYTES {
+            return Err(std::io::Error::other("read too much of the asset"));
+        }
+
+        let range = Some((self.position, length));
+        let fetched = self.runtime.block_on(async {
+            let (_, stream) = self.storage.get(&self.bucket, &self.key, range).await?;
+            collect_bytes(stream).await
+        });
+        let chunk = match fetched {
+            Ok(chunk) if !chunk.is_empty() => chunk,
+            Ok(_) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Err(why) => {
+                let message = why.to_string();
+                *self.failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(why);
+                return Err(std::io::Error::other(message));
+            }
+        };
+
+        self.chunk_start = self.position;
+        self.chunk = chunk;
+        Ok(())
+    }
+}
+
+impl Read for RangedObject {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if buffer.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let chunk_end = self.chunk_start + self.chunk.len() as u64;
+        if self.position < self.chunk_start || self.position >= chunk_end {
+            self.fetch()?;
+        }
+
+        let offset = (self.position - self.chunk_start) as usize;
+        let count = buffer.len().min(self.chunk.len() - offset);
+        buffer[..count].copy_from_slice(&self.chunk[offset..offset + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for RangedObject {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+impl MediaSource for RangedObject {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.size)
+    }
+}
+
+/// Duration of the default track of an audio or video container, `None` when
+/// the container isn't recognised or doesn't record its length
+fn media_duration(filename: &str, source: Box<dyn MediaSource>) -> Option<f64> {
+    let mut hint = Hint::new();
+    if let Some((_, extension)) = filename.rsplit_once('.') {
+        hint.with_extension(extension);
+    }
+
+    let source = MediaSourceStream::new(source, Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+
+    let track = probed.format.default_track()?;
+    let time_base = track.codec_params.time_base?;
+    let frames = track.codec_params.n_frames?;
+    if time_base.numer == 0 || time_base.denom == 0 {
+        return None;
+    }
+
+    let time = time_base.calc_time(frames);
+    Some(time.seconds as f64 + time.frac)
+}
+
+/// Read the object `key`, or the `(offset, length)` slice of it, into
+/// memory. `None` when it was deleted since it was looked up.
+async fn read_object(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    key: &str,
+    range: Option<(u64, u64)>,
+) -> Result<Option<Bytes>> {
+    let read = match storage.get(&config.assets_bucket, key, range).await {
+        Ok((_, stream)) => collect_bytes(stream).await,
+        Err(why) => Err(why),
+    };
+    match read {
+        Ok(contents) => Ok(Some(contents)),
+        Err(StorageError::NotFound) => Ok(None),
+        Err(why) => Err(why.into()),
+    }
+}
+
+/// Scale an encoded image down to fit within `width` x `height`, keeping its
+/// aspect ratio. The result is encoded in the source format when the encoder
+/// supports it, PNG otherwise.
+fn render_thumbnail(
+    source: &[u8],
+    width: u32,
+    height: u32,
+) -> image::ImageResult<(Vec<u8>, ImageFormat)> {
+    let format = image::guess_format(source)?;
+    let thumbnail = image::load_from_memory_with_format(source, format)?.thumbnail(width, height);
+
+    let format = if format.writing_enabled() {
+        format
+    } else {
+        ImageFormat::Png
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut encoded, format)?;
+
+    Ok((encoded.into_inner(), format))
+}
+
+/// Re-encode an image as lossless WebP, upright as its EXIF orientation
+/// says since the orientation tag doesn't survive
+fn transcode_to_webp(source: &[u8]) -> image::ImageResult<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(source))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    // The encoder only takes 8-bit RGB(A)
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.into_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.into_rgb8())
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, ImageFormat::WebP)?;
+    Ok(encoded.into_inner())
+}
+
+/// Remove EXIF, XMP and textual metadata from JPEG, PNG and WebP images.
+///
+/// The container is edited in place rather than re-encoded, so pixel data is
+/// left untouched. Colour profiles are kept. Anything else, including images
+/// that fail to parse, is returned unchanged.
+fn strip_image_metadata(content_type: &str, contents: Vec<u8>) -> Vec<u8> {
+    let bytes = Bytes::from(contents);
+
+    let stripped = match content_type {
+        "image/jpeg" => Jpeg::from_bytes(bytes.clone()).ok().map(|mut jpeg| {
+            for marker in [jpeg_markers::APP1, jpeg_markers::APP13, jpeg_markers::COM] {
+                jpeg.remove_segments_by_marker(marker);
+            }
+            jpeg.encoder().bytes()
+        }),
+        "image/png" => Png::from_bytes(bytes.clone()).ok().map(|mut png| {
+            for kind in [*b"eXIf", *b"tEXt", *b"iTXt", *b"zTXt", *b"tIME"] {
+                png.remove_chunks_by_type(kind);
+            }
+            png.encoder().bytes()
+        }),
+        "image/webp" => WebP::from_bytes(bytes.clone()).ok().map(|mut webp| {
+            webp.remove_chunks_by_id(*b"XMP ");
+            // Also recomputes the extended header flags
+            webp.set_exif(None);
+            webp.encoder().bytes()
+        }),
+        _ => None,
+    };
+
+    stripped.unwrap_or(bytes).to_vec()
+}
+
+/// Read an upload into memory, or `None` if it is larger than `limit` bytes.
+///
+/// The size poem recorded while receiving the part is checked before
+/// anything is buffered, and the read itself is capped as well.
+async fn read_upload(upload: Upload, limit: u64) -> Result<Option<Vec<u8>>> {
+    if upload.size() as u64 > limit {
+        return Ok(None);
+    }
+
+    let mut contents = Vec::with_capacity(upload.size());
+    upload
+        .into_async_read()
+        .take(limit + 1)
+        .read_to_end(&mut contents)
+        .await
+        .map_err(InternalServerError)?;
+
+    if contents.len() as u64 > limit {
+        return Ok(None);
+    }
+
+    Ok(Some(contents))
+}
+
+#[derive(Multipart, Debug)]
+pub struct PutImageRequest {
+    pub asset: Upload,
+    /// Name to store the asset under instead of the file's own name
+    pub name: Option<String>,
+    /// `Cache-Control` to send with downloads of this asset instead of
+    /// `DOWNLOAD_CACHE_CONTROL`
+    pub cache_control: Option<String>,
+    /// Custom metadata to store with the asset, as a JSON object. Only keys
+    /// listed in `ALLOWED_METADATA_KEYS` are accepted.
+    pub metadata: Option<JsonField<HashMap<String, String>>>,
+}
+
+#[derive(Multipart, Debug)]
+pub struct PatchAssetRequest {
+    pub asset: Upload,
+}
+
+#[derive(Multipart, Debug)]
+pub struct PutAssetsBatchRequest {
+    pub assets: Vec<Upload>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Object)]
+pub struct BatchUploadResult {
+    /// File name the upload was sent with
+    pub name: String,
+    /// Where the asset is served from, when it was stored
+    #[oai(skip_serializing_if_is_none)]
+    pub path: Option<String>,
+    /// Whether a new asset was created or an identical one reused, when it
+    /// was stored
+    #[oai(skip_serializing_if_is_none)]
+    pub outcome: Option<UploadOutcome>,
+    /// Why the upload was refused, when it wasn't stored
+    #[oai(skip_serializing_if_is_none)]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, poem_openapi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum UploadOutcome {
+    /// The upload was stored as a new object
+    Created,
+    /// An asset with identical content already existed and was reused
+    Existing,
+}
+
+impl UploadOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            UploadOutcome::Created => "created",
+            UploadOutcome::Existing => "existing",
+        }
+    }
+}
+
+/// An accepted upload
+struct StoredUpload {
+    /// Where the asset is served from
+    path: String,
+    outcome: UploadOutcome,
+}
+
+#[derive(ApiResponse)]
+enum PutAssetsBatchApiResponse {
+    #[oai(status = 200)]
+    Ok(Json<Vec<BatchUploadResult>>),
+    /// `MAX_CONCURRENT_UPLOADS` uploads are already in progress, retry
+    /// after the given seconds
+    #[oai(status = 503)]
+    Busy(#[oai(header = "Retry-After")] u64),
+}
+
+/// Why an upload was not stored
+enum UploadRejection {
+    MissingName,
+    InvalidName,
+    TooLarge,
+    UnsupportedMediaType,
+    AlreadyExists,
+    StorageUnavailable,
+    StorageTimeout,
+}
+
+impl UploadRejection {
+    /// Rejection for a failed storage request, telling timeouts apart
+    fn from_storage(why: &StorageError) -> Self {
+        match why {
+            StorageError::Timeout => UploadRejection::StorageTimeout,
+            _ => UploadRejection::StorageUnavailable,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            UploadRejection::MissingName => {
+                "the upload part must include a filename in its Content-Disposition"
+            }
+            UploadRejection::InvalidName => {
+                "the upload's filename is empty, too long or contains characters that are not allowed"
+            }
+            UploadRejection::TooLarge => "upload exceeds the maximum size",
+            UploadRejection::UnsupportedMediaType => "file type is not accepted or does not match its content",
+            UploadRejection::AlreadyExists => "an asset with this name already exists",
+            UploadRejection::StorageUnavailable => "object storage could not store the upload",
+            UploadRejection::StorageTimeout => "object storage did not answer in time",
+        }
+    }
+}
+
+/// Response to a single upload
+fn put_asset_response(
+    config: &AppConfig,
+    stored: std::result::Result<StoredUpload, UploadRejection>,
+    idempotency: Option<Pending>,
+) -> Result<PutAssetResponse> {
+    match stored {
+        Ok(stored) => {
+            if let Some(pending) = idempotency {
+                let upload = CachedUpload {
+                    path: stored.path.clone(),
+                    outcome: stored.outcome.as_str(),
+                };
+                pending.finish(upload, Duration::from_secs(config.idempotency_key_ttl_secs));
+            }
+            Ok(PutAssetResponse::Ok(
+                PlainText(stored.path),
+                stored.outcome.as_str().to_string(),
+            ))
+        }
+        Err(UploadRejection::MissingName) => Err(ApiError::missing_filename().into()),
+        Err(UploadRejection::InvalidName) => Err(ApiError::invalid_asset_name().into()),
+        Err(UploadRejection::TooLarge) => Ok(PutAssetResponse::PayloadTooLarge),
+        Err(UploadRejection::UnsupportedMediaType) => Ok(PutAssetResponse::UnsupportedMediaType),
+        Err(UploadRejection::AlreadyExists) => Ok(PutAssetResponse::Conflict),
+        Err(UploadRejection::StorageUnavailable) => Ok(PutAssetResponse::StorageUnavailable),
+        Err(UploadRejection::StorageTimeout) => Err(ApiError::storage_timeout().into()),
+    }
+}
+
+/// Check the `Cache-Control` and custom metadata sent with an upload,
+/// returning the user metadata entries to store with it. Values have to be
+/// printable ASCII to survive being sent as headers. Keys may carry an
+/// `x-meta-` prefix.
+fn upload_metadata(
+    config: &AppConfig,
+    cache_control: Option<String>,
+    metadata: Option<HashMap<String, String>>,
+) -> std::result::Result<Vec<(String, String)>, ApiError> {
+    let is_printable = |value: &str| value.chars().all(|c| c.is_ascii() && !c.is_ascii_control());
+    let mut entries = Vec::new();
+
+    if let Some(cache_control) = cache_control
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+    {
+        if !is_printable(&cache_control) {
+            return Err(ApiError::invalid_metadata("cache_control must be printable ASCII"));
+        }
+        entries.push((CACHE_CONTROL_METADATA_KEY.to_string(), cache_control));
+    }
+
+    for (key, value) in metadata.unwrap_or_default() {
+        let key = key.to_lowercase();
+        let key = key.strip_prefix("x-meta-").unwrap_or(&key).to_string();
+        if RESERVED_METADATA_KEYS.contains(&key.as_str())
+            || !config.allowed_metadata_keys.contains(&key)
+        {
+            return Err(ApiError::invalid_metadata(format!(
+                "metadata key {key:?} is not allowed"
+            )));
+        }
+        if !is_printable(&value) {
+            return Err(ApiError::invalid_metadata(format!(
+                "metadata value of {key:?} must be printable ASCII"
+            )));
+        }
+        entries.push((key, value));
+    }
+
+    let size: usize = entries.iter().map(|(key, value)| key.len() + value.len()).sum();
+    if size > MAX_UPLOAD_METADATA_BYTES {
+        return Err(ApiError::invalid_metadata(format!(
+            "metadata must not exceed {MAX_UPLOAD_METADATA_BYTES} bytes"
+        )));
+    }
+    Ok(entries)
+}
+
+/// Claim the `Idempotency-Key` of an upload. `Err` holds the response to a
+/// retry of an upload that already succeeded, `Ok(None)` means no key was
+/// sent. Keys are scoped to the token's subject so clients can't collide.
+/// `fingerprint` is only worked out when a key was sent.
+fn claim_idempotency_key(
+    claims: &BearerAuthorization,
+    key: Option<&str>,
+    fingerprint: impl FnOnce() -> String,
+) -> Result<std::result::Result<Option<Pending>, PutAssetResponse>> {
+    let Some(key) = key.map(str::trim) else {
+        return Ok(Ok(None));
+    };
+    if key.is_empty() || key.len() > idempotency::MAX_KEY_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Idempotency-Key must be between 1 and {} characters",
+            idempotency::MAX_KEY_LENGTH
+        ))
+        .into());
+    }
+
+    match idempotency::claim(&claims.sub, key, &fingerprint()) {
+        Claim::New(pending) => Ok(Ok(Some(pending))),
+        Claim::Replay(upload) => {
+            debug!(asset = %upload.path, "replayed upload for a repeated idempotency key");
+            Ok(Err(PutAssetResponse::Ok(
+                PlainText(upload.path),
+                upload.outcome.to_string(),
+            )))
+        }
+        Claim::InProgress => Err(ApiError::idempotency_key_in_use().into()),
+        Claim::Mismatch => Err(ApiError::idempotency_key_reused().into()),
+    }
+}
+
+/// Name of an existing asset whose content hashes to `sha256`.
+///
+/// The index isn't updated when assets are deleted or overwritten, so the
+/// asset it points at is checked to still carry the same hash.
+async fn find_duplicate(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    sha256: &str,
+) -> Option<String> {
+    let (_, indexed) = storage
+        .get(&config.hash_index_bucket, &config.object_key(sha256), None)
+        .await
+        .ok()?;
+    let indexed = collect_bytes(indexed).await.ok()?;
+    let name = String::from_utf8(indexed.to_vec()).ok()?;
+
+    let stat = storage.stat(&config.assets_bucket, &config.object_key(&name)).await.ok()?;
+
+    (stat.metadata.get(SHA256_METADATA_KEY).map(String::as_str) == Some(sha256))
+        .then_some(name)
+}
+
+/// Stream the `(name, key)` assets of `bucket` into a zip archive written
+/// to `writer`, skipping assets that can't be fetched. Entries are stored
+/// uncompressed, media formats are compressed already.
+async fn write_zip_archive(
+    storage: &dyn Storage,
+    bucket: &str,
+    assets: Vec<(String, String)>,
+    writer: DuplexStream,
+) -> anyhow::Result<()> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+
+    for (name, key) in assets {
+        // Missing objects are skipped, storage errors are already logged
+        let Ok((_, mut stream)) = storage.get(bucket, &key, None).await else {
+            continue;
+        };
+
+        let entry = ZipEntryBuilder::new(name.into(), Compression::Stored);
+        let mut entry_writer = zip.write_entry_stream(entry).await?;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            metrics::record_download(chunk.len());
+            entry_writer.write_all(&chunk).await?;
+        }
+        entry_writer.close().await?;
+    }
+
+    zip.close().await?;
+    Ok(())
+}
+
+/// Reject an upload that would replace the existing asset `name`. Another
+/// upload can still land in between, S3 has no conditional put.
+async fn ensure_absent(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    name: &str,
+) -> std::result::Result<(), UploadRejection> {
+    match storage.stat(&config.assets_bucket, &config.object_key(name)).await {
+        Ok(_) => {
+            warn!(asset = %name, "rejected upload that would overwrite an existing asset");
+            Err(UploadRejection::AlreadyExists)
+        }
+        Err(StorageError::NotFound) => Ok(()),
+        Err(why) => {
+            error!(asset = %name, "Error checking for an existing asset: {}", why);
+            Err(UploadRejection::from_storage(&why))
+        }
+    }
+}
+
+/// Store a request body as the asset `name` without buffering it, for files
+/// too large to upload through `store_upload`.
+///
+/// Only the first few KiB are held back to check the content matches the
+/// extension, the rest is passed on to the backend as it arrives, to MinIO
+/// one `STREAM_PART_BYTES` part at a time. Since the content is never seen
+/// as a whole it is neither hashed nor stripped of metadata.
+async fn store_stream_upload(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    name: String,
+    body: Body,
+    content_length: Option<u64>,
+    overwrite: bool,
+) -> Result<std::result::Result<StoredUpload, UploadRejection>> {
+    if !is_valid_asset_type(config, &name) {
+        warn!(asset = %name, "rejected upload with an unsupported extension");
+        return Ok(Err(UploadRejection::UnsupportedMediaType));
+    }
+
+    // SVG has to be seen whole to be sanitized
+    if content_type_for(&name) == Some("image/svg+xml") {
+        warn!(asset = %name, "rejected streamed SVG upload");
+        return Ok(Err(UploadRejection::UnsupportedMediaType));
+    }
+
+    let limit = config.max_stream_upload_bytes;
+    if content_length.is_some_and(|length| length > limit) {
+        warn!(asset = %name, size = content_length, limit, "rejected oversized upload");
+        return Ok(Err(UploadRejection::TooLarge));
+    }
+
+    if !overwrite && let Err(rejection) = ensure_absent(config, storage, &name).await {
+        return Ok(Err(rejection));
+    }
+
+    let mut body = body.into_bytes_stream();
+    let mut head = Vec::new();
+    while head.len() < SNIFF_BYTES {
+        match body.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk.map_err(BadRequest)?),
+            None => break,
+        }
+    }
+
+    if !content_matches_type(config, &name, &head) {
+        warn!(asset = %name, "rejected upload whose content does not match its extension");
+        return Ok(Err(UploadRejection::UnsupportedMediaType));
+    }
+
+    // Counted as it streams by, the declared length isn't trusted
+    let too_large = Arc::new(AtomicBool::new(false));
+    let checksum = Arc::new(Mutex::new(
+        config
+            .verify_uploads
+            .then(|| UploadChecksum::new(STREAM_PART_BYTES)),
+    ));
+    let mut received = 0u64;
+    let content = futures_util::stream::once(async move { Ok(Bytes::from(head)) })
+        .chain(body)
+        .map({
+            let too_large = too_large.clone();
+            let checksum = checksum.clone();
+            move |chunk| {
+                let chunk = chunk.map_err(|why| StorageError::Failed(why.into()))?;
+                received += chunk.len() as u64;
+                if received > limit {
+                    too_large.store(true, Ordering::Relaxed);
+                    return Err(StorageError::Failed(anyhow::anyhow!(
+                        "upload exceeds the maximum size"
+                    )));
+                }
+                if let Some(checksum) = checksum
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .as_mut()
+                {
+                    checksum.update(&chunk);
+                }
+                Ok(chunk)
+            }
+        });
+
+    let response = storage
+        .put_stream(
+            &config.assets_bucket,
+            &config.object_key(&name),
+            Box::pin(content),
+            content_length,
+            content_type_for(&name),
+        )
+        .await;
+
+    match response {
+        Ok(stored) => {
+            let checksum = checksum
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+            verify_stored(config, storage, &name, &stored.etag, checksum).await?;
+
+            metrics::record_upload(stored.size as usize);
+            info!(asset = %name, size = stored.size, "stored streamed asset");
+            asset_cache::invalidate(&name);
+            webhooks::asset_created(config, &name, Some(stored.size));
+            Ok(Ok(StoredUpload {
+                path: asset_url(config, &name),
+                outcome: UploadOutcome::Created,
+            }))
+        }
+        Err(_) if too_large.load(Ordering::Relaxed) => {
+            warn!(asset = %name, limit, "rejected oversized upload");
+            Ok(Err(UploadRejection::TooLarge))
+        }
+        Err(why) => {
+            error!(asset = %name, "Error storing streamed asset: {}", why);
+            Ok(Err(UploadRejection::from_storage(&why)))
+        }
+    }
+}
+
+/// With `VERIFY_UPLOADS`, compare the etag MinIO answered with against the
+/// bytes sent, removing the object again if they disagree
+async fn verify_stored(
+    config: &AppConfig,
+    storage: &dyn Storage,
+    name: &str,
+    etag: &str,
+    checksum: Option<UploadChecksum>,
+) -> Result<()> {
+    let Some(checksum) = checksum else {
+        return Ok(());
+    };
+    match checksum.matches(etag) {
+        Some(true) => Ok(()),
+        None => {
+            debug!(asset = name, etag, "not verifying an upload without an MD5 etag");
+            Ok(())
+        }
+        Some(false) => {
+            metrics::record_storage_error("verify_upload");
+            error!(asset = name, etag, "stored asset does not match the upload, removing it");
+            if let Err(why) = storage.remove(&config.assets_bucket, &config.object_key(name)).await {
+                error!(asset = name, "Error removing corrupt asset: {}", why);
+            }
+            Err(ApiError::upload_corrupted().into())
+        }
+    }
+}
+
+/// Validate an upload read with `read_upload` and store it, returning the
+/// path it is served from. The asset is named after `file_name`, the name
+/// sent with the upload or else its filename, and `custom_metadata` is
+/// stored with it. Unless `overwrite` is set an existing asset with the same name
+/// is left alone and the upload rejected. With `dedupe` an existing asset
+/// with the same content is returned instead of storing a copy.
+#[allow(clippy::too_many_arguments)]
+async fn store_upload(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    file_name: Option<&str>,
+    contents: Vec<u8>,
+    custom_metadata: &[(String, String)],
+    overwrite: bool,
+    dedupe: bool,
+) -> Result<std::result::Result<StoredUpload, UploadRejection>> {
+    let Some(file_name) = file_name else {
+        warn!("rejected upload without a filename");
+        return Ok(Err(UploadRejection::MissingName));
+    };
+    let Some(name) = new_asset_name(config, file_name) else {
+        warn!(file_name, "rejected upload with an unusable filename");
+        return Ok(Err(UploadRejection::InvalidName));
+    };
+
+    let size = contents.len();
+    let needs_extension = match check_upload_name(storage, config, &name, Some(size), overwrite).await {
+        Ok(needs_extension) => needs_extension,
+        Err(rejection) => return Ok(Err(rejection)),
+    };
+
+    store_contents(
+        storage,
+        config,
+        name,
+        needs_extension,
+        contents,
+        custom_metadata,
+        overwrite,
+        dedupe,
+    )
+    .await
+}
+
+/// Check the name of an upload before its content is read, returning
+/// whether it still needs an extension detected from the content.
+async fn check_upload_name(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    name: &str,
+    size: Option<usize>,
+    overwrite: bool,
+) -> std::result::Result<bool, UploadRejection> {
+    // Validate file type - only allow images, audio, and video files.
+    // The extension is a cheap pre-filter, the content itself decides.
+    // Names without a known extension, such as `IMG_0001` from a phone, get
+    // one from their content instead. Blocked extensions stay rejected.
+    let is_blocked = extension_of(name).is_some_and(|ext| config.blocked_extensions.contains(&ext));
+    let needs_extension = !is_valid_asset_type(config, name)
+        && content_type_for(name).is_none()
+        && !is_blocked;
+    if !needs_extension && !is_valid_asset_type(config, name) {
+        warn!(asset = %name, size, "rejected upload with an unsupported extension");
+        return Err(UploadRejection::UnsupportedMediaType);
+    }
+
+    // Checked before reading the body so a collision is cheap to report
+    if !needs_extension && !overwrite {
+        ensure_absent(config, storage, name).await?;
+    }
+
+    Ok(needs_extension)
+}
+
+/// Validate the content of an upload and store it. `needs_extension` comes
+/// from `check_upload_name`.
+#[allow(clippy::too_many_arguments)]
+async fn store_contents(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    mut name: String,
+    needs_extension: bool,
+    mut contents: Vec<u8>,
+    custom_metadata: &[(String, String)],
+    overwrite: bool,
+    dedupe: bool,
+) -> Result<std::result::Result<StoredUpload, UploadRejection>> {
+    let size = contents.len();
+
+    if needs_extension {
+        let Some(extension) = sniffed_extension(&contents) else {
+            warn!(asset = %name, size, "rejected upload whose type could not be detected");
+            return Ok(Err(UploadRejection::UnsupportedMediaType));
+        };
+        name.push_str(extension);
+        if !is_valid_asset_type(config, &name) {
+            warn!(asset = %name, size, "rejected upload with an unsupported detected type");
+            return Ok(Err(UploadRejection::UnsupportedMediaType));
+        }
+        info!(asset = %name, size, "named upload after its detected type");
+
+        if !overwrite && let Err(rejection) = ensure_absent(config, storage, &name).await {
+            return Ok(Err(rejection));
+        }
+    }
+
+    if !content_matches_type(config, &name, &contents) {
+        warn!(asset = %name, size, "rejected upload whose content does not match its extension");
+        return Ok(Err(UploadRejection::UnsupportedMediaType));
+    }
+
+    let is_transcodable = matches!(content_type_for(&name), Some("image/jpeg" | "image/png"));
+    if config.transcode_to_webp && is_transcodable {
+        let stem = name.rsplit_once('.').map_or(name.as_str(), |(stem, _)| stem);
+        let webp_name = format!("{stem}.webp");
+
+        let (source, transcoded) = tokio::task::spawn_blocking(move || {
+            let transcoded = transcode_to_webp(&contents);
+            (contents, transcoded)
+        })
+        .await
+        .map_err(InternalServerError)?;
+        contents = source;
+
+        match transcoded {
+            Ok(webp) if webp.len() < contents.len() && is_valid_asset_type(config, &webp_name) => {
+                if !overwrite
+                    && let Err(rejection) = ensure_absent(config, storage, &webp_name).await
+                {
+                    return Ok(Err(rejection));
+                }
+                info!(asset = %name, size, webp_size = webp.len(), "transcoded upload to WebP");
+                name = webp_name;
+                contents = webp;
+            }
+            Ok(webp) => {
+                debug!(asset = %name, size, webp_size = webp.len(), "kept upload, WebP was not smaller");
+            }
+            Err(why) => {
+                warn!(asset = %name, "Error transcoding upload to WebP, keeping the original: {}", why);
+            }
+        }
+    }
+
+    let Some(contents) = prepare_contents(config, &name, contents) else {
+        warn!(asset = %name, size, "rejected SVG upload that is not well formed");
+        return Ok(Err(UploadRejection::UnsupportedMediaType));
+    };
+
+    // Hashed after any metadata stripping so it matches what is downloaded
+    let sha256 = hex::encode(Sha256::digest(&contents));
+
+    if dedupe && let Some(existing) = find_duplicate(config, storage, &sha256).await {
+        info!(asset = %name, %existing, %sha256, "upload matched an existing asset");
+        return Ok(Ok(StoredUpload {
+            path: asset_url(config, &existing),
+            outcome: UploadOutcome::Existing,
+        }));
+    }
+
+    if let Err(rejection) =
+        put_contents(storage, config, &name, contents, &sha256, custom_metadata, None)
+            .await?
+    {
+        return Ok(Err(rejection));
+    }
+
+    Ok(Ok(StoredUpload {
+        path: asset_url(config, &name),
+        outcome: UploadOutcome::Created,
+    }))
+}
+
+/// Sanitize SVG and strip image metadata as configured, the way every
+/// stored upload is. `None` for SVG that is not well formed.
+fn prepare_contents(config: &AppConfig, name: &str, contents: Vec<u8>) -> Option<Vec<u8>> {
+    match content_type_for(name) {
+        Some("image/svg+xml") => sanitize_svg(&contents),
+        Some(content_type) if config.strip_image_metadata => {
+            Some(strip_image_metadata(content_type, contents))
+        }
+        _ => Some(contents),
+    }
+}
+
+/// Store validated `contents` as `name` along with its hash, image
+/// dimensions and `custom_metadata`, and index its hash for dedupe. `tags`
+/// are attached in the same request.
+#[allow(clippy::too_many_arguments)]
+async fn put_contents(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    name: &str,
+    contents: Vec<u8>,
+    sha256: &str,
+    custom_metadata: &[(String, String)],
+    tags: Option<HashMap<String, String>>,
+) -> Result<std::result::Result<(), UploadRejection>> {
+    let mut metadata = HashMap::new();
+    metadata.insert(SHA256_METADATA_KEY.to_string(), sha256.to_string());
+    if is_image_asset(name)
+        && let Some((width, height)) = image_dimensions(&contents)
+    {
+        metadata.insert(WIDTH_METADATA_KEY.to_string(), width.to_string());
+        metadata.insert(HEIGHT_METADATA_KEY.to_string(), height.to_string());
+    }
+    let contents = Bytes::from(contents);
+    if is_media_asset(name) {
+        let source = Box::new(Cursor::new(contents.clone()));
+        let filename = name.to_string();
+        // A panic on a malformed file only loses the duration
+        if let Ok(Some(duration)) =
+            tokio::task::spawn_blocking(move || media_duration(&filename, source)).await
+        {
+            metadata.insert(DURATION_METADATA_KEY.to_string(), duration.to_string());
+        }
+    }
+    for (key, value) in custom_metadata {
+        metadata.insert(key.clone(), value.clone());
+    }
+
+    let contents_len = contents.len();
+    let checksum = config.verify_uploads.then(|| {
+        let mut checksum = UploadChecksum::new(u64::MAX);
+        checksum.update(&contents);
+        checksum
+    });
+    let stored = storage
+        .put(
+            &config.assets_bucket,
+            &config.object_key(name),
+            contents,
+            content_type_for(name),
+            &metadata,
+            &tags.unwrap_or_default(),
+        )
+        .await;
+    let stored = match stored {
+        Ok(stored) => stored,
+        Err(why) => {
+            error!(asset = %name, size = contents_len, "Error storing asset: {}", why);
+            return Ok(Err(UploadRejection::from_storage(&why)));
+        }
+    };
+    verify_stored(config, storage, name, &stored.etag, checksum).await?;
+
+    metrics::record_upload(contents_len);
+    info!(asset = %name, size = contents_len, %sha256, "stored asset");
+    asset_cache::invalidate(name);
+    webhooks::asset_created(config, name, Some(contents_len as u64));
+
+    // Losing an index entry only costs a future dedupe, not this upload
+    if let Err(why) = storage
+        .put(
+            &config.hash_index_bucket,
+            &config.object_key(sha256),
+            Bytes::from(name.to_string()),
+            None,
+            &HashMap::new(),
+            &HashMap::new(),
+        )
+        .await
+    {
+        warn!(asset = %name, "Error indexing asset hash: {}", why);
+    }
+
+    Ok(Ok(()))
+}
+
+#[OpenApi(prefix_path = "/assets", tag = "ApiTags::Assets")]
+impl AssetsApi {
+    /// Download an asset, or the `version_id` version of it. Images are
+    /// shown inline by default, `disposition` overrides that. With
+    /// `Want-Digest: sha-256` the response carries a `Digest` of the whole
+    /// object, taken from the hash stored at upload. Large objects stored
+    /// without one get no `Digest` rather than being read twice. `token` and
+    /// `exp` from a link made by `sign_asset` stand in for a bearer token.
+    #[oai(method = "get", path = "/:asset")]
+    #[allow(clippy::too_many_arguments)]
+    async fn get_asset(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        #[oai(name = "Range")] range: Header<Option<String>>,
+        #[oai(name = "If-None-Match")] if_none_match: Header<Option<String>>,
+        #[oai(name = "If-Modified-Since")] if_modified_since: Header<Option<String>>,
+        #[oai(name = "Want-Digest")] want_digest: Header<Option<String>>,
+        version_id: Query<Option<String>>,
+        disposition: Query<Option<Disposition>>,
+        token: Query<Option<String>>,
+        exp: Query<Option<u64>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        req: &Request,
+    ) -> Result<GetImageResponse> {
+        let want_sha256 = want_digest.as_deref().is_some_and(wants_sha256);
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+        auth.authorize_download(&config, &asset, token.as_deref(), *exp)?;
+
+        // Only whole, current objects are cached
+        let cacheable =
+            config.asset_cache_max_bytes > 0 && range.is_none() && version_id.is_none();
+        if cacheable {
+            let cached = asset_cache::get(&asset);
+            metrics::record_asset_cache_lookup(cached.is_some());
+            if let Some(cached) = cached {
+                return Ok(cached_asset_response(
+                    &asset,
+                    cached,
+                    if_none_match.as_deref(),
+                    if_modified_since.as_deref(),
+                    disposition.0,
+                    want_sha256,
+                ));
+            }
+        }
+
+        // Ranges and conditional requests are resolved against the object's
+        // metadata, so those need a stat up front. Plain downloads skip the
+        // extra round trip.
+        let needs_stat =
+            range.is_some() || if_none_match.is_some() || if_modified_since.is_some();
+        let stat = if needs_stat {
+            match storage
+                .stat_version(
+                    &config.assets_bucket,
+                    &config.object_key(&asset),
+                    version_id.as_deref(),
+                )
+                .await
+            {
+                Ok(response) => Some(response),
+                Err(StorageError::NotFound) => {
+                    return asset_not_found(storage.as_ref(), &config, &asset, version_id.as_deref(), req).await;
+                }
+                Err(why) => return Err(why.into()),
+            }
+        } else {
+            None
+        };
+
+        if let Some(stat) = &stat
+            && is_not_modified(
+                &stat.etag,
+                stat.last_modified,
+                if_none_match.as_deref(),
+                if_modified_since.as_deref(),
+            )
+        {
+            return Ok(GetImageResponse::NotModified(
+                format_etag(&stat.etag),
+                stat.last_modified.map(format_http_date),
+                download_cache_control(&config, stat.metadata.get(CACHE_CONTROL_METADATA_KEY)),
+            ));
+        }
+
+        let byte_range = match (range.as_deref(), &stat) {
+            (Some(range), Some(stat)) => match parse_range(range, stat.size) {
+                Some(byte_range) => Some(byte_range),
+                None => {
+                    return Ok(GetImageResponse::RangeNotSatisfiable(format!(
+                        "bytes */{}",
+                        stat.size
+                    )));
+                }
+            },
+            _ => None,
+        };
+
+        let slice = byte_range
+            .as_ref()
+            .map(|byte_range| (byte_range.start, byte_range.len()));
+        let (mut response, stream) = match storage
+            .get_version(
+                &config.assets_bucket,
+                &config.object_key(&asset),
+                version_id.as_deref(),
+                slice,
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(StorageError::NotFound) => {
+                return asset_not_found(storage.as_ref(), &config, &asset, version_id.as_deref(), req).await;
+            }
+            Err(why) => return Err(why.into()),
+        };
+
+        let content_type = resolve_content_type(&asset, response.content_type.as_deref());
+
+        let last_modified = response.last_modified.map(format_http_date);
+        let stored_sha256 = response.metadata.remove(SHA256_METADATA_KEY);
+        let mut digest = if want_sha256 {
+            stored_sha256.as_deref().and_then(sha256_digest)
+        } else {
+            None
+        };
+        let cache_control =
+            download_cache_control(&config, response.metadata.get(CACHE_CONTROL_METADATA_KEY));
+
+        // Hand the body stream straight to the client instead of buffering
+        // the whole object in memory. Objects small enough for the cache are
+        // read whole so they can be kept.
+        let body = if cacheable && asset_cache::accepts(&config, response.size) {
+            let contents = collect_bytes(stream).await?;
+            metrics::record_download(contents.len());
+
+            // Objects from before hashes were stored are small enough here
+            // to hash on the spot
+            let sha256 =
+                stored_sha256.unwrap_or_else(|| hex::encode(Sha256::digest(&contents)));
+            if want_sha256 && digest.is_none() {
+                digest = sha256_digest(&sha256);
+            }
+            let cached = CachedAsset {
+                contents: contents.clone(),
+                content_type: content_type.clone(),
+                sha256,
+                etag: Some(response.etag.clone()),
+                last_modified: response.last_modified,
+                cache_control: cache_control.clone(),
+            };
+            asset_cache::insert(&config, &asset, cached);
+            Body::from_bytes(contents)
+        } else {
+            Body::from_bytes_stream(stream.map(|chunk| {
+                if let Ok(chunk) = &chunk {
+                    metrics::record_download(chunk.len());
+                }
+                chunk.map_err(std::io::Error::other)
+            }))
+        };
+
+        debug!(
+            asset = %asset,
+            size = response.size,
+            range = ?byte_range.as_ref().map(ByteRange::content_range),
+            "serving asset"
+        );
+        let disposition = disposition.unwrap_or_else(|| Disposition::default_for(&content_type));
+        let attachment = Attachment::new(body)
+            .attachment_type(disposition.into())
+            .filename(download_filename(&asset));
+
+        let accept_ranges = "bytes".to_string();
+        let etag = Some(format_etag(&response.etag));
+
+        // The body is streamed, so the length has to be sent explicitly for
+        // clients to show progress. Compressed responses drop it again.
+        match byte_range {
+            Some(byte_range) => Ok(GetImageResponse::PartialContent(
+                attachment,
+                byte_range.len(),
+                content_type,
+                accept_ranges,
+                byte_range.content_range(),
+                etag,
+                last_modified,
+                digest,
+                cache_control,
+            )),
+            None => Ok(GetImageResponse::Ok(
+                attachment,
+                response.size,
+                content_type,
+                accept_ranges,
+                etag,
+                last_modified,
+                digest,
+                cache_control,
+            )),
+        }
+    }
+
+    /// Check that an asset exists and read its size and type without
+    /// downloading it
+    #[oai(method = "head", path = "/:asset")]
+    async fn head_asset(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<HeadAssetResponse> {
+        auth.authorize(&config)?;
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let stat = match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(stat) => stat,
+            Err(StorageError::NotFound) => return Ok(HeadAssetResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
+
+        Ok(HeadAssetResponse::Ok(
+            stat.size,
+            resolve_content_type(&asset, stat.content_type.as_deref()),
+            format_etag(&stat.etag),
+            stat.last_modified.map(format_http_date),
+            download_cache_control(&config, stat.metadata.get(CACHE_CONTROL_METADATA_KEY)),
+        ))
+    }
+
+    /// Upload an asset. An existing asset with the same name is only replaced
+    /// with `overwrite=true`, and never when `If-None-Match: *` is sent. With
+    /// `dedupe=true` an asset with identical content is reused if there is one.
+    /// Files without a known extension are stored with one matching their
+    /// content, the returned path has the name actually used. Retries sent
+    /// with the same `Idempotency-Key` get the first upload's response.
+    /// With `If-Match` the asset is only replaced while its etag matches,
+    /// `412` otherwise. A `name` field in the form stores the file under
+    /// that name instead of its own, checked the same way. `cache_control`
+    /// and `metadata` fields are stored with the asset, `cache_control` is
+    /// sent with its downloads.
+    #[oai(method = "put", path = "/")]
+    #[allow(clippy::too_many_arguments)]
+    async fn put_asset(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        upload_slots: Data<&UploadSlots>,
+        request: PutImageRequest,
+        overwrite: Query<Option<bool>>,
+        dedupe: Query<Option<bool>>,
+        #[oai(name = "If-None-Match")] if_none_match: Header<Option<String>>,
+        #[oai(name = "If-Match")] if_match: Header<Option<String>>,
+        #[oai(name = "Idempotency-Key")] idempotency_key: Header<Option<String>>,
+    ) -> Result<PutAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        // Held until the upload is stored, it is buffered in memory until then
+        let Some(_slot) = upload_slots.acquire().await else {
+            return Ok(PutAssetResponse::Busy(UPLOAD_RETRY_AFTER_SECONDS));
+        };
+
+        let custom_metadata = upload_metadata(
+            &config,
+            request.cache_control,
+            request.metadata.map(|metadata| metadata.0),
+        )?;
+
+        // Forms send empty text fields for inputs left blank
+        let name = request.name.filter(|name| !name.trim().is_empty());
+        let file_name = name
+            .as_deref()
+            .or(request.asset.file_name())
+            .map(str::to_string);
+        let size = request.asset.size();
+        let Some(contents) = read_upload(request.asset, config.max_upload_bytes).await? else {
+            warn!(size, limit = config.max_upload_bytes, "rejected oversized upload");
+            return Ok(PutAssetResponse::PayloadTooLarge);
+        };
+
+        // A retry has to send the same file with the same metadata
+        let fingerprint = || {
+            let sha256 = hex::encode(Sha256::digest(&contents));
+            idempotency::upload_fingerprint(
+                file_name.as_deref().unwrap_or_default(),
+                &sha256,
+                &custom_metadata,
+            )
+        };
+        let pending = match claim_idempotency_key(&claims, idempotency_key.as_deref(), fingerprint)? {
+            Ok(pending) => pending,
+            Err(replayed) => return Ok(replayed),
+        };
+
+        if let Some(if_match) = if_match.as_deref() {
+            let Some(asset) = new_asset_name(&config, file_name.as_deref().unwrap_or_default()) else {
+                return Err(ApiError::invalid_asset_name().into());
+            };
+            if !check_if_match(&config, storage.as_ref(), &asset, if_match).await? {
+                return Ok(PutAssetResponse::PreconditionFailed);
+            }
+        }
+
+        // A matching If-Match is a request to replace that very asset
+        let overwrite = (overwrite.unwrap_or(false) || if_match.is_some())
+            && if_none_match.as_deref().map(str::trim) != Some("*");
+
+        let dedupe = dedupe.unwrap_or(false);
+
+        let stored = store_upload(
+            storage.as_ref(),
+            &config,
+            file_name.as_deref(),
+            contents,
+            &custom_metadata,
+            overwrite,
+            dedupe,
+        );
+        put_asset_response(&config, stored.await?, pending)
+    }
+
+    /// Upload a single asset as the raw request body, streamed to storage
+    /// instead of buffered, for files too large for a multipart form. The
+    /// size limit is `MAX_STREAM_UPLOAD_BYTES`. Existing assets are only
+    /// replaced with `overwrite=true`, and never when `If-None-Match: *` is
+    /// sent. `Idempotency-Key` and `If-Match` work as for multipart uploads.
+    /// The name may be a nested key with its slashes sent as `%2F`.
+    #[oai(method = "put", path = "/:asset")]
+    #[allow(clippy::too_many_arguments)]
+    async fn put_asset_stream(
+        &self,
+        claims: BearerAuthorization,
+        asset: Path<String>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        body: Binary<Body>,
+        overwrite: Query<Option<bool>>,
+        #[oai(name = "Content-Length")] content_length: Header<Option<u64>>,
+        #[oai(name = "If-None-Match")] if_none_match: Header<Option<String>>,
+        #[oai(name = "If-Match")] if_match: Header<Option<String>>,
+        #[oai(name = "Idempotency-Key")] idempotency_key: Header<Option<String>>,
+    ) -> Result<PutAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let Some(asset) = new_asset_key(&config, &asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        // The content is only seen while it is stored, its length has to do
+        let fingerprint = || {
+            let length = content_length.map(|length| length.to_string()).unwrap_or_default();
+            idempotency::upload_fingerprint(&asset, &length, &[])
+        };
+        let pending = match claim_idempotency_key(&claims, idempotency_key.as_deref(), fingerprint)? {
+            Ok(pending) => pending,
+            Err(replayed) => return Ok(replayed),
+        };
+
+        if let Some(if_match) = if_match.as_deref()
+            && !check_if_match(&config, storage.as_ref(), &asset, if_match).await?
+        {
+            return Ok(PutAssetResponse::PreconditionFailed);
+        }
+
+        let overwrite = (overwrite.unwrap_or(false) || if_match.is_some())
+            && if_none_match.as_deref().map(str::trim) != Some("*");
+
+        let stored = store_stream_upload(storage.as_ref(), &config, asset, body.0, *content_length, overwrite);
+        put_asset_response(&config, stored.await?, pending)
+    }
+
+    /// Replace the content of an existing asset, keeping its name, custom
+    /// metadata and tags. Unlike `put_asset` this never creates an asset,
+    /// `404` if there is none of that name. The new content has to be of the
+    /// asset's type, checked as for uploads, so an image stays an image of
+    /// the same format and audio or video stays audio or video.
+    #[oai(method = "patch", path = "/:asset")]
+    #[allow(clippy::too_many_arguments)]
+    async fn patch_asset(
+        &self,
+        claims: BearerAuthorization,
+        asset: Path<String>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        upload_slots: Data<&UploadSlots>,
+        request: PatchAssetRequest,
+    ) -> Result<PatchAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let Some(_slot) = upload_slots.acquire().await else {
+            return Ok(PatchAssetResponse::Busy(UPLOAD_RETRY_AFTER_SECONDS));
+        };
+
+        let stat = match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(stat) => stat,
+            Err(StorageError::NotFound) => return Ok(PatchAssetResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
+        let tags = storage
+            .tags(&config.assets_bucket, &config.object_key(&asset))
+            .await?;
+
+        let size = request.asset.size();
+        let Some(contents) = read_upload(request.asset, config.max_upload_bytes).await? else {
+            warn!(asset = %asset, size, limit = config.max_upload_bytes, "rejected oversized upload");
+            return Ok(PatchAssetResponse::PayloadTooLarge);
+        };
+        if !content_matches_type(&config, &asset, &contents) {
+            warn!(asset = %asset, size, "rejected replacement whose content does not match the asset");
+            return Ok(PatchAssetResponse::UnsupportedMediaType);
+        }
+        let Some(contents) = prepare_contents(&config, &asset, contents) else {
+            warn!(asset = %asset, size, "rejected SVG upload that is not well formed");
+            return Ok(PatchAssetResponse::UnsupportedMediaType);
+        };
+
+        // Everything derived from the old content is recomputed or dropped
+        let derived = [SHA256_METADATA_KEY, WIDTH_METADATA_KEY, HEIGHT_METADATA_KEY, DURATION_METADATA_KEY];
+        let kept_metadata: Vec<(String, String)> = stat
+            .metadata
+            .iter()
+            .filter(|(key, _)| !derived.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        let sha256 = hex::encode(Sha256::digest(&contents));
+        let tags = Some(tags).filter(|tags| !tags.is_empty());
+        match put_contents(
+            storage.as_ref(),
+            &config,
+            &asset,
+            contents,
+            &sha256,
+            &kept_metadata,
+            tags,
+        )
+        .await? {
+            Ok(()) => Ok(PatchAssetResponse::Ok(PlainText(asset_url(&config, &asset)))),
+            Err(UploadRejection::StorageTimeout) => Err(ApiError::storage_timeout().into()),
+            Err(_) => Ok(PatchAssetResponse::StorageUnavailable),
+        }
+    }
+
+    /// Import an asset from a remote URL instead of uploading it. The file is
+    /// fetched with the `MAX_UPLOAD_BYTES` limit and `URL_UPLOAD_TIMEOUT_SECS`
+    /// timeout, then validated and stored like an upload. Hosts are
+    /// restricted by the `URL_UPLOAD_*` policy, internal addresses are
+    /// refused by default.
+    #[oai(method = "post", path = "/from-url")]
+    #[allow(clippy::too_many_arguments)]
+    async fn put_asset_from_url(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        upload_slots: Data<&UploadSlots>,
+        request: Json<UploadFromUrlRequest>,
+        overwrite: Query<Option<bool>>,
+        dedupe: Query<Option<bool>>,
+    ) -> Result<PutAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let Some(_slot) = upload_slots.acquire().await else {
+            return Ok(PutAssetResponse::Busy(UPLOAD_RETRY_AFTER_SECONDS));
+        };
+
+        let requested = match &request.name {
+            Some(name) => name.clone(),
+            None => reqwest::Url::parse(&request.url)
+                .ok()
+                .and_then(|url| {
+                    let segment = url.path_segments()?.next_back()?.to_string();
+                    Some(percent_encoding::percent_decode_str(&segment).decode_utf8_lossy().into_owned())
+                })
+                .unwrap_or_default(),
+        };
+        let Some(name) = new_asset_name(&config, &requested) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let overwrite = overwrite.unwrap_or(false);
+        let dedupe = dedupe.unwrap_or(false);
+
+        let needs_extension = match check_upload_name(storage.as_ref(), &config, &name, None, overwrite).await {
+            Ok(needs_extension) => needs_extension,
+            Err(rejection) => return put_asset_response(&config, Err(rejection), None),
+        };
+
+        let contents = match remote::fetch(&config, &request.url, config.max_upload_bytes).await {
+            Ok(contents) => contents,
+            Err(RemoteFetchError::Forbidden(reason)) => {
+                warn!(url = %request.url, %reason, "refused to import asset");
+                return Err(ApiError::url_not_allowed(reason).into());
+            }
+            Err(RemoteFetchError::TooLarge) => {
+                warn!(url = %request.url, limit = config.max_upload_bytes, "rejected oversized import");
+                return Ok(PutAssetResponse::PayloadTooLarge);
+            }
+            Err(RemoteFetchError::Unavailable(reason)) => {
+                warn!(url = %request.url, %reason, "Error fetching asset to import");
+                return Err(ApiError::remote_unavailable(reason).into());
+            }
+        };
+
+        info!(url = %request.url, asset = %name, size = contents.len(), "fetched asset to import");
+        let stored = store_contents(
+            storage.as_ref(),
+            &config,
+            name,
+            needs_extension,
+            contents,
+            &[],
+            overwrite,
+            dedupe,
+        );
+        put_asset_response(&config, stored.await?, None)
+    }
+
+    /// Upload several assets in one request. Every file is validated and
+    /// stored on its own, the response reports the outcome of each. Existing
+    /// assets are only replaced with `overwrite=true`, `dedupe=true` reuses
+    /// assets with identical content.
+    #[oai(method = "put", path = "/batch")]
+    #[allow(clippy::too_many_arguments)]
+    async fn put_assets_batch(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        upload_slots: Data<&UploadSlots>,
+        request: PutAssetsBatchRequest,
+        overwrite: Query<Option<bool>>,
+        dedupe: Query<Option<bool>>,
+    ) -> Result<PutAssetsBatchApiResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        // One slot for the whole batch, its files are stored one at a time
+        let Some(_slot) = upload_slots.acquire().await else {
+            return Ok(PutAssetsBatchApiResponse::Busy(UPLOAD_RETRY_AFTER_SECONDS));
+        };
+
+        let overwrite = overwrite.unwrap_or(false);
+        let dedupe = dedupe.unwrap_or(false);
+        let mut results = Vec::with_capacity(request.assets.len());
+
+        for upload in request.assets {
+            let file_name = upload.file_name().map(str::to_string);
+            let name = file_name.clone().unwrap_or_default();
+
+            let size = upload.size();
+            let stored = match read_upload(upload, config.max_upload_bytes).await? {
+                Some(contents) => {
+                    let file_name = file_name.as_deref();
+                    store_upload(storage.as_ref(), &config, file_name, contents, &[], overwrite, dedupe)
+                        .await?
+                }
+                None => {
+                    warn!(file_name = name, size, "rejected oversized upload");
+                    Err(UploadRejection::TooLarge)
+                }
+            };
+            let result = match stored {
+                Ok(stored) => BatchUploadResult {
+                    name,
+                    path: Some(stored.path),
+                    outcome: Some(stored.outcome),
+                    error: None,
+                },
+                Err(rejection) => BatchUploadResult {
+                    name,
+                    path: None,
+                    outcome: None,
+                    error: Some(rejection.message().to_string()),
+                },
+            };
+            results.push(result);
+        }
+
+        Ok(PutAssetsBatchApiResponse::Ok(Json(results)))
+    }
+
+    /// Link to download an asset without a bearer token, for embedding
+    /// private assets where a token can't be sent, such as emails. It expires
+    /// after `expiry_seconds`, 15 minutes by default and at most 7 days.
+    /// Unlike a presigned storage URL the download still goes through this
+    /// service. Needs `DOWNLOAD_LINK_SECRET` to be set, and the `read asset`
+    /// permission since the link passes that on.
+    #[oai(method = "post", path = "/:asset/sign")]
+    async fn sign_asset(
+        &self,
+        claims: BearerAuthorization,
+        asset: Path<String>,
+        expiry_seconds: Query<Option<u32>>,
+        config: Data<&AppConfig>,
+    ) -> Result<Json<SignedLinkResponse>> {
+        if !claims.has_permission("read", "asset") {
+            return Err(ApiError::missing_permission("read", "asset").into());
+        }
+        let Some(secret) = &config.download_link_secret else {
+            return Err(ApiError::download_links_disabled().into());
+        };
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let expiry_seconds = expiry_seconds
+            .unwrap_or(DEFAULT_PRESIGN_EXPIRY_SECONDS)
+            .clamp(1, MAX_PRESIGN_EXPIRY_SECONDS);
+        let expires_at = Utc::now() + Duration::from_secs(expiry_seconds.into());
+        let expires = expires_at.timestamp() as u64;
+        let token = sign_download_link(secret, &asset, expires);
+
+        Ok(Json(SignedLinkResponse {
+            url: format!("{}?token={token}&exp={expires}", asset_url(&config, &asset)),
+            expires_at: expires_at.to_rfc3339(),
+        }))
+    }
+
+    /// Time-limited URL to upload an asset straight to object storage.
+    ///
+    /// Only the file name is validated here. The bytes never pass through
+    /// this service, so the magic-byte check `put_asset` performs can't run.
+    #[oai(method = "post", path = "/presign-upload")]
+    async fn presign_upload(
+        &self,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<PresignUploadRequest>,
+    ) -> Result<PresignUploadApiResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let Some(name) = new_asset_name(&config, &request.name) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        // SVG is sanitized on the way in, which a direct upload would skip
+        if !is_valid_asset_type(&config, &name) || content_type_for(&name) == Some("image/svg+xml") {
+            return Ok(PresignUploadApiResponse::UnsupportedMediaType);
+        }
+
+        let expiry_seconds = request
+            .expiry_seconds
+            .unwrap_or(DEFAULT_PRESIGN_EXPIRY_SECONDS)
+            .clamp(1, MAX_PRESIGN_EXPIRY_SECONDS);
+
+        let presigned = storage
+            .presigned_url(
+                &config.assets_bucket,
+                &config.object_key(&name),
+                Method::PUT,
+                expiry_seconds,
+            )
+            .await?;
+
+        Ok(PresignUploadApiResponse::Ok(Json(PresignUploadResponse {
+            url: presigned.url,
+            expires_at: presigned.expires_at.to_rfc3339(),
+            path: asset_url(&config, &name),
+            key: name,
+        })))
+    }
+
+    /// List assets one page at a time. Pass the returned `next_token` back as
+    /// `continuation_token` to fetch the following page.
+    ///
+    /// `prefix` scopes the listing to keys starting with it. Without a
+    /// `delimiter` every key under the prefix is returned; with one, keys are
+    /// grouped into `common_prefixes` at the next delimiter, like folders.
+    ///
+    /// `detailed=true` also returns the size and modification time of every
+    /// asset, taken from the listing itself.
+    ///
+    /// `sort=name|size|modified` with `order=asc|desc` reorders the assets.
+    /// MinIO always lists keys lexically, so sorting applies within the
+    /// returned page, not across pages.
+    ///
+    /// `modified_since`, an RFC 3339 timestamp, keeps only assets modified at
+    /// or after it, for incremental syncs. Details are included by default
+    /// then. The filter applies to each page, so a page may hold few or no
+    /// assets and still have a `next_token`.
+    #[oai(method = "get", path = "/")]
+    #[allow(clippy::too_many_arguments)]
+    async fn list_assets(
+        &self,
+        auth: ReadAuthorization,
+        limit: Query<Option<u16>>,
+        continuation_token: Query<Option<String>>,
+        prefix: Query<Option<String>>,
+        delimiter: Query<Option<String>>,
+        detailed: Query<Option<bool>>,
+        sort: Query<Option<SortField>>,
+        order: Query<Option<SortOrder>>,
+        modified_since: Query<Option<String>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<ListAssetsApiResponse> {
+        auth.authorize(&config)?;
+
+        let modified_since = match modified_since.as_deref() {
+            Some(since) => match DateTime::parse_from_rfc3339(since) {
+                Ok(since) => Some(since.with_timezone(&Utc)),
+                Err(_) => {
+                    return Err(ApiError::bad_request(format!(
+                        "modified_since must be an RFC 3339 timestamp (got {since:?})"
+                    ))
+                    .into());
+                }
+            },
+            None => None,
+        };
+
+        let detailed = detailed.unwrap_or(modified_since.is_some());
+        let limit = limit.unwrap_or(MAX_LIST_PAGE_SIZE).clamp(1, MAX_LIST_PAGE_SIZE);
+        let delimiter = delimiter.0.filter(|delimiter| !delimiter.is_empty());
+
+        let query = ListQuery {
+            prefix: config.object_key(prefix.as_deref().unwrap_or_default()),
+            delimiter,
+            max_keys: Some(limit),
+            continuation_token: continuation_token.0,
+            start_after: None,
+        };
+        let page = storage.list(&config.assets_bucket, &query).await?;
+
+        let mut entries = Vec::new();
+        for mut object in page.objects {
+            object.key = config.asset_name(&object.key).to_string();
+            if !is_trashed(&object.key) {
+                entries.push(object);
+            }
+        }
+        let common_prefixes = page
+            .common_prefixes
+            .iter()
+            .map(|prefix| config.asset_name(prefix).to_string())
+            .filter(|prefix| !is_trashed(prefix))
+            .collect();
+        let next_token = page.next_token;
+
+        if let Some(since) = modified_since {
+            entries.retain(|entry| entry.last_modified.is_some_and(|modified| modified >= since));
+        }
+
+        if let Some(sort) = sort.0 {
+            sort_entries(&mut entries, sort, order.unwrap_or(SortOrder::Asc));
+        }
+
+        let asset_names: Vec<String> = entries.iter().map(|entry| entry.key.clone()).collect();
+        let details = detailed.then(|| entries.into_iter().map(AssetInfo::from).collect());
+        let count = asset_names.len();
+
+        Ok(ListAssetsApiResponse::Ok(Json(ListAssetsResponse {
+            assets: asset_names,
+            common_prefixes,
+            details,
+            count,
+            next_token,
+        })))
+    }
+
+    /// Folder view of the assets under `path`, with the assets directly in
+    /// it as `files` and the folders directly below as `folders`, for file
+    /// browsers. Folders are the `/` separated parts of asset keys. Pages
+    /// work like `list_assets`, and folders count towards `limit`.
+    #[oai(method = "get", path = "/browse")]
+    async fn browse_assets(
+        &self,
+        auth: ReadAuthorization,
+        path: Query<Option<String>>,
+        limit: Query<Option<u16>>,
+        continuation_token: Query<Option<String>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<BrowseApiResponse> {
+        auth.authorize(&config)?;
+
+        let Some(path) = normalize_folder(path.as_deref().unwrap_or_default()) else {
+            return Err(ApiError::bad_request("path must not contain .. or control characters").into());
+        };
+        let limit = limit.unwrap_or(MAX_LIST_PAGE_SIZE).clamp(1, MAX_LIST_PAGE_SIZE);
+
+        // Only one page, like `list_assets`
+        let query = ListQuery {
+            prefix: config.object_key(&path),
+            delimiter: Some("/".to_string()),
+            max_keys: Some(limit),
+            continuation_token: continuation_token.0,
+            start_after: None,
+        };
+        let page = storage.list(&config.assets_bucket, &query).await?;
+
+        let mut files = Vec::new();
+        for mut object in page.objects {
+            object.key = config.asset_name(&object.key).to_string();
+            if !is_trashed(&object.key) {
+                files.push(AssetInfo::from(object));
+            }
+        }
+        let folders = page
+            .common_prefixes
+            .iter()
+            .map(|prefix| config.asset_name(prefix).to_string())
+            .filter(|prefix| !is_trashed(prefix))
+            .collect();
+
+        Ok(BrowseApiResponse::Ok(Json(BrowseResponse {
+            path,
+            folders,
+            files,
+            next_token: page.next_token,
+        })))
+    }
+
+    /// Assets whose name contains `q`, ignoring case, optionally limited to
+    /// one `type` of media.
+    ///
+    /// Pages work like `list_assets`, pass `next_token` back as
+    /// `continuation_token`. A page can come back with fewer than `limit`
+    /// matches and still have a `next_token` when many keys were scanned.
+    #[oai(method = "get", path = "/search")]
+    #[allow(clippy::too_many_arguments)]
+    async fn search_assets(
+        &self,
+        auth: ReadAuthorization,
+        q: Query<String>,
+        #[oai(name = "type")] kind: Query<Option<AssetKind>>,
+        limit: Query<Option<u16>>,
+        continuation_token: Query<Option<String>>,
+        detailed: Query<Option<bool>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<ListAssetsApiResponse> {
+        auth.authorize(&config)?;
+
+        let needle = q.to_lowercase();
+        let limit = usize::from(limit.unwrap_or(MAX_LIST_PAGE_SIZE).clamp(1, MAX_LIST_PAGE_SIZE));
+        let detailed = detailed.unwrap_or(false);
+
+        // Matches are collected across listing pages, so the token is the
+        // last key looked at rather than a MinIO continuation token.
+        let mut query = ListQuery {
+            start_after: continuation_token.as_deref().map(|token| config.object_key(token)),
+            ..ListQuery::prefix(config.object_key(""))
+        };
+
+        let mut asset_names = Vec::new();
+        let mut details = detailed.then(Vec::new);
+        let mut scanned = 0;
+        let mut last_scanned = None;
+        let mut exhausted = true;
+
+        'pages: loop {
+            let page = storage.list(&config.assets_bucket, &query).await?;
+            for mut object in page.objects {
+                if asset_names.len() == limit || scanned == MAX_SEARCH_SCAN {
+                    exhausted = false;
+                    break 'pages;
+                }
+                object.key = config.asset_name(&object.key).to_string();
+                scanned += 1;
+                last_scanned = Some(object.key.clone());
+
+                let is_match = object.key.to_lowercase().contains(&needle)
+                    && kind.is_none_or(|kind| kind.matches(&object.key))
+                    && !is_trashed(&object.key);
+                if is_match {
+                    asset_names.push(object.key.clone());
+                    if let Some(details) = details.as_mut() {
+                        details.push(AssetInfo::from(object));
+                    }
+                }
+            }
+
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
+            }
+        }
+        let count = asset_names.len();
+
+        Ok(ListAssetsApiResponse::Ok(Json(ListAssetsResponse {
+            assets: asset_names,
+            common_prefixes: Vec::new(),
+            details,
+            count,
+            next_token: if exhausted { None } else { last_scanned },
+        })))
+    }
+
+    /// Number of assets, optionally only those under `prefix` or of one
+    /// `type` of media. Counted from a full listing that isn't kept, so it is
+    /// exact but as slow as listing everything.
+    #[oai(method = "get", path = "/count")]
+    async fn count_assets(
+        &self,
+        auth: ReadAuthorization,
+        prefix: Query<Option<String>>,
+        #[oai(name = "type")] kind: Query<Option<AssetKind>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<AssetCountApiResponse> {
+        auth.authorize(&config)?;
+
+        let mut query = ListQuery::prefix(config.object_key(prefix.as_deref().unwrap_or_default()));
+        let mut count = 0;
+        loop {
+            let page = storage.list(&config.assets_bucket, &query).await?;
+            count += page
+                .objects
+                .iter()
+                .map(|object| config.asset_name(&object.key))
+                .filter(|name| !is_trashed(name) && kind.is_none_or(|kind| kind.matches(name)))
+                .count() as u64;
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(AssetCountApiResponse::Ok(Json(AssetCountResponse { count })))
+    }
+
+    /// The `limit` most recently modified assets, newest first, optionally
+    /// only those of one `type` of media. `limit` defaults to 20 and is at
+    /// most 100.
+    ///
+    /// MinIO only lists keys lexically, so every request lists the whole
+    /// bucket to find them. Only the newest entries seen so far are kept,
+    /// but the time taken grows with the number of assets.
+    #[oai(method = "get", path = "/recent")]
+    async fn recent_assets(
+        &self,
+        auth: ReadAuthorization,
+        limit: Query<Option<usize>>,
+        #[oai(name = "type")] kind: Query<Option<AssetKind>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<RecentAssetsApiResponse> {
+        auth.authorize(&config)?;
+
+        let limit = limit.unwrap_or(DEFAULT_RECENT_LIMIT).clamp(1, MAX_RECENT_LIMIT);
+        let newest_first =
+            |a: &ObjectInfo, b: &ObjectInfo| b.last_modified.cmp(&a.last_modified);
+
+        let mut newest: Vec<ObjectInfo> = Vec::new();
+        let mut query = ListQuery::prefix(config.object_key(""));
+        loop {
+            let page = storage.list(&config.assets_bucket, &query).await?;
+            newest.extend(page.objects.into_iter().filter(|object| {
+                let name = config.asset_name(&object.key);
+                !is_trashed(name) && kind.is_none_or(|kind| kind.matches(name))
+            }));
+            // Trimmed now and then rather than per entry to keep sorting cheap
+            if newest.len() > 2 * limit {
+                newest.sort_by(newest_first);
+                newest.truncate(limit);
+            }
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
+            }
+        }
+        newest.sort_by(newest_first);
+        newest.truncate(limit);
+
+        for object in &mut newest {
+            object.key = config.asset_name(&object.key).to_string();
+        }
+        Ok(RecentAssetsApiResponse::Ok(Json(
+            newest.into_iter().map(AssetInfo::from).collect(),
+        )))
+    }
+
+    /// Number and size of all assets, in total and by kind.
+    ///
+    /// Computed by listing the whole bucket, which is slow for large ones, and
+    /// cached for a minute, so the numbers are approximate. `computed_at`
+    /// tells how fresh they are.
+    #[oai(method = "get", path = "/stats")]
+    async fn get_storage_stats(
+        &self,
+        auth: ReadAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<StorageStatsResponse> {
+        auth.authorize(&config)?;
+
+        // Held while computing so concurrent requests share one listing
+        let mut cache = STATS_CACHE.lock().await;
+        if let Some((computed, stats)) = cache.as_ref()
+            && computed.elapsed() < STATS_CACHE_TTL
+        {
+            return Ok(StorageStatsResponse::Ok(Json(stats.clone())));
+        }
+
+        let stats = compute_storage_stats(&config, storage.as_ref()).await?;
+        *cache = Some((Instant::now(), stats.clone()));
+
+        Ok(StorageStatsResponse::Ok(Json(stats)))
+    }
+
+    /// Size, modification time and hash of an asset, the dimensions of
+    /// images and play length of audio and video, plus its tags with
+    /// `tags=true`
+    #[oai(method = "get", path = "/:asset/info")]
+    async fn get_asset_info(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        tags: Query<Option<bool>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<AssetInfoResponse> {
+        auth.authorize(&config)?;
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let response = match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(response) => response,
+            Err(StorageError::NotFound) => return Ok(AssetInfoResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
+
+        let has_dimensions = metadata_number::<u32>(&response, WIDTH_METADATA_KEY).is_some();
+        let has_duration = metadata_number::<f64>(&response, DURATION_METADATA_KEY).is_some();
+        let probed = if (is_image_asset(&asset) && !has_dimensions)
+            || (is_media_asset(&asset) && !has_duration)
+        {
+            Some(probed_info(&config, &storage, &asset, &response).await?)
+        } else {
+            None
+        };
+
+        let mut asset_info = AssetInfo {
+            name: asset.clone(),
+            ..AssetInfo::from_stat(response)
+        };
+        if let Some(probed) = probed {
+            if probed.width.is_some() {
+                asset_info.width = probed.width;
+                asset_info.height = probed.height;
+            }
+            if probed.duration_seconds.is_some() {
+                asset_info.duration_seconds = probed.duration_seconds;
+            }
+        }
+        if tags.unwrap_or(false) {
+            asset_info.tags = match storage
+                .tags(&config.assets_bucket, &config.object_key(&asset))
+                .await
+            {
+                Ok(tags) => Some(tags),
+                Err(StorageError::NotFound) => return Ok(AssetInfoResponse::NotFound),
+                Err(why) => return Err(why.into()),
+            };
+        }
+
+        Ok(AssetInfoResponse::Ok(
+            Json(asset_info),
+            config.info_cache_control.clone(),
+        ))
+    }
+
+    /// Stored versions of an asset, when the bucket has versioning enabled.
+    /// Without versioning only the current object is listed.
+    #[oai(method = "get", path = "/:asset/versions")]
+    async fn get_asset_versions(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<AssetVersionsApiResponse> {
+        auth.authorize(&config)?;
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let versions: Vec<AssetVersion> = storage
+            .versions(&config.assets_bucket, &config.object_key(&asset))
+            .await?
+            .into_iter()
+            .map(|version| AssetVersion {
+                version_id: version.version_id,
+                last_modified: version
+                    .last_modified
+                    .map(|dt| dt.to_rfc3339())
+                    .unwrap_or_default(),
+                size: version.size,
+                is_latest: version.is_latest,
+                is_delete_marker: version.is_delete_marker,
+            })
+            .collect();
+
+        if versions.is_empty() {
+            return Ok(AssetVersionsApiResponse::NotFound);
+        }
+
+        Ok(AssetVersionsApiResponse::Ok(Json(AssetVersionsResponse { versions })))
+    }
+
+    /// Key/value tags attached to an asset, such as alt text or author
+    #[oai(method = "get", path = "/:asset/tags")]
+    async fn get_asset_tags(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<AssetTagsResponse> {
+        auth.authorize(&config)?;
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        match storage
+            .tags(&config.assets_bucket, &config.object_key(&asset))
+            .await
+        {
+            Ok(tags) => Ok(AssetTagsResponse::Ok(Json(tags))),
+            Err(StorageError::NotFound) => Ok(AssetTagsResponse::NotFound),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    /// Replace the tags of an asset. Stored as S3 object tags, so at most 10
+    /// with keys up to 128 and values up to 256 characters.
+    #[oai(method = "put", path = "/:asset/tags")]
+    async fn put_asset_tags(
+        &self,
+        claims: BearerAuthorization,
+        asset: Path<String>,
+        tags: Json<HashMap<String, String>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<AssetTagsResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        let tags = tags.0;
+        let within_limits = tags.len() <= MAX_TAGS
+            && tags.iter().all(|(key, value)| {
+                !key.is_empty()
+                    && key.chars().count() <= MAX_TAG_KEY_LENGTH
+                    && value.chars().count() <= MAX_TAG_VALUE_LENGTH
+            });
+        if !within_limits {
+            return Err(ApiError::bad_request(format!(
+                "at most {MAX_TAGS} tags with keys up to {MAX_TAG_KEY_LENGTH} and values up to \
+                 {MAX_TAG_VALUE_LENGTH} characters are allowed"
+            ))
+            .into());
+        }
+
+        match storage
+            .set_tags(&config.assets_bucket, &config.object_key(&asset), &tags)
+            .await
+        {
+            Ok(()) => Ok(AssetTagsResponse::Ok(Json(tags))),
+            Err(StorageError::NotFound) => Ok(AssetTagsResponse::NotFound),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    /// Downscaled copy of an image asset that fits within `width` x `height`.
+    /// Either dimension can be left out to constrain only the other one.
+    ///
+    /// Rendered thumbnails are cached in object storage under the source's
+    /// etag, so an overwritten source gets a fresh thumbnail.
+    #[oai(method = "get", path = "/:asset/thumbnail")]
+    async fn get_thumbnail(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        width: Query<Option<u32>>,
+        height: Query<Option<u32>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<ThumbnailResponse> {
+        auth.authorize(&config)?;
+
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
 
-    // Audio file extensions
-    let audio_extensions = [
-        ".mp3", ".wav", ".flac", ".aac", ".ogg", ".m4a", ".wma", ".opus",
-    ];
+        if width.is_none() && height.is_none() {
+            return Err(ApiError::bad_request("width or height is required").into());
+        }
+        let width = width.unwrap_or(MAX_THUMBNAIL_DIMENSION).clamp(1, MAX_THUMBNAIL_DIMENSION);
+        let height = height.unwrap_or(MAX_THUMBNAIL_DIMENSION).clamp(1, MAX_THUMBNAIL_DIMENSION);
 
-    // Video file extensions
-    let video_extensions = [
-        ".mp4", ".avi", ".mov", ".wmv", ".flv", ".webm", ".mkv", ".m4v", ".3gp", ".ogv",
-    ];
+        // SVG is vector art and is served as is rather than rasterized
+        match content_type_for(&asset) {
+            Some(content_type)
+                if media_category(content_type) == "image" && content_type != "image/svg+xml" => {}
+            _ => return Ok(ThumbnailResponse::UnsupportedMediaType),
+        }
 
-    image_extensions
-        .iter()
-        .any(|ext| filename_lower.ends_with(ext))
-        || audio_extensions
-            .iter()
-            .any(|ext| filename_lower.ends_with(ext))
-        || video_extensions
-            .iter()
-            .any(|ext| filename_lower.ends_with(ext))
-}
+        let etag = match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(response) => response.etag,
+            Err(StorageError::NotFound) => return Ok(ThumbnailResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
 
-#[derive(Serialize, Deserialize, poem_openapi::Object)]
-pub struct AssetInfo {
-    pub name: String,
-    pub size: u64,
-    pub last_modified: String,
-}
+        let thumbnail_key = format!("{}/{}-{}x{}", config.object_key(&asset), etag, width, height);
 
-#[derive(Serialize, Deserialize, poem_openapi::Object)]
-pub struct ListAssetsResponse {
-    pub assets: Vec<String>,
-    pub total_count: usize,
-}
+        if let Ok((cached, contents)) = storage
+            .get(&config.thumbnails_bucket, &thumbnail_key, None)
+            .await
+        {
+            let content_type = cached
+                .content_type
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            let bytes = collect_bytes(contents).await?;
 
-#[derive(Serialize, Deserialize, poem_openapi::Object)]
-pub struct BatchAssetInfoRequest {
-    pub asset_names: Vec<String>,
-}
+            return Ok(ThumbnailResponse::Ok(Binary(bytes.to_vec()), content_type));
+        }
 
-#[derive(Serialize, Deserialize, poem_openapi::Object)]
-pub struct BatchAssetInfoResponse {
-    pub assets: Vec<AssetInfo>,
-}
+        let source = match storage
+            .get(&config.assets_bucket, &config.object_key(&asset), None)
+            .await
+        {
+            Ok((_, contents)) => collect_bytes(contents).await?,
+            Err(StorageError::NotFound) => return Ok(ThumbnailResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
 
-#[derive(ApiResponse)]
-enum GetImageResponse {
-    #[oai(status = 200)]
-    Ok(Attachment<Vec<u8>>),
-    #[oai(status = 404)]
-    NotFound,
-}
+        let rendered =
+            tokio::task::spawn_blocking(move || render_thumbnail(&source, width, height))
+                .await
+                .map_err(InternalServerError)?;
 
-#[derive(ApiResponse)]
-enum ListAssetsApiResponse {
-    #[oai(status = 200)]
-    Ok(Json<ListAssetsResponse>),
-}
+        let (thumbnail, format) = match rendered {
+            Ok(rendered) => rendered,
+            Err(why) => {
+                error!("Error rendering thumbnail for {}: {}", asset, why);
+                return Ok(ThumbnailResponse::UnsupportedMediaType);
+            }
+        };
+        let content_type = format.to_mime_type().to_string();
 
-#[derive(ApiResponse)]
-enum AssetInfoResponse {
-    #[oai(status = 200)]
-    Ok(Json<AssetInfo>),
-    #[oai(status = 404)]
-    NotFound,
-}
+        // A failed cache write only costs a re-render next time
+        if let Err(why) = storage
+            .put(
+                &config.thumbnails_bucket,
+                &thumbnail_key,
+                Bytes::from(thumbnail.clone()),
+                Some(&content_type),
+                &HashMap::new(),
+                &HashMap::new(),
+            )
+            .await
+        {
+            error!("Error caching thumbnail {}: {}", thumbnail_key, why);
+        }
 
-#[derive(ApiResponse)]
-enum BatchAssetInfoApiResponse {
-    #[oai(status = 200)]
-    Ok(Json<BatchAssetInfoResponse>),
-}
+        Ok(ThumbnailResponse::Ok(Binary(thumbnail), content_type))
+    }
 
-#[derive(ApiResponse)]
-enum PutAssetResponse {
-    #[oai(status = 200)]
-    Ok(PlainText<String>),
-    #[oai(status = 415)]
-    UnsupportedMediaType,
-}
+    /// Time-limited URL to download the asset straight from object storage
+    #[oai(method = "get", path = "/:asset/presign")]
+    async fn presign_asset(
+        &self,
+        auth: ReadAuthorization,
+        asset: Path<String>,
+        expiry_seconds: Query<Option<u32>>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<PresignedUrlApiResponse> {
+        auth.authorize(&config)?;
 
-#[derive(ApiResponse)]
-enum DeleteAssetResponse {
-    #[oai(status = 204)]
-    NoContent,
-    #[oai(status = 404)]
-    NotFound,
-}
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
 
-#[derive(Multipart, Debug)]
-pub struct PutImageRequest {
-    pub asset: Upload,
-}
+        let expiry_seconds = expiry_seconds
+            .unwrap_or(DEFAULT_PRESIGN_EXPIRY_SECONDS)
+            .clamp(1, MAX_PRESIGN_EXPIRY_SECONDS);
 
-#[OpenApi(prefix_path = "/assets", tag = "ApiTags::Assets")]
-impl AssetsApi {
-    #[oai(method = "get", path = "/:asset")]
-    async fn get_asset(
+        // Presigning is purely local, so check the object exists first
+        match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(_) => {}
+            Err(StorageError::NotFound) => return Ok(PresignedUrlApiResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        }
+
+        let presigned = storage
+            .presigned_url(
+                &config.assets_bucket,
+                &config.object_key(&asset),
+                Method::GET,
+                expiry_seconds,
+            )
+            .await?;
+
+        Ok(PresignedUrlApiResponse::Ok(Json(PresignedUrlResponse {
+            url: presigned.url,
+            expires_at: presigned.expires_at.to_rfc3339(),
+        })))
+    }
+
+    /// Info of several assets at once, looked up concurrently. Missing
+    /// assets are left out, invalid names and failed lookups are listed in
+    /// `failures` with the status a single request would have had.
+    #[oai(method = "post", path = "/batch/info")]
+    async fn get_batch_asset_info(
         &self,
-        asset: Path<String>,
-        object_storage: Data<&ObjectStorage>,
-    ) -> Result<GetImageResponse> {
-        let get_object_request = object_storage.get_object(ASSETS_FILE_BUCKET, &*asset);
+        auth: ReadAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<BatchAssetInfoRequest>,
+    ) -> Result<BatchAssetInfoApiResponse> {
+        auth.authorize(&config)?;
 
-        let response = match get_object_request.send().await {
-            Ok(response) => response,
-            Err(why) => match why {
-                minio::s3::error::Error::HttpError(error) => {
-                    if let Some(status) = error.status() {
-                        if status.as_u16() == 404 {
-                            return Ok(GetImageResponse::NotFound);
+        // Missing assets are left out, the rest keep the order they were
+        // asked for in
+        let config: &AppConfig = &config;
+        let lookups = request.0.asset_names.into_iter().map(|asset_name| {
+            let storage = storage.clone();
+            async move {
+                let Some(asset) = sanitize_asset_key(&asset_name) else {
+                    return Some(Err(BatchInfoFailure {
+                        name: asset_name,
+                        status: StatusCode::BAD_REQUEST.as_u16(),
+                        message: "invalid asset name".to_string(),
+                    }));
+                };
+                match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+                    Ok(stat) => Some(Ok(AssetInfo {
+                        name: asset,
+                        ..AssetInfo::from_stat(stat)
+                    })),
+                    Err(StorageError::NotFound) => None,
+                    Err(why) => {
+                        error!(asset = %asset, "Error looking up asset info: {}", why);
+                        let status = if matches!(why, StorageError::Timeout) {
+                            StatusCode::GATEWAY_TIMEOUT
                         } else {
-                            println!("Error fetching asset: {}", error);
-                            return Err(InternalServerError(error));
-                        }
-                    } else {
-                        println!("Error fetching asset: {}", error);
-                        return Err(InternalServerError(error));
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        };
+                        Some(Err(BatchInfoFailure {
+                            name: asset_name,
+                            status: status.as_u16(),
+                            message: why.to_string(),
+                        }))
                     }
                 }
-                why => {
-                    println!("Error fetching asset: {}", why);
-                    return Err(InternalServerError(why));
-                }
-            },
-        };
+            }
+        });
+        let mut assets = Vec::new();
+        let mut failures = Vec::new();
+        let mut results = futures_util::stream::iter(lookups)
+            .buffered(config.batch_stat_concurrency)
+            .filter_map(std::future::ready);
+        while let Some(result) = results.next().await {
+            match result {
+                Ok(info) => assets.push(info),
+                Err(failure) => failures.push(failure),
+            }
+        }
+
+        Ok(BatchAssetInfoApiResponse::Ok(Json(BatchAssetInfoResponse {
+            assets,
+            failures,
+        })))
+    }
 
-        let segmented_bytes = response
-            .content
-            .to_segmented_bytes()
+    /// Which of the given assets exist, without their metadata. The lookups
+    /// run concurrently.
+    #[oai(method = "post", path = "/batch/exists")]
+    async fn batch_assets_exist(
+        &self,
+        auth: ReadAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<BatchAssetInfoRequest>,
+    ) -> Result<BatchExistsApiResponse> {
+        auth.authorize(&config)?;
+
+        let lookups = request
+            .0
+            .asset_names
+            .into_iter()
+            .map(|asset_name| asset_exists(&config, storage.clone(), asset_name));
+        let results = futures_util::stream::iter(lookups)
+            .buffer_unordered(config.batch_stat_concurrency)
+            .collect::<Vec<Result<_>>>()
             .await
-            .map_err(InternalServerError)?;
+            .into_iter()
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        Ok(BatchExistsApiResponse::Ok(Json(BatchExistsResponse { results })))
+    }
+
+    /// Download several assets as one zip archive. Assets that don't exist
+    /// are left out. The archive is streamed while it is built, so it is
+    /// never held in memory as a whole.
+    #[oai(method = "post", path = "/batch/download")]
+    async fn download_assets_batch(
+        &self,
+        auth: ReadAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<BatchAssetInfoRequest>,
+    ) -> Result<BatchDownloadResponse> {
+        auth.authorize(&config)?;
 
-        let bytes = segmented_bytes.to_bytes();
-        let bytes = bytes.to_vec();
+        let mut seen = HashSet::new();
+        let names: Vec<String> = request
+            .0
+            .asset_names
+            .iter()
+            .filter_map(|name| sanitize_asset_key(name))
+            .filter(|name| seen.insert(name.clone()))
+            .collect();
+
+        let assets = names
+            .into_iter()
+            .map(|name| {
+                let key = config.object_key(&name);
+                (name, key)
+            })
+            .collect();
 
-        let attachment = Attachment::new(bytes).filename(&*asset);
+        let (writer, reader) = tokio::io::duplex(ZIP_PIPE_CAPACITY);
+        let storage = (*storage).clone();
+        let bucket = config.assets_bucket.clone();
+        tokio::spawn(async move {
+            if let Err(why) = write_zip_archive(storage.as_ref(), &bucket, assets, writer).await {
+                error!("Error building zip archive: {}", why);
+            }
+        });
 
-        return Ok(GetImageResponse::Ok(attachment));
+        let attachment = Attachment::new(Body::from_async_read(reader)).filename("assets.zip");
+        Ok(BatchDownloadResponse::Ok(attachment, "application/zip".to_string()))
     }
-    #[oai(method = "put", path = "/")]
-    async fn put_asset(
+
+    /// Delete many assets at once. Missing or failing entries are reported
+    /// per asset instead of failing the whole batch.
+    /// `SOFT_DELETE` and `permanent` work as for single deletes.
+    #[oai(method = "post", path = "/batch/delete")]
+    async fn batch_delete_assets(
         &self,
         claims: BearerAuthorization,
-        object_storage: Data<&ObjectStorage>,
-        request: PutImageRequest,
-    ) -> Result<PutAssetResponse> {
-        if !claims.has_permission("create", "asset") {
-            return Err(Error::from_status(StatusCode::FORBIDDEN));
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<BatchDeleteRequest>,
+        permanent: Query<Option<bool>>,
+    ) -> Result<BatchDeleteApiResponse> {
+        if !claims.has_permission("delete", "asset") {
+            return Err(ApiError::missing_permission("delete", "asset").into());
         }
 
-        let asset = request.asset;
+        let soft_delete = config.soft_delete && !permanent.unwrap_or(false);
+        let mut results = Vec::with_capacity(request.asset_names.len());
+        let mut existing = Vec::new();
 
-        let Some(name) = asset.file_name() else {
-            return Err(Error::from_status(StatusCode::BAD_REQUEST));
-        };
-        let name = name.to_string();
+        // Multi-object delete reports missing keys as deleted, so find out
+        // which ones exist first.
+        for asset_name in &request.asset_names {
+            let (status, message) = match sanitize_asset_key(asset_name) {
+                None => (BatchDeleteStatus::Error, Some("invalid asset name".to_string())),
+                Some(key) => match storage.stat(&config.assets_bucket, &config.object_key(&key)).await {
+                    Ok(stat) if soft_delete && stat.size > MAX_SINGLE_COPY_BYTES => (
+                        BatchDeleteStatus::Error,
+                        Some("too large for the trash, delete with permanent=true".to_string()),
+                    ),
+                    Ok(_) => {
+                        existing.push((results.len(), key));
+                        (BatchDeleteStatus::Deleted, None)
+                    }
+                    Err(StorageError::NotFound) => (BatchDeleteStatus::NotFound, None),
+                    Err(why) => (BatchDeleteStatus::Error, Some(why.to_string())),
+                },
+            };
 
-        // Validate file type - only allow images, audio, and video files
-        if !is_valid_asset_type(&name) {
-            return Ok(PutAssetResponse::UnsupportedMediaType);
+            results.push(BatchDeleteResult {
+                name: asset_name.clone(),
+                status,
+                message,
+            });
         }
 
-        let contents = asset.into_vec().await.unwrap();
-
-        let put_object_request = object_storage.put_object(
-            ASSETS_FILE_BUCKET,
-            &*name,
-            SegmentedBytes::from(Bytes::from(contents)),
-        );
+        let keys = existing.iter().map(|(_, key)| key.clone()).collect();
+        let failures = delete_keys(&config, storage.as_ref(), keys, soft_delete).await;
+        for (index, key) in &existing {
+            if let Some(why) = failures.get(key) {
+                results[*index].status = BatchDeleteStatus::Error;
+                results[*index].message = Some(why.clone());
+            }
+        }
 
-        put_object_request.send().await.unwrap();
+        Ok(BatchDeleteApiResponse::Ok(Json(BatchDeleteResponse {
+            results,
+        })))
+    }
 
-        Ok(PutAssetResponse::Ok(PlainText(format!("/assets/{}", name))))
+    /// `delete_prefix` without a prefix, refused like a prefix of only `/`
+    /// rather than left to `404`
+    #[oai(method = "delete", path = "/prefix")]
+    async fn delete_empty_prefix(
+        &self,
+        claims: BearerAuthorization,
+    ) -> Result<PrefixDeleteApiResponse> {
+        if !claims.has_permission("delete", "asset") {
+            return Err(ApiError::missing_permission("delete", "asset").into());
+        }
+        Err(ApiError::bad_request("refusing to delete with an empty prefix").into())
     }
 
-    #[oai(method = "get", path = "/")]
-    async fn list_assets(
+    /// Delete every asset whose name starts with `prefix`, e.g.
+    /// `posts%2F123%2F` for everything under `posts/123/`. An empty prefix,
+    /// which would empty the bucket, is refused. `SOFT_DELETE` and
+    /// `permanent` work as for single deletes.
+    #[oai(method = "delete", path = "/prefix/:prefix")]
+    async fn delete_prefix(
         &self,
-        object_storage: Data<&ObjectStorage>,
-    ) -> Result<ListAssetsApiResponse> {
-        let mut stream = (**object_storage)
-            .list_objects(ASSETS_FILE_BUCKET)
-            .recursive(false)
-            .disable_url_encoding(true)
-            .use_api_v1(false) // use v2
-            .to_stream()
-            .await;
+        prefix: Path<String>,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        permanent: Query<Option<bool>>,
+    ) -> Result<PrefixDeleteApiResponse> {
+        if !claims.has_permission("delete", "asset") {
+            return Err(ApiError::missing_permission("delete", "asset").into());
+        }
 
-        let mut asset_names = Vec::new();
+        let prefix = prefix.trim_start_matches('/');
+        if prefix.is_empty() {
+            return Err(ApiError::bad_request("refusing to delete with an empty prefix").into());
+        }
+        let prefix: String = prefix.nfc().collect();
 
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(response) => {
-                    for object in response.contents {
-                    
-                        asset_names.push(object.name);
-                    }
+        let soft_delete = config.soft_delete && !permanent.unwrap_or(false);
+        let mut keys = Vec::new();
+        let mut failures = Vec::new();
+
+        let mut query = ListQuery::prefix(config.object_key(&prefix));
+        loop {
+            let page = storage.list(&config.assets_bucket, &query).await.inspect_err(|why| {
+                error!(%prefix, "Error listing assets to delete: {}", why);
+            })?;
+            for object in page.objects {
+                let name = config.asset_name(&object.key).to_string();
+                if is_trashed(&name) {
+                    continue;
+                }
+                if soft_delete && object.size > MAX_SINGLE_COPY_BYTES {
+                    failures.push(PrefixDeleteFailure {
+                        name,
+                        message: "too large for the trash, delete with permanent=true".to_string(),
+                    });
+                } else {
+                    keys.push(name);
                 }
-                Err(e) => return Err(InternalServerError(e)),
+            }
+
+            match page.next_token {
+                Some(token) => query.continuation_token = Some(token),
+                None => break,
             }
         }
-        let total_count = asset_names.len();
 
-        Ok(ListAssetsApiResponse::Ok(Json(ListAssetsResponse {
-            assets: asset_names,
-            total_count,
+        let found = keys.len();
+        let mut errors: Vec<_> = delete_keys(&config, storage.as_ref(), keys, soft_delete)
+            .await
+            .into_iter()
+            .map(|(name, message)| PrefixDeleteFailure { name, message })
+            .collect();
+        let deleted = found.saturating_sub(errors.len());
+        errors.sort_by(|a, b| a.name.cmp(&b.name));
+        failures.append(&mut errors);
+
+        info!(%prefix, deleted, failed = failures.len(), "deleted assets by prefix");
+        Ok(PrefixDeleteApiResponse::Ok(Json(PrefixDeleteResponse {
+            deleted,
+            failures,
         })))
     }
 
-    #[oai(method = "get", path = "/:asset/info")]
-    async fn get_asset_info(
+    /// Move an asset deleted with `SOFT_DELETE` back out of the trash
+    #[oai(method = "post", path = "/:asset/restore")]
+    async fn restore_asset(
         &self,
         asset: Path<String>,
-        object_storage: Data<&ObjectStorage>,
-    ) -> Result<AssetInfoResponse> {
-        let stat_request = object_storage.stat_object(ASSETS_FILE_BUCKET, &*asset);
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+    ) -> Result<RestoreAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
 
-        let response = match stat_request.send().await {
-            Ok(response) => response,
-            Err(why) => match why {
-                minio::s3::error::Error::HttpError(error) => {
-                    if let Some(status) = error.status() {
-                        if status.as_u16() == 404 {
-                            return Ok(AssetInfoResponse::NotFound);
-                        } else {
-                            return Err(InternalServerError(error));
-                        }
-                    } else {
-                        return Err(InternalServerError(error));
-                    }
-                }
-                _ => return Err(InternalServerError(why)),
-            },
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
         };
 
-        let asset_info = AssetInfo {
-            name: response.object,
-            size: response.size as u64,
-            last_modified: response
-                .last_modified
-                .map(|dt| dt.to_rfc3339())
-                .unwrap_or_default(),
+        let trashed = trash_key(&asset);
+        match copy_object_key(&config, storage.as_ref(), &trashed, &asset).await? {
+            CopyOutcome::Copied => {}
+            CopyOutcome::SourceMissing => return Ok(RestoreAssetResponse::NotFound),
+            CopyOutcome::DestinationExists => return Ok(RestoreAssetResponse::Conflict),
+        }
+
+        // The asset is back either way, a leftover copy only takes up space
+        if let Err(why) = storage
+            .remove(&config.assets_bucket, &config.object_key(&trashed))
+            .await
+        {
+            warn!(%asset, "Error removing restored asset from the trash: {}", why);
+        }
+
+        info!(%asset, "restored asset from the trash");
+        asset_cache::invalidate(&asset);
+        webhooks::asset_created(&config, &asset, None);
+        Ok(RestoreAssetResponse::Ok(PlainText(asset_url(&config, &asset))))
+    }
+
+    /// Duplicate an asset under a new name without re-uploading it
+    #[oai(method = "post", path = "/:asset/copy")]
+    async fn copy_asset(
+        &self,
+        asset: Path<String>,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<CopyAssetRequest>,
+    ) -> Result<CopyAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+
+        let (Some(source), Some(destination)) = (
+            sanitize_asset_key(&asset),
+            new_asset_key(&config, &request.destination),
+        ) else {
+            return Err(ApiError::invalid_asset_name().into());
         };
 
-        Ok(AssetInfoResponse::Ok(Json(asset_info)))
+        if !is_valid_asset_type(&config, &destination) {
+            return Ok(CopyAssetResponse::UnsupportedMediaType);
+        }
+
+        match copy_object_key(&config, storage.as_ref(), &source, &destination).await? {
+            CopyOutcome::Copied => {
+                asset_cache::invalidate(&destination);
+                webhooks::asset_created(&config, &destination, None);
+                Ok(CopyAssetResponse::Ok(PlainText(asset_url(&config, &destination))))
+            }
+            CopyOutcome::SourceMissing => Ok(CopyAssetResponse::NotFound),
+            CopyOutcome::DestinationExists => Ok(CopyAssetResponse::Conflict),
+        }
     }
 
-    #[oai(method = "post", path = "/batch/info")]
-    async fn get_batch_asset_info(
+    /// Move an asset to a new name. The source is only removed once the copy
+    /// exists, and the copy is rolled back if that removal fails.
+    #[oai(method = "post", path = "/:asset/rename")]
+    async fn rename_asset(
         &self,
-        object_storage: Data<&ObjectStorage>,
-        request: Json<BatchAssetInfoRequest>,
-    ) -> Result<BatchAssetInfoApiResponse> {
-        let mut assets = Vec::new();
+        asset: Path<String>,
+        claims: BearerAuthorization,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        request: Json<CopyAssetRequest>,
+    ) -> Result<CopyAssetResponse> {
+        if !claims.has_permission("create", "asset") {
+            return Err(ApiError::missing_permission("create", "asset").into());
+        }
+        if !claims.has_permission("delete", "asset") {
+            return Err(ApiError::missing_permission("delete", "asset").into());
+        }
 
-        for asset_name in &request.asset_names {
-            let stat_request = object_storage.stat_object(ASSETS_FILE_BUCKET, asset_name);
-
-            match stat_request.send().await {
-                Ok(response) => {
-                    assets.push(AssetInfo {
-                        name: response.object,
-                        size: response.size as u64,
-                        last_modified: response
-                            .last_modified
-                            .map(|dt| dt.to_rfc3339())
-                            .unwrap_or_default(),
-                    });
-                }
-                Err(_) => {
-                    // Skip assets that don't exist or can't be accessed
-                    continue;
-                }
+        let (Some(source), Some(destination)) = (
+            sanitize_asset_key(&asset),
+            new_asset_key(&config, &request.destination),
+        ) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
+
+        if !is_valid_asset_type(&config, &destination) {
+            return Ok(CopyAssetResponse::UnsupportedMediaType);
+        }
+
+        match copy_object_key(&config, storage.as_ref(), &source, &destination).await? {
+            CopyOutcome::Copied => {}
+            CopyOutcome::SourceMissing => return Ok(CopyAssetResponse::NotFound),
+            CopyOutcome::DestinationExists => return Ok(CopyAssetResponse::Conflict),
+        }
+
+        if let Err(why) = storage
+            .remove(&config.assets_bucket, &config.object_key(&source))
+            .await
+        {
+            error!("Error removing renamed asset: {}", why);
+            if let Err(cleanup) = storage
+                .remove(&config.assets_bucket, &config.object_key(&destination))
+                .await
+            {
+                error!("Error rolling back renamed asset copy: {}", cleanup);
             }
+            return Err(why.into());
         }
 
-        Ok(BatchAssetInfoApiResponse::Ok(Json(
-            BatchAssetInfoResponse { assets },
-        )))
+        asset_cache::invalidate(&destination);
+        asset_cache::invalidate(&source);
+        webhooks::asset_created(&config, &destination, None);
+        webhooks::asset_deleted(&config, &source);
+
+        Ok(CopyAssetResponse::Ok(PlainText(asset_url(&config, &destination))))
     }
 
+    /// Delete an asset. With `SOFT_DELETE` it is moved to the trash, to be
+    /// brought back with `restore`, unless `permanent=true` is passed. With
+    /// `If-Match` it is only deleted while its etag matches, `412` otherwise.
     #[oai(method = "delete", path = "/:asset")]
+    #[allow(clippy::too_many_arguments)]
     async fn delete_asset(
         &self,
         asset: Path<String>,
         claims: BearerAuthorization,
-        object_storage: Data<&ObjectStorage>,
+        storage: Data<&SharedStorage>,
+        config: Data<&AppConfig>,
+        permanent: Query<Option<bool>>,
+        #[oai(name = "If-Match")] if_match: Header<Option<String>>,
     ) -> Result<DeleteAssetResponse> {
         if !claims.has_permission("delete", "asset") {
-            return Err(Error::from_status(StatusCode::FORBIDDEN));
+            return Err(ApiError::missing_permission("delete", "asset").into());
         }
 
-        let delete_object_request =  object_storage.delete_object(ASSETS_FILE_BUCKET, &*asset);
+        let Some(asset) = sanitize_asset_key(&asset) else {
+            return Err(ApiError::invalid_asset_name().into());
+        };
 
-        match delete_object_request.send().await {
-            Ok(_) => Ok(DeleteAssetResponse::NoContent),
-            Err(why) => match why {
-                minio::s3::error::Error::HttpError(error) => {
-                    if let Some(status) = error.status() {
-                        if status.as_u16() == 404 {
-                            return Ok(DeleteAssetResponse::NotFound);
-                        } else {
-                            println!("Error deleting asset: {}", error);
-                            return Err(InternalServerError(error));
-                        }
-                    } else {
-                        println!("Error deleting asset: {}", error);
-                        return Err(InternalServerError(error));
-                    }
-                }
-                why => {
-                    println!("Error deleting asset: {}", why);
-                    return Err(InternalServerError(why));
-                }
-            },
+        // S3 treats deleting a missing key as a success, so probe first to
+        // be able to report a proper 404.
+        let stat = match storage.stat(&config.assets_bucket, &config.object_key(&asset)).await {
+            Ok(stat) => stat,
+            Err(StorageError::NotFound) => return Ok(DeleteAssetResponse::NotFound),
+            Err(why) => return Err(why.into()),
+        };
+        if let Some(if_match) = if_match.as_deref()
+            && !if_match_satisfied(if_match, Some(&stat.etag))
+        {
+            return Ok(DeleteAssetResponse::PreconditionFailed);
+        }
+
+        if config.soft_delete && !permanent.unwrap_or(false) {
+            if stat.size > MAX_SINGLE_COPY_BYTES {
+                return Err(ApiError::too_large_for_trash().into());
+            }
+            copy_to_trash(&config, storage.as_ref(), &asset)
+                .await
+                .map_err(InternalServerError)?;
         }
+
+        storage.remove(&config.assets_bucket, &config.object_key(&asset)).await?;
+        asset_cache::invalidate(&asset);
+        webhooks::asset_deleted(&config, &asset);
+        Ok(DeleteAssetResponse::NoContent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(vars: &[(&str, &str)]) -> AppConfig {
+        let mut all = vec![
+            ("STORAGE_BACKEND", "fs"),
+            ("JWT_PUBLIC_KEY", include_str!("../../testdata/jwt.pub.pem")),
+        ];
+        all.extend_from_slice(vars);
+        AppConfig::from_vars(&all).expect("test configuration is valid")
+    }
+
+    #[test]
+    fn asset_url_escapes_the_name_as_one_segment() {
+        let config = test_config(&[]);
+        assert_eq!(asset_url(&config, "img.png"), "/assets/img.png");
+        assert_eq!(asset_url(&config, "posts/1/img.png"), "/assets/posts%2F1%2Fimg.png");
+        assert_eq!(asset_url(&config, "my pic#1?.png"), "/assets/my%20pic%231%3F.png");
+        assert_eq!(asset_url(&config, "caf\u{e9}.png"), "/assets/caf%C3%A9.png");
+
+        let config = test_config(&[("PUBLIC_BASE_URL", "https://cdn.example.com")]);
+        assert_eq!(asset_url(&config, "a b.png"), "https://cdn.example.com/assets/a%20b.png");
+    }
+
+    #[test]
+    fn sanitize_asset_key_keeps_folders() {
+        assert_eq!(sanitize_asset_key("posts/123/img.png").as_deref(), Some("posts/123/img.png"));
+        assert_eq!(sanitize_asset_key("img.png").as_deref(), Some("img.png"));
+    }
+
+    #[test]
+    fn sanitize_asset_key_normalizes_segments() {
+        assert_eq!(sanitize_asset_key("/posts//./img.png").as_deref(), Some("posts/img.png"));
+        assert_eq!(sanitize_asset_key("posts\\img.png").as_deref(), Some("posts/img.png"));
+        assert_eq!(sanitize_asset_key(" img.png ").as_deref(), Some("img.png"));
+        assert_eq!(
+            sanitize_asset_key("cafe\u{301}.png").as_deref(),
+            Some("caf\u{e9}.png")
+        );
+    }
+
+    #[test]
+    fn sanitize_asset_key_refuses_unusable_keys() {
+        assert_eq!(sanitize_asset_key(""), None);
+        assert_eq!(sanitize_asset_key("/./"), None);
+        assert_eq!(sanitize_asset_key("../secret"), None);
+        assert_eq!(sanitize_asset_key("posts/../../secret"), None);
+        assert_eq!(sanitize_asset_key("img\u{202E}gnp.exe"), None);
+        assert_eq!(sanitize_asset_key("img\n.png"), None);
+    }
+
+    #[test]
+    fn sanitize_asset_key_refuses_the_trash() {
+        assert_eq!(sanitize_asset_key(".trash/img.png"), None);
+        assert_eq!(sanitize_asset_key(".trash"), None);
+        assert_eq!(sanitize_asset_key("./.trash//img.png"), None);
+        assert_eq!(sanitize_asset_key("posts/.trash/img.png").as_deref(), Some("posts/.trash/img.png"));
+    }
+
+    #[test]
+    fn composed_and_decomposed_names_are_the_same_key() {
+        let composed = "caf\u{e9}/cr\u{e8}me.png";
+        let decomposed = "cafe\u{301}/cre\u{300}me.png";
+        assert_ne!(composed, decomposed);
+
+        assert_eq!(sanitize_asset_key(decomposed).as_deref(), Some(composed));
+        assert_eq!(sanitize_asset_key(composed).as_deref(), Some(composed));
+        assert_eq!(sanitize_asset_name("cre\u{300}me.png").as_deref(), Some("cr\u{e8}me.png"));
+        let config = test_config(&[]);
+        assert_eq!(new_asset_key(&config, decomposed), new_asset_key(&config, composed));
+        assert_eq!(normalize_folder("cafe\u{301}").as_deref(), Some("caf\u{e9}/"));
+    }
+
+    #[test]
+    fn new_asset_names_are_held_to_the_length_limit() {
+        let config = test_config(&[("MAX_ASSET_NAME_LENGTH", "9")]);
+        assert_eq!(new_asset_name(&config, "abcde.png").as_deref(), Some("abcde.png"));
+        assert_eq!(new_asset_name(&config, "abcdef.png"), None);
+        // Bytes are counted, not characters
+        assert_eq!(new_asset_name(&config, "\u{e9}\u{e9}\u{e9}.png"), None);
+        // Folders count towards the limit of keys
+        assert_eq!(new_asset_key(&config, "a/b/c.png"), Some("a/b/c.png".to_string()));
+        assert_eq!(new_asset_key(&config, "ab/cd/e.png"), None);
+    }
+
+    #[test]
+    fn new_asset_names_may_use_any_character_by_default() {
+        let config = test_config(&[]);
+        assert_eq!(new_asset_name(&config, "my photo (1).png").as_deref(), Some("my photo (1).png"));
+        let longest = format!("{}.png", "a".repeat(251));
+        assert_eq!(new_asset_name(&config, &longest), Some(longest.clone()));
+        assert_eq!(new_asset_name(&config, &format!("a{longest}")), None);
+    }
+
+    #[test]
+    fn reject_refuses_names_outside_the_safe_set() {
+        let config = test_config(&[("ASSET_NAME_CHARACTERS", "reject")]);
+        assert_eq!(new_asset_name(&config, "my-photo_1.png").as_deref(), Some("my-photo_1.png"));
+        assert_eq!(new_asset_key(&config, "posts/1/img.png").as_deref(), Some("posts/1/img.png"));
+        assert_eq!(new_asset_name(&config, "my photo.png"), None);
+        assert_eq!(new_asset_name(&config, "caf\u{e9}.png"), None);
+        assert_eq!(new_asset_key(&config, "posts/#1/img.png"), None);
+    }
+
+    #[test]
+    fn replace_swaps_characters_outside_the_safe_set() {
+        let config = test_config(&[("ASSET_NAME_CHARACTERS", "replace")]);
+        assert_eq!(new_asset_name(&config, "my photo (1).png").as_deref(), Some("my_photo__1_.png"));
+        assert_eq!(new_asset_key(&config, "posts/#1/img.png").as_deref(), Some("posts/_1/img.png"));
+        // One `_` per character, however many bytes it took
+        assert_eq!(new_asset_name(&config, "caf\u{e9}.png").as_deref(), Some("caf_.png"));
+    }
+
+    #[test]
+    fn replaced_names_are_held_to_the_length_limit() {
+        let config = test_config(&[
+            ("ASSET_NAME_CHARACTERS", "replace"),
+            ("MAX_ASSET_NAME_LENGTH", "8"),
+        ]);
+        // Too long as UTF-8, but short enough once replaced
+        assert_eq!(new_asset_name(&config, "\u{e9}\u{e9}\u{e9}\u{e9}.png").as_deref(), Some("____.png"));
+        assert_eq!(new_asset_name(&config, "abcde.png"), None);
     }
 }
@@ -0,0 +1,65 @@
+/// MD5 of an upload as it will be stored, kept both for the whole object
+/// and per multipart part, so it can be compared against either kind of
+/// etag S3 hands back
+pub(crate) struct UploadChecksum {
+    part_size: u64,
+    whole: md5::Context,
+    part: md5::Context,
+    part_len: u64,
+    parts: Vec<[u8; 16]>,
+}
+
+impl UploadChecksum {
+    /// Checksum for an upload split into parts of `part_size` bytes, the
+    /// way `put_object_content` splits it
+    pub fn new(part_size: u64) -> Self {
+        Self {
+            part_size,
+            whole: md5::Context::new(),
+            part: md5::Context::new(),
+            part_len: 0,
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.whole.consume(data);
+        while !data.is_empty() {
+            if self.part_len == self.part_size {
+                let part = std::mem::replace(&mut self.part, md5::Context::new());
+                self.parts.push(part.compute().0);
+                self.part_len = 0;
+            }
+            let take = data.len().min((self.part_size - self.part_len) as usize);
+            self.part.consume(&data[..take]);
+            self.part_len += take as u64;
+            data = &data[take..];
+        }
+    }
+
+    /// Whether `etag` matches the bytes seen, or `None` if it isn't an MD5
+    /// style etag, as with SSE-KMS or SSE-C encrypted objects
+    pub fn matches(self, etag: &str) -> Option<bool> {
+        let etag = etag.trim_matches('"');
+        let (digest, part_count) = match etag.split_once('-') {
+            Some((digest, count)) => (digest, Some(count.parse::<usize>().ok()?)),
+            None => (etag, None),
+        };
+        let digest = hex::decode(digest).ok().filter(|digest| digest.len() == 16)?;
+
+        let expected = match part_count {
+            None => self.whole.compute().0.to_vec(),
+            Some(count) => {
+                let mut parts = self.parts;
+                if self.part_len > 0 || parts.is_empty() {
+                    parts.push(self.part.compute().0);
+                }
+                if parts.len() != count {
+                    return Some(false);
+                }
+                md5::compute(parts.concat()).0.to_vec()
+            }
+        };
+        Some(digest == expected)
+    }
+}
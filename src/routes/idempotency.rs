@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Longest `Idempotency-Key` accepted
+pub(crate) const MAX_KEY_LENGTH: usize = 255;
+
+/// Keys remembered before expired ones are dropped
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Result of an upload, replayed to retries sent with the same key
+#[derive(Clone)]
+pub(crate) struct CachedUpload {
+    pub path: String,
+    pub outcome: &'static str,
+}
+
+enum Entry {
+    /// The first request with the key hasn't finished yet
+    Pending { fingerprint: String },
+    Done {
+        fingerprint: String,
+        upload: CachedUpload,
+        expires: Instant,
+    },
+}
+
+static ENTRIES: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(Default::default);
+
+fn entries() -> std::sync::MutexGuard<'static, HashMap<String, Entry>> {
+    ENTRIES.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// What to do with a request carrying an idempotency key
+pub(crate) enum Claim {
+    /// First use of the key, the upload goes ahead
+    New(Pending),
+    /// The key was used for an upload that succeeded, answer with its result
+    Replay(CachedUpload),
+    /// A request with the key is still being handled
+    InProgress,
+    /// The key was used for a different upload
+    Mismatch,
+}
+
+/// Fingerprint of an upload for `claim`, from the name it is stored under,
+/// its `content` and the custom metadata sent with it, so a key reused for
+/// another file or other metadata under the same name is refused too.
+/// `content` is the SHA-256 of a buffered upload, streamed uploads aren't
+/// read before they are stored and only pass their length.
+pub(crate) fn upload_fingerprint(
+    name: &str,
+    content: &str,
+    metadata: &[(String, String)],
+) -> String {
+    let mut metadata = metadata.to_vec();
+    metadata.sort();
+    let fingerprint = json!([name, content, metadata]).to_string();
+    hex::encode(Sha256::digest(fingerprint.as_bytes()))
+}
+
+/// Look up `key` for a client. `fingerprint` identifies the upload, see
+/// `upload_fingerprint`, so a key reused for something else is refused
+/// rather than answered with another upload's result.
+pub(crate) fn claim(client: &str, key: &str, fingerprint: &str) -> Claim {
+    let key = format!("{client}\n{key}");
+    let now = Instant::now();
+    let mut entries = entries();
+
+    if entries.len() >= MAX_TRACKED_KEYS {
+        entries.retain(|_, entry| match entry {
+            Entry::Pending { .. } => true,
+            Entry::Done { expires, .. } => *expires > now,
+        });
+    }
+
+    match entries.get(&key) {
+        Some(Entry::Pending { fingerprint: used }) | Some(Entry::Done { fingerprint: used, .. })
+            if used != fingerprint =>
+        {
+            Claim::Mismatch
+        }
+        Some(Entry::Pending { .. }) => Claim::InProgress,
+        Some(Entry::Done {
+            upload, expires, ..
+        }) if *expires > now => Claim::Replay(upload.clone()),
+        _ => {
+            entries.insert(
+                key.clone(),
+                Entry::Pending {
+                    fingerprint: fingerprint.to_string(),
+                },
+            );
+            Claim::New(Pending {
+                key,
+                fingerprint: fingerprint.to_string(),
+                finished: false,
+            })
+        }
+    }
+}
+
+/// A claimed key whose upload is under way. Dropping it without `finish`,
+/// because the upload failed or the request was cancelled, frees the key so
+/// a retry performs the upload again.
+pub(crate) struct Pending {
+    key: String,
+    fingerprint: String,
+    finished: bool,
+}
+
+impl Pending {
+    /// Remember the upload's result for retries during `ttl`
+    pub(crate) fn finish(mut self, upload: CachedUpload, ttl: Duration) {
+        self.finished = true;
+        entries().insert(
+            std::mem::take(&mut self.key),
+            Entry::Done {
+                fingerprint: std::mem::take(&mut self.fingerprint),
+                upload,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.finished {
+            entries().remove(&self.key);
+        }
+    }
+}
@@ -1,25 +1,131 @@
+use poem::web::Data;
+use poem_openapi::payload::{Json, PlainText};
+use poem_openapi::{ApiResponse, Enum, Object, OpenApi, Tags};
 
-use poem_openapi::{OpenApi, Tags};
+use crate::config::AppConfig;
+use crate::connections::{SharedStorage, Storage};
+use crate::metrics;
 
+mod admin;
+mod asset_cache;
 mod assets;
+mod checksum;
+mod idempotency;
+mod remote;
+mod svg;
+
+pub(crate) use assets::sanitize_asset_key;
 
 #[derive(Debug, Tags)]
 #[allow(dead_code)]
 pub enum ApiTags {
     Assets,
+    Admin,
+
+}
+
+/// Outcome of one readiness check
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+enum CheckStatus {
+    Ok,
+    /// Object storage could not be reached at all
+    Down,
+    /// Object storage answered but the bucket doesn't exist
+    Missing,
+    /// The bucket could not be checked
+    Unknown,
+}
+
+#[derive(Object)]
+struct ReadinessChecks {
+    connection: CheckStatus,
+    assets_bucket: CheckStatus,
+    thumbnails_bucket: CheckStatus,
+    hash_index_bucket: CheckStatus,
+}
+
+#[derive(ApiResponse)]
+enum ReadinessResponse {
+    #[oai(status = 200)]
+    Ready(Json<ReadinessChecks>),
+    #[oai(status = 503)]
+    NotReady(Json<ReadinessChecks>),
+}
 
+/// Whether `bucket` exists, `None` when storage didn't answer. The backend
+/// logs and counts the failure.
+async fn check_bucket(storage: &dyn Storage, bucket: &str) -> Option<bool> {
+    storage.bucket_exists(bucket).await.ok()
+}
+
+#[derive(ApiResponse)]
+enum MetricsResponse {
+    #[oai(status = 200)]
+    Ok(PlainText<String>, #[oai(header = "Content-Type")] String),
 }
 
 pub struct RootApi;
 
 #[OpenApi]
 impl RootApi {
+      /// Liveness probe, succeeds as long as the process is serving requests
       #[oai(method = "get", path = "/healthcheck")]
       async fn healthcheck(&self) {
 
       }
+
+      /// Readiness probe, fails while object storage can't be reached or a
+      /// bucket the service needs is missing. The body tells which, e.g.
+      /// `{"connection": "ok", "assets_bucket": "missing", ...}`.
+      #[oai(method = "get", path = "/readyz")]
+      async fn readyz(
+          &self,
+          config: Data<&AppConfig>,
+          storage: Data<&SharedStorage>,
+      ) -> ReadinessResponse {
+          let assets = check_bucket(storage.as_ref(), &config.assets_bucket).await;
+          let thumbnails = check_bucket(storage.as_ref(), &config.thumbnails_bucket).await;
+          let hash_index = check_bucket(storage.as_ref(), &config.hash_index_bucket).await;
+
+          let reachable = [assets, thumbnails, hash_index].iter().any(Option::is_some);
+          let status = |exists: Option<bool>| match exists {
+              Some(true) => CheckStatus::Ok,
+              Some(false) => CheckStatus::Missing,
+              None => CheckStatus::Unknown,
+          };
+          let checks = ReadinessChecks {
+              connection: if reachable { CheckStatus::Ok } else { CheckStatus::Down },
+              assets_bucket: status(assets),
+              thumbnails_bucket: status(thumbnails),
+              hash_index_bucket: status(hash_index),
+          };
+
+          let ready = [
+              checks.connection,
+              checks.assets_bucket,
+              checks.thumbnails_bucket,
+              checks.hash_index_bucket,
+          ]
+          .iter()
+          .all(|check| *check == CheckStatus::Ok);
+          if ready {
+              ReadinessResponse::Ready(Json(checks))
+          } else {
+              ReadinessResponse::NotReady(Json(checks))
+          }
+      }
+
+      /// Request, transfer and storage error metrics in the Prometheus text format
+      #[oai(method = "get", path = "/metrics")]
+      async fn metrics(&self) -> MetricsResponse {
+          MetricsResponse::Ok(
+              PlainText(metrics::render()),
+              prometheus::TEXT_FORMAT.to_string(),
+          )
+      }
 }
 
 pub fn api() -> impl OpenApi {
-    (RootApi, assets::AssetsApi)
-}
\ No newline at end of file
+    (RootApi, assets::AssetsApi, admin::AdminApi)
+}
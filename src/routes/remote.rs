@@ -0,0 +1,203 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_LENGTH, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::{StatusCode, Url};
+use tracing::{debug, warn};
+
+use crate::config::AppConfig;
+
+/// Redirects followed before giving up on a URL
+const MAX_REDIRECTS: usize = 5;
+
+/// Why a remote asset could not be fetched
+pub(crate) enum RemoteFetchError {
+    /// The URL, or one it redirected to, is not allowed by the import policy
+    Forbidden(String),
+    /// The resource is larger than the upload limit
+    TooLarge,
+    /// The host could not be reached or didn't answer with the resource
+    Unavailable(String),
+}
+
+/// Download `url` for importing it as an asset, reading at most `limit`
+/// bytes.
+///
+/// Every host, including those redirected to, has to pass the import
+/// policy: `URL_UPLOAD_DENIED_HOSTS`, `URL_UPLOAD_ALLOWED_HOSTS`, and unless
+/// `URL_UPLOAD_ALLOW_PRIVATE` is set, every address it resolves to must be
+/// public. The connection goes to the address that was checked, so a second
+/// DNS answer can't point it somewhere internal.
+pub(crate) async fn fetch(
+    config: &AppConfig,
+    url: &str,
+    limit: u64,
+) -> Result<Vec<u8>, RemoteFetchError> {
+    let mut url = Url::parse(url)
+        .map_err(|_| RemoteFetchError::Forbidden(format!("{url:?} is not a valid URL")))?;
+    let timeout = Duration::from_secs(config.url_upload_timeout_secs);
+
+    for _ in 0..=MAX_REDIRECTS {
+        let address = check_target(config, &url).await?;
+        let host = url.host_str().unwrap_or_default().to_string();
+
+        let client = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .no_proxy()
+            .timeout(timeout)
+            .resolve(&host, address)
+            .build()
+            .map_err(|why| RemoteFetchError::Unavailable(why.to_string()))?;
+
+        debug!(%url, %address, "fetching remote asset");
+        let mut response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|why| RemoteFetchError::Unavailable(why.without_url().to_string()))?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    RemoteFetchError::Unavailable("redirect without a location".to_string())
+                })?;
+            url = url.join(location).map_err(|_| {
+                RemoteFetchError::Unavailable(format!("invalid redirect to {location:?}"))
+            })?;
+            continue;
+        }
+        if response.status() != StatusCode::OK {
+            return Err(RemoteFetchError::Unavailable(format!(
+                "remote server answered {}",
+                response.status()
+            )));
+        }
+
+        let declared = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        if declared.is_some_and(|length| length > limit) {
+            return Err(RemoteFetchError::TooLarge);
+        }
+
+        let mut contents = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|why| RemoteFetchError::Unavailable(why.without_url().to_string()))?
+        {
+            if (contents.len() + chunk.len()) as u64 > limit {
+                return Err(RemoteFetchError::TooLarge);
+            }
+            contents.extend_from_slice(&chunk);
+        }
+        return Ok(contents);
+    }
+
+    Err(RemoteFetchError::Unavailable("too many redirects".to_string()))
+}
+
+/// The address to connect to for `url`, if the import policy allows it
+async fn check_target(config: &AppConfig, url: &Url) -> Result<SocketAddr, RemoteFetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(RemoteFetchError::Forbidden(format!(
+            "only http and https URLs can be imported (got {})",
+            url.scheme()
+        )));
+    }
+    let Some(host) = url.host_str().map(str::to_lowercase) else {
+        return Err(RemoteFetchError::Forbidden("the URL has no host".to_string()));
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let is_listed = |hosts: &[String]| {
+        hosts
+            .iter()
+            .any(|listed| host == *listed || host.ends_with(&format!(".{listed}")))
+    };
+    if is_listed(&config.url_upload_denied_hosts) {
+        return Err(RemoteFetchError::Forbidden(format!("imports from {host} are not allowed")));
+    }
+    if let Some(allowed) = &config.url_upload_allowed_hosts
+        && !is_listed(allowed)
+    {
+        return Err(RemoteFetchError::Forbidden(format!("imports from {host} are not allowed")));
+    }
+
+    let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<SocketAddr> = match bare_host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((bare_host, port))
+            .await
+            .map_err(|why| RemoteFetchError::Unavailable(format!("could not resolve {host}: {why}")))?
+            .collect(),
+    };
+
+    // Every answer is checked, the resolver could hand out any of them
+    if !config.url_upload_allow_private
+        && let Some(internal) = addresses.iter().find(|address| !is_public(address.ip()))
+    {
+        warn!(%host, address = %internal.ip(), "refused import from an internal address");
+        return Err(RemoteFetchError::Forbidden(format!(
+            "{host} resolves to an internal address"
+        )));
+    }
+
+    addresses
+        .into_iter()
+        .next()
+        .ok_or_else(|| RemoteFetchError::Unavailable(format!("{host} has no addresses")))
+}
+
+/// Whether an address is reachable on the public internet, as opposed to
+/// loopback, private, link-local, shared, reserved or documentation ranges
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_v4(mapped);
+            }
+            let segments = ip.segments();
+            // NAT64 addresses embed the IPv4 address they translate to
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_v4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || is_in_v6(ip, 0xfc00, 7) // unique local
+                || is_in_v6(ip, 0xfe80, 10) // link-local
+                || is_in_v6(ip, 0xfec0, 10) // site-local
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)) // documentation
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)) // shared address space
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+        || (a == 198 && (18..20).contains(&b)) // benchmarking
+        || a >= 240) // reserved
+}
+
+fn is_in_v6(ip: Ipv6Addr, prefix: u16, bits: u32) -> bool {
+    let mask = u16::MAX << (16 - bits);
+    ip.segments()[0] & mask == prefix & mask
+}
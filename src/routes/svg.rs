@@ -0,0 +1,243 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+
+/// Elements dropped with everything inside them, they run code or pull in
+/// other documents
+const ACTIVE_ELEMENTS: &[&str] = &[
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "handler",
+    "listener",
+];
+
+/// Remove anything that could run script or load external resources from an
+/// SVG document, so it is safe to serve inline.
+///
+/// Active elements, event handler attributes, links other than fragments
+/// and embedded raster images, and styles referencing other URLs are
+/// dropped. So are the doctype, which could declare entities, and
+/// processing instructions such as `xml-stylesheet`. `None` when the
+/// document isn't well formed XML.
+pub(crate) fn sanitize_svg(source: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = Reader::from_reader(source);
+    let mut writer = Writer::new(Vec::with_capacity(source.len()));
+
+    // Nesting depth inside a dropped element, 0 when outside of one
+    let mut skipped_depth = 0usize;
+    let mut in_style = false;
+
+    loop {
+        let event = reader.read_event().ok()?;
+        if skipped_depth > 0 {
+            match event {
+                Event::Start(_) => skipped_depth += 1,
+                Event::End(_) => skipped_depth -= 1,
+                Event::Eof => return None,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(element) => {
+                if is_active(&element)? {
+                    skipped_depth = 1;
+                    continue;
+                }
+                in_style = local_name(&element) == "style";
+                writer.write_event(Event::Start(clean_attributes(&element)?)).ok()?;
+            }
+            Event::Empty(element) => {
+                if !is_active(&element)? {
+                    writer.write_event(Event::Empty(clean_attributes(&element)?)).ok()?;
+                }
+            }
+            Event::End(element) => {
+                in_style = false;
+                writer.write_event(Event::End(element)).ok()?;
+            }
+            Event::Text(text) => {
+                if in_style && references_url(&text.unescape().ok()?) {
+                    continue;
+                }
+                writer.write_event(Event::Text(text)).ok()?;
+            }
+            Event::CData(data) => {
+                if in_style && references_url(&String::from_utf8_lossy(&data)) {
+                    continue;
+                }
+                writer.write_event(Event::CData(data)).ok()?;
+            }
+            Event::Decl(decl) => writer.write_event(Event::Decl(decl)).ok()?,
+            Event::DocType(_) | Event::PI(_) | Event::Comment(_) => {}
+            Event::Eof => break,
+        }
+    }
+
+    Some(writer.into_inner())
+}
+
+fn local_name(element: &BytesStart) -> String {
+    String::from_utf8_lossy(element.local_name().as_ref()).to_lowercase()
+}
+
+/// Whether an element is dropped as a whole. Besides the script-like ones,
+/// animations can set an `href` or event handler after the fact.
+fn is_active(element: &BytesStart) -> Option<bool> {
+    let name = local_name(element);
+    if ACTIVE_ELEMENTS.contains(&name.as_str()) {
+        return Some(true);
+    }
+
+    if matches!(name.as_str(), "set" | "animate") {
+        for attribute in element.attributes() {
+            let attribute = attribute.ok()?;
+            if attribute.key.local_name().as_ref() == b"attributeName" {
+                let target = attribute.unescape_value().ok()?.trim().to_lowercase();
+                let target = target.rsplit(':').next().unwrap_or_default();
+                if target == "href" || target.starts_with("on") {
+                    return Some(true);
+                }
+            }
+        }
+    }
+
+    Some(false)
+}
+
+/// Copy of an element without event handlers, external links and styles
+/// loading other URLs
+fn clean_attributes<'a>(element: &'a BytesStart) -> Option<BytesStart<'a>> {
+    let mut clean = element.to_owned();
+    clean.clear_attributes();
+
+    for attribute in element.attributes() {
+        let attribute = attribute.ok()?;
+        let name = String::from_utf8_lossy(attribute.key.local_name().as_ref()).to_lowercase();
+        let value = attribute.unescape_value().ok()?;
+        let normalized = value.trim().to_lowercase();
+
+        let is_unsafe = name.starts_with("on")
+            || normalized.contains("javascript:")
+            || (name == "href" && !is_local_reference(&normalized))
+            || (name == "style" && references_url(&normalized));
+        if !is_unsafe {
+            clean.push_attribute(attribute);
+        }
+    }
+
+    Some(clean)
+}
+
+/// Links that stay within the document, or inline raster images. Inline SVG
+/// is excluded as it could nest a script of its own.
+fn is_local_reference(href: &str) -> bool {
+    href.starts_with('#')
+        || ["data:image/png", "data:image/jpeg", "data:image/gif", "data:image/webp"]
+            .iter()
+            .any(|prefix| href.starts_with(prefix))
+}
+
+/// Whether CSS imports or refers to anything but a fragment of the document
+fn references_url(css: &str) -> bool {
+    let css = css.to_lowercase();
+    if css.contains("@import") {
+        return true;
+    }
+
+    css.split("url(").skip(1).any(|target| {
+        let target = target.trim_start().trim_start_matches(['"', '\'']);
+        !target.starts_with('#')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(svg: &str) -> String {
+        String::from_utf8(sanitize_svg(svg.as_bytes()).expect("SVG is well formed"))
+            .expect("sanitized SVG is UTF-8")
+    }
+
+    #[test]
+    fn keeps_plain_drawings() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg"><rect width="10" fill="url(#g)"/><use href="#shape"/></svg>"##;
+        assert_eq!(sanitized(svg), svg);
+    }
+
+    #[test]
+    fn drops_event_handlers() {
+        let svg = sanitized(r#"<svg onload="alert(1)"><circle r="1" OnClick="alert(2)"/></svg>"#);
+        assert!(!svg.to_lowercase().contains("alert"), "{svg}");
+        assert!(svg.contains(r#"<circle r="1"/>"#), "{svg}");
+    }
+
+    #[test]
+    fn drops_scripts_with_their_content() {
+        let svg = sanitized(
+            r#"<svg><script>alert(1)</script><g><script type="text/javascript"><![CDATA[alert(2)]]></script></g></svg>"#,
+        );
+        assert_eq!(svg, "<svg><g></g></svg>");
+    }
+
+    #[test]
+    fn drops_javascript_links() {
+        let svg = sanitized(
+            r#"<svg xmlns:xlink="http://www.w3.org/1999/xlink"><a href="javascript:alert(1)"><text>a</text></a><a xlink:href=" JavaScript:alert(2)"><text>b</text></a></svg>"#,
+        );
+        assert!(!svg.to_lowercase().contains("javascript"), "{svg}");
+        assert!(svg.contains("<text>a</text>") && svg.contains("<text>b</text>"), "{svg}");
+    }
+
+    #[test]
+    fn drops_entity_encoded_javascript_links() {
+        let svg = sanitized(
+            r#"<svg><a href="&#106;avascript&#58;alert(1)"><text>a</text></a><a href="&#x6A;&#x61;vascript:alert(2)"/></svg>"#,
+        );
+        assert!(!svg.contains("href"), "{svg}");
+    }
+
+    #[test]
+    fn drops_animations_setting_links_or_handlers() {
+        let svg = sanitized(
+            r#"<svg><a><set attributeName="href" to="javascript:alert(1)"/><animate attributeName="xlink:href" values="javascript:alert(2)"/><set attributeName="onclick" to="alert(3)"/><set attributeName="fill" to="red"/></a></svg>"#,
+        );
+        assert!(!svg.contains("alert"), "{svg}");
+        assert!(svg.contains(r#"<set attributeName="fill" to="red"/>"#), "{svg}");
+    }
+
+    #[test]
+    fn drops_foreign_objects() {
+        let svg = sanitized(
+            r#"<svg><foreignObject width="10"><body xmlns="http://www.w3.org/1999/xhtml"><iframe src="https://example.com"/></body></foreignObject><circle r="1"/></svg>"#,
+        );
+        assert_eq!(svg, r#"<svg><circle r="1"/></svg>"#);
+    }
+
+    #[test]
+    fn drops_styles_loading_other_urls() {
+        let svg = sanitized(
+            r##"<svg><style>@import url("https://example.com/x.css");</style><style>rect { fill: url(#g) }</style><rect style="background: url(https://example.com/t.png)"/></svg>"##,
+        );
+        assert!(!svg.contains("example.com"), "{svg}");
+        assert!(svg.contains("fill: url(#g)"), "{svg}");
+    }
+
+    #[test]
+    fn drops_the_doctype_and_its_entities() {
+        let svg = sanitized(
+            r#"<?xml version="1.0"?><!DOCTYPE svg [<!ENTITY xxe SYSTEM "file:///etc/passwd"><!ENTITY lol "lol">]><svg><text>hi</text></svg>"#,
+        );
+        assert_eq!(svg, r#"<?xml version="1.0"?><svg><text>hi</text></svg>"#);
+    }
+
+    #[test]
+    fn refuses_malformed_documents() {
+        assert_eq!(sanitize_svg(b"<svg><script>alert(1)</svg>"), None);
+        assert_eq!(sanitize_svg(b"<svg><g></svg>"), None);
+    }
+}
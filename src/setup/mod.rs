@@ -1,21 +1,103 @@
-use crate::config;
-use crate::connections::ObjectStorage;
+use std::sync::Arc;
 
+use anyhow::Context;
+use poem::http::{HeaderName, Method};
+use poem::http::header::{
+    ACCEPT_RANGES, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+};
+use poem::middleware::Cors;
+use tracing::info;
 
-pub fn get_object_storage() -> anyhow::Result<ObjectStorage> {
-    Ok(ObjectStorage::new(
-        config::CONFIG.minio_url.clone(),
-        config::CONFIG.minio_access.clone(),
-        config::CONFIG.minio_secret.clone(),
-    )?)
+use crate::config::{self, AppConfig, StorageBackend};
+use crate::connections::fs_storage::FsStorage;
+use crate::connections::{ObjectStorage, SharedStorage, Storage};
+
+/// CORS policy for browser clients on other origins.
+///
+/// Allows `GET`, `HEAD`, `PUT`, `PATCH`, `POST`, `DELETE` and `OPTIONS` with the
+/// `Authorization`, `Content-Type`, `Range`, `If-None-Match`, `If-Match`,
+/// `If-Modified-Since` and `Idempotency-Key` request headers, and exposes the caching and range
+/// headers of downloads. Only meant to be mounted when origins are configured,
+/// an empty allow list means any origin to poem.
+pub fn get_cors(config: &AppConfig) -> Cors {
+    let origins = &config.cors_allowed_origins;
+
+    let cors = Cors::new()
+        .allow_methods([
+            Method::GET,
+            Method::HEAD,
+            Method::PUT,
+            Method::PATCH,
+            Method::POST,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([
+            AUTHORIZATION,
+            CONTENT_TYPE,
+            RANGE,
+            IF_NONE_MATCH,
+            IF_MATCH,
+            IF_MODIFIED_SINCE,
+            HeaderName::from_static("idempotency-key"),
+        ])
+        .expose_headers([
+            ETAG,
+            LAST_MODIFIED,
+            CONTENT_RANGE,
+            ACCEPT_RANGES,
+            CONTENT_DISPOSITION,
+        ]);
+
+    if origins.iter().any(|origin| origin == "*") {
+        cors
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    }
+}
+
+/// The backend selected with `STORAGE_BACKEND`
+pub fn get_storage(config: &AppConfig) -> anyhow::Result<SharedStorage> {
+    Ok(match config.storage_backend {
+        StorageBackend::Minio => Arc::new(ObjectStorage::new(config)?),
+        StorageBackend::Fs => Arc::new(FsStorage::new(&config.storage_fs_root)),
+    })
+}
+
+/// Create any bucket the service relies on that doesn't exist yet
+pub async fn ensure_buckets(config: &AppConfig, storage: &dyn Storage) -> anyhow::Result<()> {
+    for bucket in [&config.assets_bucket, &config.thumbnails_bucket, &config.hash_index_bucket] {
+        let exists = storage
+            .bucket_exists(bucket)
+            .await
+            .with_context(|| format!("could not check whether bucket {bucket:?} exists"))?;
+
+        if exists {
+            info!("bucket {bucket:?} already exists");
+            continue;
+        }
+
+        storage
+            .create_bucket(bucket)
+            .await
+            .with_context(|| format!("could not create bucket {bucket:?}"))?;
+        info!("created bucket {bucket:?}");
+    }
+
+    Ok(())
 }
 
 
 pub struct SetupResult {
-    pub object_storage: ObjectStorage,
+    pub config: &'static AppConfig,
+    pub storage: SharedStorage,
 }
 
 pub async fn setup_all() -> anyhow::Result<SetupResult> {
-    let object_storage = get_object_storage()?;
-    Ok(SetupResult { object_storage })
+    let config = config::load()?;
+    info!(?config, "loaded configuration");
+    let storage = get_storage(config)?;
+    ensure_buckets(config, storage.as_ref()).await?;
+    Ok(SetupResult { config, storage })
 }
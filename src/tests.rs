@@ -0,0 +1,1055 @@
+//! The API as `build_app` assembles it, driven in-process with poem's
+//! `TestClient`. Tests touching storage run against the `fs` backend in a
+//! temporary directory. The same scenarios also run against MinIO in a
+//! container, those need Docker and only run with `cargo test -- --ignored`.
+//! The MinIO client blocks in place while it looks up bucket regions, so
+//! tests reaching it run on the multi-threaded runtime.
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageFormat};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use poem::endpoint::BoxEndpoint;
+use poem::EndpointExt;
+use poem::http::StatusCode;
+use poem::test::{TestClient, TestForm, TestFormField, TestResponse};
+use serde_json::{Value, json};
+use testcontainers_modules::minio::MinIO;
+use testcontainers_modules::testcontainers::ContainerAsync;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+
+use crate::auth::{Claims, Permission};
+use crate::build_app;
+use crate::config::AppConfig;
+use crate::setup;
+
+const JWT_PRIVATE_KEY: &str = include_str!("../testdata/jwt.pem");
+const JWT_PUBLIC_KEY: &str = include_str!("../testdata/jwt.pub.pem");
+
+/// Nothing listens here, requests that reach storage fail
+const UNREACHABLE_MINIO_URL: &str = "http://127.0.0.1:9";
+
+fn test_config(minio_url: &str) -> AppConfig {
+    minio_config(minio_url, &[])
+}
+
+/// Configuration for MinIO at `minio_url`, `vars` adds to or overrides it
+fn minio_config(minio_url: &str, vars: &[(&str, &str)]) -> AppConfig {
+    let mut all = vec![
+        ("MINIO_URL", minio_url),
+        ("MINIO_ACCESS", "minioadmin"),
+        ("MINIO_SECRET", "minioadmin"),
+        ("JWT_PUBLIC_KEY", JWT_PUBLIC_KEY),
+        ("STORAGE_RETRIES", "0"),
+    ];
+    all.extend_from_slice(vars);
+    AppConfig::from_vars(&all).expect("test configuration is valid")
+}
+
+/// The app behind a box, its full type is too deeply nested for the
+/// compiler to lay out the futures of the tests
+fn test_client(config: &AppConfig) -> TestClient<BoxEndpoint<'static>> {
+    let storage = setup::get_storage(config).expect("storage backend can be built");
+    TestClient::new(build_app(config, storage).map_to_response().boxed())
+}
+
+/// Bearer token allowing every asset route
+fn token() -> String {
+    token_with(vec![
+        Permission::new("create", "asset", "any"),
+        Permission::new("delete", "asset", "any"),
+    ])
+}
+
+/// Bearer token with only `permissions`
+fn token_with(permissions: Vec<Permission>) -> String {
+    let claims = Claims {
+        sub: "tests".to_string(),
+        company: "tests".to_string(),
+        exp: (chrono::Utc::now().timestamp() + 600) as usize,
+        permissions,
+    };
+    let key = EncodingKey::from_rsa_pem(JWT_PRIVATE_KEY.as_bytes()).expect("test key is valid");
+    encode(&Header::new(Algorithm::RS256), &claims, &key).expect("token can be signed")
+}
+
+/// Configuration checking issuer, audience and a named key, without leeway
+/// so times just past are already out of range
+fn jwt_config() -> AppConfig {
+    let keys = json!([{ "kid": "current", "pem": JWT_PUBLIC_KEY }]).to_string();
+    AppConfig::from_vars(&[
+        ("MINIO_URL", UNREACHABLE_MINIO_URL),
+        ("MINIO_ACCESS", "minioadmin"),
+        ("MINIO_SECRET", "minioadmin"),
+        ("JWT_PUBLIC_KEYS", &keys),
+        ("JWT_ISSUER", "accounts"),
+        ("JWT_AUDIENCE", "assets"),
+        ("JWT_LEEWAY_SECS", "0"),
+    ])
+    .expect("test configuration is valid")
+}
+
+/// Claims `jwt_config` accepts, allowing uploads
+fn valid_claims() -> Value {
+    let now = chrono::Utc::now().timestamp();
+    json!({
+        "sub": "tests",
+        "company": "tests",
+        "iss": "accounts",
+        "aud": "assets",
+        "nbf": now - 60,
+        "exp": now + 600,
+        "permissions": [Permission::new("create", "asset", "any")],
+    })
+}
+
+fn signed(claims: &Value, kid: &str) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+    let key = EncodingKey::from_rsa_pem(JWT_PRIVATE_KEY.as_bytes()).expect("test key is valid");
+    encode(&header, claims, &key).expect("token can be signed")
+}
+
+/// Status of an upload with `token`. The file type is refused before
+/// storage is reached, so accepted tokens get `415` and rejected ones `401`.
+async fn upload_status_with(token: &str) -> StatusCode {
+    let client = test_client(&jwt_config());
+    let form = TestForm::new()
+        .field(TestFormField::bytes(b"MZ".to_vec()).name("asset").filename("tool.exe"));
+    client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {token}"))
+        .multipart(form)
+        .send()
+        .await
+        .0
+        .status()
+}
+
+fn png() -> Vec<u8> {
+    png_sized(2)
+}
+
+/// A square PNG, differing in content for each `size`
+fn png_sized(size: u32) -> Vec<u8> {
+    let mut contents = Cursor::new(Vec::new());
+    DynamicImage::new_rgb8(size, size)
+        .write_to(&mut contents, ImageFormat::Png)
+        .expect("PNG can be encoded");
+    contents.into_inner()
+}
+
+async fn upload(
+    client: &TestClient<BoxEndpoint<'static>>,
+    file_name: &str,
+    contents: Vec<u8>,
+) -> TestResponse {
+    upload_with(client, file_name, contents, &[]).await
+}
+
+/// `upload` with extra request headers
+async fn upload_with(
+    client: &TestClient<BoxEndpoint<'static>>,
+    file_name: &str,
+    contents: Vec<u8>,
+    headers: &[(&str, &str)],
+) -> TestResponse {
+    let form = TestForm::new()
+        .field(TestFormField::bytes(contents).name("asset").filename(file_name));
+    let mut request = client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.multipart(form).send().await
+}
+
+/// `ETag` header of a download of `asset`
+async fn etag_of(client: &TestClient<BoxEndpoint<'static>>, asset: &str) -> String {
+    let response = client.get(format!("/assets/{asset}")).send().await;
+    response.assert_status_is_ok();
+    response
+        .0
+        .headers()
+        .get("ETag")
+        .and_then(|etag| etag.to_str().ok())
+        .expect("downloads have an ETag")
+        .to_string()
+}
+
+/// MinIO in a container with the buckets the service needs, dropped with
+/// the returned handle
+async fn minio() -> (ContainerAsync<MinIO>, AppConfig) {
+    let container = MinIO::default().start().await.expect("MinIO starts, is Docker running?");
+    let host = container.get_host().await.expect("MinIO has a host");
+    let port = container.get_host_port_ipv4(9000).await.expect("MinIO exposes its API");
+    let config = test_config(&format!("http://{host}:{port}"));
+    create_buckets(&config).await;
+    (container, config)
+}
+
+/// Directory the `fs` backend keeps its buckets in, removed on drop
+struct FsRoot(PathBuf);
+
+impl Drop for FsRoot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The `fs` backend in a directory of its own with the buckets the service
+/// needs, dropped with the returned handle. `name` keeps concurrent tests
+/// apart, `vars` adds to or overrides the test configuration.
+async fn fs_backend(name: &str, vars: &[(&str, &str)]) -> (FsRoot, AppConfig) {
+    let root = std::env::temp_dir().join(format!("assets-service-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let config = fs_config(&root, vars);
+    create_buckets(&config).await;
+    (FsRoot(root), config)
+}
+
+/// Configuration for the `fs` backend at `root`, e.g. for a second
+/// deployment sharing the buckets of `fs_backend`
+fn fs_config(root: &Path, vars: &[(&str, &str)]) -> AppConfig {
+    let root = root.to_str().expect("temporary directory is UTF-8");
+    let mut all = vec![
+        ("STORAGE_BACKEND", "fs"),
+        ("STORAGE_FS_ROOT", root),
+        ("JWT_PUBLIC_KEY", JWT_PUBLIC_KEY),
+    ];
+    all.extend_from_slice(vars);
+    AppConfig::from_vars(&all).expect("fs configuration needs no MinIO settings")
+}
+
+async fn create_buckets(config: &AppConfig) {
+    let storage = setup::get_storage(config).expect("storage backend can be built");
+    setup::ensure_buckets(config, storage.as_ref())
+        .await
+        .expect("buckets can be created");
+}
+
+#[tokio::test]
+async fn healthcheck_does_not_need_storage() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    client.get("/healthcheck").send().await.assert_status_is_ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn readiness_fails_without_storage() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    client
+        .get("/readyz")
+        .send()
+        .await
+        .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn upload_needs_a_token() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let form = TestForm::new().field(TestFormField::bytes(png()).name("asset").filename("a.png"));
+    let response = client.put("/assets").multipart(form).send().await;
+
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_with_valid_claims_is_accepted() {
+    let token = signed(&valid_claims(), "current");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn expired_token_is_rejected() {
+    let mut claims = valid_claims();
+    claims["exp"] = json!(chrono::Utc::now().timestamp() - 10);
+    let token = signed(&claims, "current");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_from_another_issuer_is_rejected() {
+    let mut claims = valid_claims();
+    claims["iss"] = json!("someone-else");
+    let token = signed(&claims, "current");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_for_another_audience_is_rejected() {
+    let mut claims = valid_claims();
+    claims["aud"] = json!("billing");
+    let token = signed(&claims, "current");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_not_yet_valid_is_rejected() {
+    let mut claims = valid_claims();
+    claims["nbf"] = json!(chrono::Utc::now().timestamp() + 300);
+    let token = signed(&claims, "current");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn token_with_an_unknown_kid_is_rejected() {
+    let token = signed(&valid_claims(), "retired");
+    assert_eq!(upload_status_with(&token).await, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn upload_of_unsupported_type_is_refused_before_storage() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let response = upload(&client, "tool.exe", b"MZ\x90\x00".to_vec()).await;
+
+    response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn malformed_form_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let response = client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()))
+        .content_type("multipart/form-data; boundary=missing")
+        .body("not a form")
+        .send()
+        .await;
+
+    response.assert_status(StatusCode::BAD_REQUEST);
+    let body = response.json().await;
+    let error = body.value().object().get("error");
+    error.object().get("code").assert_string("invalid_multipart");
+}
+
+/// Upload with a hand-built body, for forms `TestForm` can't produce
+async fn upload_raw(
+    client: &TestClient<BoxEndpoint<'static>>,
+    content_type: &str,
+    body: impl Into<Vec<u8>>,
+) -> TestResponse {
+    client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()))
+        .content_type(content_type)
+        .body(body.into())
+        .send()
+        .await
+}
+
+async fn assert_invalid_multipart(response: TestResponse) {
+    response.assert_status(StatusCode::BAD_REQUEST);
+    let body = response.json().await;
+    let error = body.value().object().get("error");
+    error.object().get("code").assert_string("invalid_multipart");
+}
+
+#[tokio::test]
+async fn truncated_part_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let body = "--cut\r\nContent-Disposition: form-data; name=\"asset\"; filename=\"a.png\"\r\n\
+                Content-Type: image/png\r\n\r\n\u{89}PNG half of an ima";
+    let response = upload_raw(&client, "multipart/form-data; boundary=cut", body).await;
+    assert_invalid_multipart(response).await;
+}
+
+#[tokio::test]
+async fn form_without_a_boundary_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let body = "--cut\r\nContent-Disposition: form-data; name=\"asset\"; filename=\"a.png\"\r\n\r\n\
+                contents\r\n--cut--\r\n";
+    let response = upload_raw(&client, "multipart/form-data", body).await;
+    assert_invalid_multipart(response).await;
+}
+
+#[tokio::test]
+async fn garbage_body_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let body: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    let response = upload_raw(&client, "multipart/form-data; boundary=cut", body).await;
+    assert_invalid_multipart(response).await;
+}
+
+#[tokio::test]
+async fn form_without_an_asset_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let form = TestForm::new().field(TestFormField::bytes(png()).name("file").filename("a.png"));
+    let response = client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()))
+        .multipart(form)
+        .send()
+        .await;
+    assert_invalid_multipart(response).await;
+}
+
+#[tokio::test]
+async fn upload_without_a_filename_is_a_bad_request() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    let form = TestForm::new().field(TestFormField::bytes(png()).name("asset"));
+    let response = client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()))
+        .multipart(form)
+        .send()
+        .await;
+    response.assert_status(StatusCode::BAD_REQUEST);
+    let body = response.json().await;
+    let error = body.value().object().get("error");
+    error.object().get("code").assert_string("missing_filename");
+}
+
+#[tokio::test]
+async fn prefix_delete_refuses_an_empty_prefix() {
+    let config = test_config(UNREACHABLE_MINIO_URL);
+    let client = test_client(&config);
+
+    for path in ["/assets/prefix", "/assets/prefix/%2F", "/assets/prefix/%2F%2F"] {
+        let response = client
+            .delete(path)
+            .header("Authorization", format!("Bearer {}", token()))
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        let body = response.json().await;
+        let error = body.value().object().get("error");
+        error.object().get("message").assert_string("refusing to delete with an empty prefix");
+    }
+}
+
+/// Upload, list, inspect, download and delete one asset
+async fn asset_lifecycle(config: &AppConfig) {
+    let client = test_client(config);
+    let contents = png();
+
+    let response = upload(&client, "lifecycle.png", contents.clone()).await;
+    response.assert_status_is_ok();
+    response.assert_text("/assets/lifecycle.png").await;
+
+    let response = client.get("/assets").send().await;
+    response.assert_status_is_ok();
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("assets")
+        .assert_string_array(&["lifecycle.png"]);
+
+    let response = client.get("/assets/lifecycle.png/info").send().await;
+    response.assert_status_is_ok();
+    let info = response.json().await;
+    let info = info.value().object();
+    info.get("name").assert_string("lifecycle.png");
+    info.get("size").assert_i64(contents.len() as i64);
+
+    let response = client.get("/assets/lifecycle.png").send().await;
+    response.assert_status_is_ok();
+    response.assert_content_type("image/png");
+    response.assert_bytes(&contents).await;
+
+    let response = client
+        .delete("/assets/lifecycle.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .send()
+        .await;
+    response.assert_status(StatusCode::NO_CONTENT);
+
+    client
+        .get("/assets/lifecycle.png")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    client
+        .get("/assets")
+        .send()
+        .await
+        .json()
+        .await
+        .value()
+        .object()
+        .get("assets")
+        .assert_string_array(&[]);
+}
+
+/// Missing assets are 404 everywhere, and content not matching its name is
+/// never stored
+async fn missing_and_unsupported_assets(config: &AppConfig) {
+    let client = test_client(config);
+
+    client
+        .get("/assets/missing.png")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    client
+        .get("/assets/missing.png/info")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    client
+        .delete("/assets/missing.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+
+    // Named like an image but isn't one
+    let response = upload(&client, "fake.png", b"plain text".to_vec()).await;
+    response.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    client
+        .get("/assets/fake.png")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn asset_lifecycle_against_fs() {
+    let (_root, config) = fs_backend("lifecycle", &[]).await;
+    asset_lifecycle(&config).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "starts MinIO in a container, needs Docker"]
+async fn asset_lifecycle_against_minio() {
+    let (_minio, config) = minio().await;
+    asset_lifecycle(&config).await;
+}
+
+#[tokio::test]
+async fn missing_and_unsupported_assets_against_fs() {
+    let (_root, config) = fs_backend("missing", &[]).await;
+    missing_and_unsupported_assets(&config).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "starts MinIO in a container, needs Docker"]
+async fn missing_and_unsupported_assets_against_minio() {
+    let (_minio, config) = minio().await;
+    missing_and_unsupported_assets(&config).await;
+}
+
+#[tokio::test]
+async fn recent_assets_only_lists_the_own_prefix() {
+    let (root, blog_a) = fs_backend("recent-prefixes", &[("KEY_PREFIX", "blog-a")]).await;
+    let blog_b = fs_config(&root.0, &[("KEY_PREFIX", "blog-b")]);
+    let client_a = test_client(&blog_a);
+    let client_b = test_client(&blog_b);
+
+    upload(&client_a, "a.png", png()).await.assert_status_is_ok();
+    upload(&client_b, "b.png", png()).await.assert_status_is_ok();
+
+    for (client, expected) in [(&client_a, "a.png"), (&client_b, "b.png")] {
+        let response = client.get("/assets/recent").send().await;
+        response.assert_status_is_ok();
+        let recent = response.json().await;
+        let recent = recent.value().array();
+        recent.assert_len(1);
+        recent.get(0).object().get("name").assert_string(expected);
+    }
+}
+
+/// Replacing and deleting with `If-Match` only succeed while it names the
+/// current content, looking at the asset's info doesn't change that
+async fn if_match_preconditions(config: &AppConfig) {
+    let client = test_client(config);
+    upload(&client, "guarded.png", png_sized(2)).await.assert_status_is_ok();
+    let first = etag_of(&client, "guarded.png").await;
+
+    let response = upload_with(&client, "guarded.png", png_sized(3), &[("If-Match", &first)]).await;
+    response.assert_status_is_ok();
+    let second = etag_of(&client, "guarded.png").await;
+    assert_ne!(first, second);
+
+    // The etag the replacement made stale
+    upload_with(&client, "guarded.png", png_sized(4), &[("If-Match", &first)])
+        .await
+        .assert_status(StatusCode::PRECONDITION_FAILED);
+    let response = client
+        .delete("/assets/guarded.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .header("If-Match", &first)
+        .send()
+        .await;
+    response.assert_status(StatusCode::PRECONDITION_FAILED);
+
+    // Nothing matches an asset that isn't there
+    upload_with(&client, "absent.png", png(), &[("If-Match", &second)])
+        .await
+        .assert_status(StatusCode::PRECONDITION_FAILED);
+    client
+        .get("/assets/absent.png")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    client
+        .delete("/assets/absent.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .header("If-Match", &second)
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+
+    client.get("/assets/guarded.png/info").send().await.assert_status_is_ok();
+    assert_eq!(etag_of(&client, "guarded.png").await, second);
+    client
+        .delete("/assets/guarded.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .header("If-Match", &second)
+        .send()
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn if_match_preconditions_against_fs() {
+    let (_root, config) = fs_backend("if-match", &[]).await;
+    if_match_preconditions(&config).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "starts MinIO in a container, needs Docker"]
+async fn if_match_preconditions_against_minio() {
+    let (_minio, config) = minio().await;
+    if_match_preconditions(&config).await;
+}
+
+#[tokio::test]
+async fn if_match_holds_after_info_probed_the_asset() {
+    let (root, config) = fs_backend("if-match-probed", &[]).await;
+    let client = test_client(&config);
+    // Copied in by hand, so its dimensions are only known once probed
+    std::fs::write(root.0.join(&config.assets_bucket).join("copied.png"), png())
+        .expect("asset can be copied into the bucket");
+    let etag = etag_of(&client, "copied.png").await;
+
+    let response = client.get("/assets/copied.png/info").send().await;
+    response.assert_status_is_ok();
+    response.json().await.value().object().get("width").assert_i64(2);
+
+    upload_with(&client, "copied.png", png_sized(3), &[("If-Match", &etag)])
+        .await
+        .assert_status_is_ok();
+}
+
+/// Two deployments keeping their assets in the same buckets under different
+/// `KEY_PREFIX`es only ever see their own, even under the same name
+async fn prefixes_sharing_buckets(blog_a: &AppConfig, blog_b: &AppConfig) {
+    let client_a = test_client(blog_a);
+    let client_b = test_client(blog_b);
+
+    upload(&client_a, "logo.png", png_sized(2)).await.assert_status_is_ok();
+    upload(&client_b, "logo.png", png_sized(3)).await.assert_status_is_ok();
+    upload(&client_b, "only-b.png", png()).await.assert_status_is_ok();
+
+    client_a
+        .get("/assets")
+        .send()
+        .await
+        .json()
+        .await
+        .value()
+        .object()
+        .get("assets")
+        .assert_string_array(&["logo.png"]);
+    client_a
+        .get("/assets/only-b.png")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+    client_a.get("/assets/logo.png").send().await.assert_bytes(png_sized(2)).await;
+    client_b.get("/assets/logo.png").send().await.assert_bytes(png_sized(3)).await;
+
+    client_a
+        .delete("/assets/logo.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .send()
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+    client_b.get("/assets/logo.png").send().await.assert_status_is_ok();
+    client_b
+        .get("/assets")
+        .send()
+        .await
+        .json()
+        .await
+        .value()
+        .object()
+        .get("assets")
+        .assert_string_array(&["logo.png", "only-b.png"]);
+}
+
+#[tokio::test]
+async fn prefixes_sharing_buckets_against_fs() {
+    let (root, blog_a) = fs_backend("shared-buckets", &[("KEY_PREFIX", "blog-a")]).await;
+    let blog_b = fs_config(&root.0, &[("KEY_PREFIX", "blog-b")]);
+    prefixes_sharing_buckets(&blog_a, &blog_b).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "starts MinIO in a container, needs Docker"]
+async fn prefixes_sharing_buckets_against_minio() {
+    let (_minio, config) = minio().await;
+    let blog_a = minio_config(&config.minio_url, &[("KEY_PREFIX", "blog-a")]);
+    let blog_b = minio_config(&config.minio_url, &[("KEY_PREFIX", "blog-b")]);
+    prefixes_sharing_buckets(&blog_a, &blog_b).await;
+}
+
+#[tokio::test]
+async fn composed_and_decomposed_names_reach_the_same_asset() {
+    let (_root, config) = fs_backend("unicode-names", &[]).await;
+    let client = test_client(&config);
+
+    // Uploaded decomposed, as macOS file names often are
+    let response = client
+        .put("/assets/cre%CC%80me.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .content_type("image/png")
+        .body(png())
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    response.assert_text("/assets/cr%C3%A8me.png").await;
+
+    for name in ["cr%C3%A8me.png", "cre%CC%80me.png"] {
+        let response = client.get(format!("/assets/{name}")).send().await;
+        response.assert_status_is_ok();
+        response.assert_bytes(png()).await;
+    }
+    client
+        .get("/assets")
+        .send()
+        .await
+        .json()
+        .await
+        .value()
+        .object()
+        .get("assets")
+        .assert_string_array(&["cr\u{e8}me.png"]);
+    client
+        .delete("/assets/cre%CC%80me.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .send()
+        .await
+        .assert_status(StatusCode::NO_CONTENT);
+}
+
+/// Address of a server that accepts connections and never answers, for as
+/// long as the test runs
+async fn silent_storage() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("a local port is free");
+    let address = listener.local_addr().expect("listener has an address");
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((connection, _)) = listener.accept().await {
+            connections.push(connection);
+        }
+    });
+    format!("http://{address}")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn silent_storage_times_out() {
+    let url = silent_storage().await;
+    let config = minio_config(&url, &[("STORAGE_TIMEOUT_SECS", "1")]);
+    let client = test_client(&config);
+
+    let started = std::time::Instant::now();
+    let response = client.get("/assets/slow.png/info").send().await;
+    response.assert_status(StatusCode::GATEWAY_TIMEOUT);
+    let body = response.json().await;
+    let error = body.value().object().get("error");
+    error.object().get("code").assert_string("storage_timeout");
+    let elapsed = started.elapsed();
+    assert!(elapsed < std::time::Duration::from_secs(10), "answered after {elapsed:?}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_over_the_concurrency_limit_are_turned_away() {
+    // The first upload holds its slot while storage doesn't answer, longer
+    // than the second waits for one
+    let url = silent_storage().await;
+    let config = minio_config(
+        &url,
+        &[("MAX_CONCURRENT_UPLOADS", "1"), ("STORAGE_TIMEOUT_SECS", "7")],
+    );
+    let client = test_client(&config);
+
+    let held = upload(&client, "held.png", png());
+    let turned_away = async {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        upload(&client, "turned-away.png", png()).await
+    };
+    let (held, turned_away) = tokio::join!(held, turned_away);
+
+    turned_away.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+    turned_away.assert_header("Retry-After", "1");
+    held.assert_status(StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn missing_assets_redirect_to_a_nested_default() {
+    let (_root, config) =
+        fs_backend("default-asset", &[("DEFAULT_ASSET_KEY", "defaults/missing.png")]).await;
+    let client = test_client(&config);
+    client
+        .put("/assets/defaults%2Fmissing.png")
+        .header("Authorization", format!("Bearer {}", token()))
+        .content_type("image/png")
+        .body(png())
+        .send()
+        .await
+        .assert_status_is_ok();
+
+    let response = client.get("/assets/gone.png?w=100").send().await;
+    response.assert_status(StatusCode::FOUND);
+    response.assert_header("Location", "/assets/defaults%2Fmissing.png?w=100");
+    let response = client.get("/assets/defaults%2Fmissing.png").send().await;
+    response.assert_status_is_ok();
+    response.assert_bytes(png()).await;
+
+    // Info still reports the asset is missing
+    client
+        .get("/assets/gone.png/info")
+        .send()
+        .await
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn gc_deletes_only_what_a_confirmed_dry_run_reported() {
+    let (_root, config) = fs_backend("gc", &[]).await;
+    let client = test_client(&config);
+    upload(&client, "keep.png", png()).await.assert_status_is_ok();
+    upload(&client, "drop.png", png()).await.assert_status_is_ok();
+
+    let admin = format!("Bearer {}", token_with(vec![Permission::new("admin", "gc", "any")]));
+    let gc = |body: serde_json::Value| {
+        client.post("/admin/gc").header("Authorization", &admin).body_json(&body).send()
+    };
+
+    client
+        .post("/admin/gc")
+        .header("Authorization", format!("Bearer {}", token()))
+        .body_json(&json!({ "referenced": ["keep.png"] }))
+        .send()
+        .await
+        .assert_status(StatusCode::FORBIDDEN);
+
+    // Dry runs are the default and delete nothing
+    let response = gc(json!({ "referenced": ["keep.png"] })).await;
+    response.assert_status_is_ok();
+    let body = response.json().await;
+    let report = body.value().object();
+    report.get("dry_run").assert_bool(true);
+    report.get("kept").assert_i64(1);
+    report.get("deleted").assert_i64(1);
+    report.get("unreferenced").assert_string_array(&["drop.png"]);
+    let confirmation = report.get("confirmation").string().to_string();
+    client.get("/assets/drop.png").send().await.assert_status_is_ok();
+
+    gc(json!({ "referenced": ["keep.png"], "dry_run": false }))
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+
+    // An upload since the dry run changes what would be deleted
+    upload(&client, "new.png", png()).await.assert_status_is_ok();
+    let response = gc(json!({
+        "referenced": ["keep.png"],
+        "dry_run": false,
+        "confirmation": confirmation,
+    }))
+    .await;
+    response.assert_status(StatusCode::CONFLICT);
+    response
+        .json()
+        .await
+        .value()
+        .object()
+        .get("error")
+        .object()
+        .get("code")
+        .assert_string("gc_confirmation_mismatch");
+    client.get("/assets/drop.png").send().await.assert_status_is_ok();
+
+    let response = gc(json!({ "referenced": ["keep.png", "new.png"] })).await;
+    let body = response.json().await;
+    let confirmation = body.value().object().get("confirmation").string().to_string();
+    let response = gc(json!({
+        "referenced": ["keep.png", "new.png"],
+        "dry_run": false,
+        "confirmation": confirmation,
+    }))
+    .await;
+    response.assert_status_is_ok();
+    let body = response.json().await;
+    let report = body.value().object();
+    report.get("dry_run").assert_bool(false);
+    report.get("kept").assert_i64(2);
+    report.get("deleted").assert_i64(1);
+    client.get("/assets/drop.png").send().await.assert_status(StatusCode::NOT_FOUND);
+    client.get("/assets/keep.png").send().await.assert_status_is_ok();
+    client.get("/assets/new.png").send().await.assert_status_is_ok();
+}
+
+#[tokio::test]
+async fn patch_replaces_content_and_keeps_metadata_and_tags() {
+    let (_root, config) = fs_backend("patch", &[("ALLOWED_METADATA_KEYS", "author")]).await;
+    let client = test_client(&config);
+    let patch = |asset: &str, contents: Vec<u8>| {
+        let form = TestForm::new()
+            .field(TestFormField::bytes(contents).name("asset").filename(asset));
+        client
+            .patch(format!("/assets/{asset}"))
+            .header("Authorization", format!("Bearer {}", token()))
+            .multipart(form)
+            .send()
+    };
+
+    patch("missing.png", png()).await.assert_status(StatusCode::NOT_FOUND);
+
+    let form = TestForm::new()
+        .field(TestFormField::bytes(png_sized(2)).name("asset").filename("cat.png"))
+        .field(TestFormField::text("max-age=60").name("cache_control"))
+        .field(TestFormField::text(r#"{"author":"ada"}"#).name("metadata"));
+    client
+        .put("/assets")
+        .header("Authorization", format!("Bearer {}", token()))
+        .multipart(form)
+        .send()
+        .await
+        .assert_status_is_ok();
+    client
+        .put("/assets/cat.png/tags")
+        .header("Authorization", format!("Bearer {}", token()))
+        .body_json(&json!({ "alt": "a cat" }))
+        .send()
+        .await
+        .assert_status_is_ok();
+    let before = client.get("/assets/cat.png/info").send().await.json().await;
+    let before = before.value().object().get("sha256").string().to_string();
+
+    // A GIF is an image too, but not the PNG the asset is
+    let gif = b"GIF89a\x01\x00\x01\x00\x00\x00\x00;".to_vec();
+    patch("cat.png", gif).await.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    patch("cat.png", png_sized(3)).await.assert_status_is_ok();
+    client.get("/assets/cat.png").send().await.assert_bytes(png_sized(3)).await;
+    let response = client.get("/assets/cat.png/info?tags=true").send().await;
+    response.assert_status_is_ok();
+    let body = response.json().await;
+    let info = body.value().object();
+    assert_ne!(info.get("sha256").string(), before);
+    info.get("width").assert_i64(3);
+    info.get("cache_control").assert_string("max-age=60");
+    info.get("metadata").object().get("author").assert_string("ada");
+    info.get("tags").object().get("alt").assert_string("a cat");
+}
+
+#[tokio::test]
+async fn signed_links_download_private_assets_until_they_expire() {
+    let (_root, config) = fs_backend(
+        "signed-links",
+        &[("REQUIRE_READ_AUTH", "true"), ("DOWNLOAD_LINK_SECRET", "link-secret")],
+    )
+    .await;
+    let client = test_client(&config);
+    upload(&client, "private.png", png()).await.assert_status_is_ok();
+    client.get("/assets/private.png").send().await.assert_status(StatusCode::UNAUTHORIZED);
+
+    let reader = token_with(vec![Permission::new("read", "asset", "any")]);
+    let response = client
+        .post("/assets/private.png/sign")
+        .header("Authorization", format!("Bearer {reader}"))
+        .send()
+        .await;
+    response.assert_status_is_ok();
+    let body = response.json().await;
+    let url = body.value().object().get("url").string().to_string();
+    let response = client.get(&url).send().await;
+    response.assert_status_is_ok();
+    response.assert_bytes(png()).await;
+
+    let expires = (chrono::Utc::now().timestamp() - 1) as u64;
+    let expired = crate::auth::sign_download_link("link-secret", "private.png", expires);
+    let (token, exp) = url
+        .split_once("token=")
+        .and_then(|(_, query)| query.split_once("&exp="))
+        .expect("signed links carry a token and expiry");
+    // The last digit of the signature altered, or the expiry pushed back
+    let last = if token.ends_with('0') { '1' } else { '0' };
+    let tampered = format!("{}{last}", &token[..token.len() - 1]);
+    let extended = exp.parse::<u64>().expect("expiry is a number") + 60;
+    for (token, exp) in [
+        (expired, expires.to_string()),
+        (tampered, exp.to_string()),
+        (token.to_string(), extended.to_string()),
+    ] {
+        let response = client
+            .get(format!("/assets/private.png?token={token}&exp={exp}"))
+            .send()
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        let body = response.json().await;
+        let error = body.value().object().get("error");
+        error.object().get("code").assert_string("invalid_download_link");
+    }
+}
+
+#[tokio::test]
+async fn idempotency_keys_only_replay_the_same_upload() {
+    let (_root, config) = fs_backend("idempotency", &[("ALLOWED_METADATA_KEYS", "author")]).await;
+    let client = test_client(&config);
+    let upload_keyed = |key: &str, contents: Vec<u8>, author: &str| {
+        let form = TestForm::new()
+            .field(TestFormField::bytes(contents).name("asset").filename("keyed.png"))
+            .field(TestFormField::text(json!({ "author": author }).to_string()).name("metadata"));
+        client
+            .put("/assets")
+            .header("Authorization", format!("Bearer {}", token()))
+            .header("Idempotency-Key", key)
+            .multipart(form)
+            .send()
+    };
+
+    let response = upload_keyed("keyed-upload", png_sized(2), "ada").await;
+    response.assert_status_is_ok();
+    response.assert_text("/assets/keyed.png").await;
+    // Replayed rather than refused as a conflict with the stored asset
+    let response = upload_keyed("keyed-upload", png_sized(2), "ada").await;
+    response.assert_status_is_ok();
+    response.assert_text("/assets/keyed.png").await;
+
+    for (contents, author) in [(png_sized(3), "ada"), (png_sized(2), "grace")] {
+        let response = upload_keyed("keyed-upload", contents, author).await;
+        response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        let body = response.json().await;
+        let error = body.value().object().get("error");
+        error.object().get("code").assert_string("idempotency_key_reused");
+    }
+}
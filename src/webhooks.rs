@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, warn};
+
+use crate::config::AppConfig;
+
+/// Header carrying `sha256=<hex HMAC of the body>` when `WEBHOOK_SECRET` is set
+const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Deliveries attempted per event, waiting twice as long after each failure
+const MAX_ATTEMPTS: u32 = 3;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("webhook HTTP client")
+});
+
+/// Body POSTed to `WEBHOOK_URL`
+#[derive(Serialize)]
+struct AssetEvent {
+    event: &'static str,
+    asset_name: String,
+    /// Bytes stored, when known
+    size: Option<u64>,
+    timestamp: String,
+}
+
+/// Report a newly stored asset
+pub fn asset_created(config: &AppConfig, asset_name: &str, size: Option<u64>) {
+    send(config, AssetEvent {
+        event: "asset.created",
+        asset_name: asset_name.to_string(),
+        size,
+        timestamp: Utc::now().to_rfc3339(),
+    });
+}
+
+/// Report a removed asset
+pub fn asset_deleted(config: &AppConfig, asset_name: &str) {
+    send(config, AssetEvent {
+        event: "asset.deleted",
+        asset_name: asset_name.to_string(),
+        size: None,
+        timestamp: Utc::now().to_rfc3339(),
+    });
+}
+
+/// Deliver an event in the background so the request reporting it doesn't
+/// wait on the receiver. Does nothing unless `WEBHOOK_URL` is set.
+fn send(config: &AppConfig, event: AssetEvent) {
+    let Some(url) = config.webhook_url.clone() else {
+        return;
+    };
+
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(why) => {
+            warn!(event = event.event, "Error encoding webhook event: {}", why);
+            return;
+        }
+    };
+    let signature = config.webhook_secret.as_deref().map(|secret| sign(secret, &body));
+
+    tokio::spawn(async move {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut request = CLIENT
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let failure = match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    debug!(event = event.event, asset = %event.asset_name, "delivered webhook");
+                    return;
+                }
+                Ok(response) => format!("receiver answered {}", response.status()),
+                Err(why) => why.to_string(),
+            };
+
+            if attempt == MAX_ATTEMPTS {
+                warn!(
+                    event = event.event,
+                    asset = %event.asset_name,
+                    attempts = attempt,
+                    "Error delivering webhook: {}",
+                    failure
+                );
+                return;
+            }
+            debug!(event = event.event, attempt, "retrying webhook: {}", failure);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    });
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}